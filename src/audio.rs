@@ -0,0 +1,332 @@
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::mem;
+use std::path::Path;
+use std::time::Duration;
+
+use lewton::inside_ogg::OggStreamReader;
+use rodio::Source;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::{MediaSourceStream, ReadOnlySource};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::default::formats::AdtsReader;
+
+use crate::mpegts;
+
+// Enough of the start of a file to tell what it holds
+const HEAD_LEN: usize = 64;
+// The last Ogg page of a file is well within this many bytes of its end
+const OGG_TAIL_LEN: u64 = 64 * 1024;
+const TS_PACKET_LEN: usize = 188;
+// MP4 brands only audio files carry. Video and HEIC or AVIF pictures come in
+// the same container, so files with other brands need an audio track.
+const AUDIO_BRANDS: [&[u8]; 5] = [b"M4A ", b"M4B ", b"M4P ", b"F4A ", b"F4B "];
+
+/// What a file holds, going by its contents rather than its name.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AudioFormat {
+    Mp3,
+    Wav,
+    Flac,
+    Vorbis,
+    Opus,
+    /// AAC in ADTS frames.
+    Aac,
+    /// An MP4/M4A container with an AAC or ALAC track.
+    Mp4,
+    /// MPEG audio or AAC in an MPEG transport stream.
+    MpegTs,
+}
+
+impl AudioFormat {
+    /// Looks at the first bytes of `path`. `None` for anything that isn't
+    /// audio, or can't be read.
+    pub fn detect(path: &Path) -> Option<AudioFormat> {
+        let mut file = File::open(path).ok()?;
+        let mut head = [0; HEAD_LEN];
+        let len = read_up_to(&mut file, &mut head).ok()?;
+        let head = &head[..len];
+
+        if head.starts_with(b"ID3") && len >= 10 {
+            // An ID3v2 tag is almost always in front of MP3 audio, but FLAC
+            // files are sometimes given one too
+            let size = head[6..10].iter().fold(0u64, |size, &b| size << 7 | u64::from(b & 0x7f));
+            let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+            let mut after = [0; 4];
+            file.seek(SeekFrom::Start(10 + size + footer)).ok()?;
+            let is_flac = read_up_to(&mut file, &mut after).is_ok_and(|n| n == 4 && &after == b"fLaC");
+            return Some(if is_flac { AudioFormat::Flac } else { AudioFormat::Mp3 });
+        }
+        if head.starts_with(b"fLaC") {
+            return Some(AudioFormat::Flac);
+        }
+        if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WAVE") {
+            return Some(AudioFormat::Wav);
+        }
+        if head.starts_with(b"OggS") {
+            // The first page holds only the codec's identification packet,
+            // which starts after the segment table
+            let packet = head.get(27 + usize::from(*head.get(26)?)..)?;
+            if packet.starts_with(b"\x01vorbis") {
+                return Some(AudioFormat::Vorbis);
+            }
+            if packet.starts_with(b"OpusHead") {
+                return Some(AudioFormat::Opus);
+            }
+            return None;
+        }
+        if head.get(4..8) == Some(b"ftyp") {
+            // The major brand, a version, then the compatible brands
+            let size = usize::try_from(u32::from_be_bytes(head[..4].try_into().ok()?)).ok()?.min(len);
+            let brands = head.get(8..size)?;
+            let is_audio = brands.chunks_exact(4).any(|brand| AUDIO_BRANDS.contains(&brand));
+            return (is_audio || has_audio_track(path)).then_some(AudioFormat::Mp4);
+        }
+        if head.first() == Some(&0x47) && is_transport_stream(&mut file) {
+            return mpegts::find_audio(path).map(|_| AudioFormat::MpegTs);
+        }
+        match mpeg_audio_layer(head)? {
+            0 => Some(AudioFormat::Aac),
+            _ => Some(AudioFormat::Mp3),
+        }
+    }
+}
+
+/// The length of an Ogg Vorbis file, from the position of its last page.
+pub fn vorbis_duration(path: &Path) -> Option<Duration> {
+    let mut file = BufReader::new(File::open(path).ok()?);
+    let rate = OggStreamReader::new(&mut file).ok()?.ident_hdr.audio_sample_rate;
+    let granule = last_granule(&mut file.into_inner())?;
+    if rate == 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(granule as f64 / f64::from(rate)))
+}
+
+/// The granule position of the last page of an Ogg file, which counts the
+/// samples per channel up to the end.
+pub fn last_granule(file: &mut File) -> Option<u64> {
+    let end = file.seek(SeekFrom::End(0)).ok()?;
+    file.seek(SeekFrom::Start(end.saturating_sub(OGG_TAIL_LEN))).ok()?;
+    let mut tail = vec![];
+    file.read_to_end(&mut tail).ok()?;
+    let page = tail.windows(4).rposition(|w| w == b"OggS")?;
+    Some(u64::from_le_bytes(tail.get(page + 6..page + 14)?.try_into().ok()?))
+}
+
+/// Opens `path` with symphonia's demuxer, which rodio lacks for MP4 and
+/// ADTS. `None` if it can't make sense of the file.
+pub fn probe(path: &Path) -> Option<Box<dyn FormatReader>> {
+    let stream = MediaSourceStream::new(Box::new(File::open(path).ok()?), Default::default());
+    let probed = symphonia::default::get_probe()
+        .format(&Hint::new(), stream, &FormatOptions::default(), &MetadataOptions::default())
+        .ok()?;
+    Some(probed.format)
+}
+
+// Whether symphonia finds a track in `path` it can decode.
+fn has_audio_track(path: &Path) -> bool {
+    let Some(format) = probe(path) else { return false };
+    let codecs = symphonia::default::get_codecs();
+    format.tracks().iter()
+        .any(|t| t.codec_params.codec != CODEC_TYPE_NULL && codecs.get_codec(t.codec_params.codec).is_some())
+}
+
+/// The length of an MP4 or ADTS file, from its first audio track. ADTS
+/// files have no index, so theirs is estimated from the bitrate.
+pub fn probed_duration(path: &Path) -> Option<Duration> {
+    let format = probe(path)?;
+    let params = &format.tracks().iter().find(|t| t.codec_params.codec != CODEC_TYPE_NULL)?.codec_params;
+    let rate = params.sample_rate.filter(|&rate| rate > 0)?;
+    Some(Duration::from_secs_f64(params.n_frames? as f64 / f64::from(rate)))
+}
+
+/// The decoded audio of an MP4 (AAC or ALAC) or ADTS file or stream, for
+/// the sink.
+pub struct ProbedSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track: u32,
+    samples: Vec<i16>,
+    channels: u16,
+    rate: u32,
+    pos: usize,
+}
+
+impl ProbedSource {
+    pub fn open(path: &Path) -> io::Result<ProbedSource> {
+        let format = probe(path).ok_or_else(|| Error::new(ErrorKind::InvalidData, "not a known container"))?;
+        Self::decode(format)
+    }
+
+    /// Decodes AAC in ADTS frames as `reader` hands them over, which needn't
+    /// be able to seek: a radio station or a transport stream's audio.
+    pub fn adts(reader: impl Read + Send + Sync + 'static) -> io::Result<ProbedSource> {
+        let stream = MediaSourceStream::new(Box::new(ReadOnlySource::new(reader)), Default::default());
+        let format = AdtsReader::try_new(stream, &FormatOptions::default())
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err.to_string()))?;
+        Self::decode(Box::new(format))
+    }
+
+    fn decode(format: Box<dyn FormatReader>) -> io::Result<ProbedSource> {
+        let track = format.tracks().iter().find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no audio track"))?;
+        let decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|err| Error::new(ErrorKind::Unsupported, err.to_string()))?;
+        let mut source = ProbedSource {
+            track: track.id,
+            format,
+            decoder,
+            samples: vec![],
+            channels: 0,
+            rate: 0,
+            pos: 0,
+        };
+        source.advance();
+        if source.samples.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "no audio could be decoded"));
+        }
+        Ok(source)
+    }
+
+    /// The samples of the packet decoded last, as channels, sample rate and
+    /// samples, after which the next one is decoded. `None` at the end.
+    pub fn take_packet(&mut self) -> Option<(u16, u32, Vec<i16>)> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let packet = (self.channels, self.rate, mem::take(&mut self.samples));
+        self.advance();
+        Some(packet)
+    }
+
+    // Decodes the next packet of the track, leaving no samples at the end.
+    // Packets that fail to decode are skipped, as minimp3's junk is.
+    fn advance(&mut self) {
+        self.pos = 0;
+        self.samples.clear();
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(_) => return,
+            };
+            if packet.track_id() != self.track {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) if decoded.frames() > 0 => {
+                    let spec = *decoded.spec();
+                    let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, spec);
+                    buffer.copy_interleaved_ref(decoded);
+                    self.channels = spec.channels.count() as u16;
+                    self.rate = spec.rate;
+                    self.samples.extend_from_slice(buffer.samples());
+                    return;
+                },
+                Ok(_) | Err(DecodeError::DecodeError(_)) => {},
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl Iterator for ProbedSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = *self.samples.get(self.pos)?;
+        self.pos += 1;
+        if self.pos == self.samples.len() {
+            self.advance();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for ProbedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.samples.len() - self.pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels.max(1)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.rate.max(1)
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Fills as much of `buf` as the file has.
+fn read_up_to(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+// A transport stream has a sync byte at the start of every packet, so a
+// few in a row rule out a file that just happens to start with one.
+fn is_transport_stream(file: &mut File) -> bool {
+    let mut packets = [0; TS_PACKET_LEN * 3];
+    file.rewind().is_ok()
+        && read_up_to(file, &mut packets).is_ok_and(|n| n == packets.len())
+        && packets.iter().step_by(TS_PACKET_LEN).all(|&b| b == 0x47)
+}
+
+// The layer field of an MPEG audio frame header at the start of `head`,
+// where 0 means an ADTS (AAC) header. `None` if it isn't one.
+fn mpeg_audio_layer(head: &[u8]) -> Option<u8> {
+    let &[b0, b1, b2, _] = head.get(..4)? else { return None };
+    if b0 != 0xff || b1 & 0xe0 != 0xe0 {
+        return None;
+    }
+    let layer = (b1 >> 1) & 3;
+    if layer == 0 {
+        return (b1 & 0xf6 == 0xf0).then_some(0);
+    }
+    let valid = (b1 >> 3) & 3 != 1 && b2 >> 4 != 0xf && (b2 >> 2) & 3 != 3;
+    valid.then_some(layer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn only_mp4_files_with_audio_brands_or_tracks_are_audio() {
+        let path = env::temp_dir().join(format!("tasco-{}-brands", process::id()));
+        let ftyp = |major: &[u8; 4], compatible: &[u8; 4]| {
+            let mut file = b"\0\0\0\x18ftyp".to_vec();
+            file.extend(major);
+            file.extend(b"\0\0\0\0");
+            file.extend(compatible);
+            file.extend(b"\0\0\0\x08free");
+            fs::write(&path, file).unwrap();
+            AudioFormat::detect(&path)
+        };
+        assert_eq!(ftyp(b"M4A ", b"isom"), Some(AudioFormat::Mp4));
+        assert_eq!(ftyp(b"mp42", b"M4B "), Some(AudioFormat::Mp4));
+        // Pictures, and a video container with no track at all
+        assert_eq!(ftyp(b"heic", b"mif1"), None);
+        assert_eq!(ftyp(b"avif", b"mif1"), None);
+        assert_eq!(ftyp(b"isom", b"mp42"), None);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,41 @@
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::daemon::socket_path;
+use crate::protocol::{Request, Response};
+
+/// A connection to a running daemon's control socket.
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    pub fn connect() -> io::Result<Client> {
+        let path = socket_path().ok_or_else(|| Error::new(ErrorKind::NotFound, "no directory for the control socket"))?;
+        Self::connect_to(&path)
+    }
+
+    /// Connects to the socket at `path` rather than the usual one.
+    pub fn connect_to(path: &Path) -> io::Result<Client> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    pub fn send(&mut self, request: &Request) -> io::Result<Response> {
+        let mut data = serde_json::to_string(request).map_err(Error::from)?;
+        data.push('\n');
+        self.writer.write_all(data.as_bytes())?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            // The daemon may exit before its answer to `shutdown` gets out
+            if let Request::Shutdown = request {
+                return Ok(Response::ok());
+            }
+            return Err(Error::new(ErrorKind::UnexpectedEof, "the daemon closed the connection"));
+        }
+        serde_json::from_str(&line).map_err(Error::from)
+    }
+}
@@ -0,0 +1,517 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::{expand_home, Station};
+use crate::play_controller::{RepeatMode, SortKey};
+use crate::radio::is_stream;
+use crate::scan::natural_cmp;
+use crate::session::state_dir;
+
+const HISTORY_LEN: usize = 100;
+
+// Name, arguments and a short description for the help screen.
+pub const COMMANDS: [(&str, &str, &str); 13] = [
+    ("add", "<path>", "Add a file or directory to the playlist"),
+    ("save", "<file.m3u>", "Save the playlist as M3U"),
+    ("seek", "[+|-]<m:ss>", "Jump to a position, or forward or back"),
+    ("vol", "[+|-]<percent>", "Set or change the volume"),
+    ("repeat", "[off|all|one]", "Set or cycle the repeat mode"),
+    ("shuffle", "[on|off]", "Set or toggle shuffle"),
+    ("sort", "<key>", "Sort the playlist by name, path, duration, title, artist, album or track"),
+    ("sleep", "<minutes>|<h:mm>|off", "Pause after a while, or cancel that"),
+    ("radio", "<station>", "Play an internet radio station from the config"),
+    ("clear", "", "Clear the playlist"),
+    ("layout", "", "Switch to the next layout preset"),
+    ("help", "", "Show the help screen"),
+    ("quit", "", "Quit"),
+];
+
+/// A value to go to, or to move up or down by.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Amount<T> {
+    To(T),
+    Up(T),
+    Down(T),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Add(PathBuf),
+    Save(PathBuf),
+    Seek(Amount<Duration>),
+    Volume(Amount<u8>),
+    Repeat(Option<RepeatMode>),
+    Shuffle(Option<bool>),
+    Sort(SortKey),
+    /// Pause after this long, or never.
+    Sleep(Option<Duration>),
+    /// Play the configured station with this name.
+    Radio(String),
+    Clear,
+    Layout,
+    Help,
+    Quit,
+}
+
+impl Command {
+    /// Parses a command line, without the leading `:`. Relative paths are
+    /// taken relative to `base`.
+    pub fn parse(line: &str, base: &Path) -> Result<Command, String> {
+        let line = line.trim();
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (line, ""),
+        };
+        let path = || -> Result<PathBuf, String> {
+            if arg.is_empty() {
+                return Err(format!("{} needs a path", name));
+            }
+            // Stream URLs aren't paths, so they are left alone
+            if is_stream(Path::new(arg)) {
+                return Ok(PathBuf::from(arg));
+            }
+            Ok(base.join(expand_home(Path::new(arg))))
+        };
+        let command = match name {
+            "add" | "a" => Command::Add(path()?),
+            "save" | "w" => Command::Save(path()?),
+            "seek" => Command::Seek(parse_amount(arg, parse_time)
+                .ok_or_else(|| format!("invalid position \"{}\", expected e.g. 1:30, +10 or -0:05", arg))?),
+            "vol" | "volume" => Command::Volume(parse_amount(arg, |v| v.parse().ok().filter(|&v| v <= 100))
+                .ok_or_else(|| format!("invalid volume \"{}\", expected 0-100, +5 or -5", arg))?),
+            "repeat" => Command::Repeat(match arg {
+                "" => None,
+                mode => Some(RepeatMode::from_name(mode)
+                    .ok_or_else(|| format!("invalid repeat mode \"{}\", expected off, all or one", mode))?),
+            }),
+            "shuffle" => Command::Shuffle(match arg {
+                "" => None,
+                "on" => Some(true),
+                "off" => Some(false),
+                other => return Err(format!("invalid shuffle \"{}\", expected on or off", other)),
+            }),
+            "sort" => Command::Sort(SortKey::from_name(arg).ok_or_else(|| {
+                let keys: Vec<&str> = SortKey::ALL.iter().map(|k| k.name()).collect();
+                format!("invalid sort key \"{}\", expected one of: {}", arg, keys.join(", "))
+            })?),
+            "sleep" => Command::Sleep(match arg {
+                "off" => None,
+                arg => Some(parse_minutes(arg)
+                    .ok_or_else(|| format!("invalid time \"{}\", expected minutes, h:mm or off", arg))?),
+            }),
+            "radio" if arg.is_empty() => return Err("radio needs a station".to_string()),
+            "radio" => Command::Radio(arg.to_string()),
+            "clear" => Command::Clear,
+            "layout" => Command::Layout,
+            "help" | "h" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            "" => return Err("no command given".to_string()),
+            other => return Err(format!("unknown command \"{}\"", other)),
+        };
+        Ok(command)
+    }
+}
+
+/// Parses `5`, `+5` or `-5` with `parse` into a value to go to, or to
+/// move up or down by.
+pub fn parse_amount<T>(arg: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Amount<T>> {
+    if let Some(rest) = arg.strip_prefix('+') {
+        parse(rest).map(Amount::Up)
+    }
+    else if let Some(rest) = arg.strip_prefix('-') {
+        parse(rest).map(Amount::Down)
+    }
+    else {
+        parse(arg).map(Amount::To)
+    }
+}
+
+/// Parses `90`, `1:30` or `1:02:03` into a duration.
+pub fn parse_time(text: &str) -> Option<Duration> {
+    let mut seconds = 0u64;
+    for (i, part) in text.split(':').enumerate() {
+        if i > 2 || part.is_empty() {
+            return None;
+        }
+        seconds = seconds.checked_mul(60)?.checked_add(part.parse().ok()?)?;
+    }
+    Some(Duration::from_secs(seconds))
+}
+
+// Parses `45` or `1:30` as minutes, or hours and minutes.
+fn parse_minutes(text: &str) -> Option<Duration> {
+    if text.matches(':').count() > 1 {
+        return None;
+    }
+    parse_time(text).and_then(|time| time.checked_mul(60)).filter(|time| !time.is_zero())
+}
+
+/// Every way to finish the command line `line`: command names, argument
+/// values, or paths relative to `base` for `add` and `save`.
+pub fn complete(line: &str, base: &Path, stations: &[Station]) -> Vec<String> {
+    let Some((name, arg)) = line.split_once(' ') else {
+        return COMMANDS.iter()
+            .filter(|(command, _, _)| command.starts_with(line))
+            .map(|(command, args, _)| if args.is_empty() { command.to_string() } else { format!("{} ", command) })
+            .collect();
+    };
+    let arg = arg.trim_start();
+    let options: Vec<String> = match name {
+        "add" | "a" | "save" | "w" => complete_path(arg, base),
+        "repeat" => ["off", "all", "one"].map(String::from).to_vec(),
+        "shuffle" => ["on", "off"].map(String::from).to_vec(),
+        "sleep" => vec!["off".to_string()],
+        "radio" => stations.iter().map(|s| s.name.clone()).collect(),
+        "sort" => SortKey::ALL.iter().map(|k| k.name().to_string()).collect(),
+        _ => vec![],
+    };
+    options.into_iter()
+        .filter(|option| option.starts_with(arg))
+        .map(|option| format!("{} {}", name, option))
+        .collect()
+}
+
+// Entries of the directory part of `arg` that start with its last
+// component. Directories end in `/` so completion can carry on into them.
+fn complete_path(arg: &str, base: &Path) -> Vec<String> {
+    let (dir_part, prefix) = match arg.rfind('/') {
+        Some(i) => (&arg[..=i], &arg[i+1..]),
+        None => ("", arg),
+    };
+    let dir = base.join(expand_home(Path::new(dir_part)));
+    let Ok(entries) = fs::read_dir(dir) else { return vec![] };
+    let mut names: Vec<String> = entries.flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.path().is_dir();
+            Some(if is_dir { format!("{}/", name) } else { name })
+        })
+        .collect();
+    names.sort_by(|a, b| natural_cmp(a, b));
+    names.into_iter().map(|name| format!("{}{}", dir_part, name)).collect()
+}
+
+/// What came of the last command, shown where the prompt was.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Info(String),
+    Error(String),
+}
+
+/// The `:` prompt: the line being edited, its completions and the history
+/// of earlier commands, which is kept between runs.
+#[derive(Debug, Default)]
+pub struct CommandLine {
+    pub active: bool,
+    pub message: Option<Message>,
+    pub input: String,
+    /// Byte offset of the cursor in `input`.
+    pub cursor: usize,
+    history: Vec<String>,
+    history_pos: Option<usize>,
+    // Candidates from the last Tab, cycled through by pressing it again
+    completions: Vec<String>,
+    completion_index: usize,
+}
+
+impl CommandLine {
+    pub fn new() -> CommandLine {
+        let history = Self::history_path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|data| data.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        Self { history, ..Self::default() }
+    }
+
+    fn history_path() -> Option<PathBuf> {
+        Some(state_dir()?.join("history"))
+    }
+
+    /// Starts editing an empty line.
+    pub fn open(&mut self) {
+        self.active = true;
+        self.message = None;
+        self.set_input(String::new());
+        self.history_pos = None;
+    }
+
+    pub fn close(&mut self) {
+        self.active = false;
+        self.set_input(String::new());
+    }
+
+    fn set_input(&mut self, input: String) {
+        self.cursor = input.len();
+        self.input = input;
+        self.completions.clear();
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.input.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+        self.completions.clear();
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(c) = self.input[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.input.remove(self.cursor);
+            self.completions.clear();
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.input.len() {
+            self.input.remove(self.cursor);
+            self.completions.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.set_input(String::new());
+    }
+
+    pub fn left(&mut self) {
+        if let Some(c) = self.input[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    pub fn right(&mut self) {
+        if let Some(c) = self.input[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.input.len();
+    }
+
+    pub fn history_prev(&mut self) {
+        let pos = match self.history_pos {
+            Some(0) => return,
+            Some(pos) => pos - 1,
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+        self.history_pos = Some(pos);
+        self.set_input(self.history[pos].clone());
+    }
+
+    pub fn history_next(&mut self) {
+        match self.history_pos {
+            Some(pos) if pos + 1 < self.history.len() => {
+                self.history_pos = Some(pos + 1);
+                self.set_input(self.history[pos + 1].clone());
+            }
+            Some(_) => {
+                self.history_pos = None;
+                self.set_input(String::new());
+            }
+            None => {}
+        }
+    }
+
+    /// Completes the line. The first Tab fills in as much as all candidates
+    /// share; pressing it again steps through them one by one.
+    pub fn complete(&mut self, base: &Path, stations: &[Station]) {
+        if !self.completions.is_empty() {
+            self.completion_index = (self.completion_index + 1) % self.completions.len();
+            let input = self.completions[self.completion_index].clone();
+            self.input = input;
+            self.cursor = self.input.len();
+            return;
+        }
+        let candidates = complete(&self.input, base, stations);
+        match candidates.len() {
+            0 => {}
+            1 => self.set_input(candidates[0].clone()),
+            _ => {
+                let common = common_prefix(&candidates);
+                if common.len() > self.input.len() {
+                    self.set_input(common);
+                }
+                else {
+                    self.completion_index = 0;
+                    self.input = candidates[0].clone();
+                    self.cursor = self.input.len();
+                    self.completions = candidates;
+                }
+            }
+        }
+    }
+
+    /// Finishes editing and returns the line, remembering it in the history.
+    pub fn submit(&mut self) -> String {
+        self.active = false;
+        let line = std::mem::take(&mut self.input);
+        self.cursor = 0;
+        self.completions.clear();
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+            if self.history.len() > HISTORY_LEN {
+                self.history.remove(0);
+            }
+            self.save_history();
+        }
+        line
+    }
+
+    // History is only a convenience, so failing to write it is ignored.
+    fn save_history(&self) {
+        if let Some(path) = Self::history_path() {
+            if let Some(parent) = path.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+            let _ = fs::write(path, self.history.join("\n") + "\n");
+        }
+    }
+}
+
+fn common_prefix(candidates: &[String]) -> String {
+    let first = &candidates[0];
+    let mut len = first.len();
+    for other in &candidates[1..] {
+        len = first.char_indices()
+            .zip(other.chars())
+            .take_while(|((i, a), b)| *i < len && a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0);
+    }
+    first[..len].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn parse(line: &str) -> Result<Command, String> {
+        Command::parse(line, Path::new("/music"))
+    }
+
+    fn line(history: &[&str]) -> CommandLine {
+        CommandLine { history: history.iter().map(|line| line.to_string()).collect(), ..CommandLine::default() }
+    }
+
+    #[test]
+    fn commands_and_their_arguments() {
+        assert_eq!(parse("  add  jazz/a.mp3 "), Ok(Command::Add(PathBuf::from("/music/jazz/a.mp3"))));
+        assert_eq!(parse("a /tmp/b.mp3"), Ok(Command::Add(PathBuf::from("/tmp/b.mp3"))));
+        assert_eq!(parse("add http://radio.example/live"), Ok(Command::Add(PathBuf::from("http://radio.example/live"))));
+        assert_eq!(parse("vol -5"), Ok(Command::Volume(Amount::Down(5))));
+        assert_eq!(parse("repeat"), Ok(Command::Repeat(None)));
+        assert_eq!(parse("shuffle off"), Ok(Command::Shuffle(Some(false))));
+        assert_eq!(parse("sleep off"), Ok(Command::Sleep(None)));
+        assert_eq!(parse("sleep 1:30"), Ok(Command::Sleep(Some(Duration::from_secs(90 * 60)))));
+        assert_eq!(parse("radio Jazz FM"), Ok(Command::Radio("Jazz FM".to_string())));
+        assert_eq!(parse("q"), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn bad_commands_say_what_was_expected() {
+        assert_eq!(parse(""), Err("no command given".to_string()));
+        assert_eq!(parse("dance"), Err("unknown command \"dance\"".to_string()));
+        assert_eq!(parse("save"), Err("save needs a path".to_string()));
+        assert_eq!(parse("radio"), Err("radio needs a station".to_string()));
+        assert_eq!(parse("vol 101"), Err("invalid volume \"101\", expected 0-100, +5 or -5".to_string()));
+        assert_eq!(parse("shuffle maybe"), Err("invalid shuffle \"maybe\", expected on or off".to_string()));
+        assert_eq!(parse("sleep 0"), Err("invalid time \"0\", expected minutes, h:mm or off".to_string()));
+        assert!(parse("sleep 1:00:00").is_err());
+        // Minutes that don't fit in a duration once counted in seconds
+        assert_eq!(parse("sleep 307445734561825860"), Ok(Command::Sleep(Some(Duration::from_secs(u64::MAX - 15)))));
+        assert!(parse("sleep 307445734561825861").is_err());
+        assert!(parse("sleep 5124095576030432:0").is_err());
+        assert!(parse("repeat twice").is_err());
+        assert!(parse("sort size").is_err());
+    }
+
+    #[test]
+    fn times_and_positions() {
+        assert_eq!(parse_time("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_time("1:30"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_time("1:02:03"), Some(Duration::from_secs(3723)));
+        for bad in ["", ":30", "1:", "1::2", "1:2:3:4", "x", "-1"] {
+            assert_eq!(parse_time(bad), None, "{:?}", bad);
+        }
+        // Too large to count in seconds
+        assert_eq!(parse_time("1:18446744073709551615"), None);
+        assert_eq!(parse_time("18446744073709551615:0"), None);
+        assert_eq!(parse("seek +0:05"), Ok(Command::Seek(Amount::Up(Duration::from_secs(5)))));
+        assert_eq!(parse("seek -10"), Ok(Command::Seek(Amount::Down(Duration::from_secs(10)))));
+        assert!(parse("seek 1:18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn tab_fills_in_what_is_shared_then_cycles() {
+        let mut line = line(&[]);
+        line.input = "re".to_string();
+        line.complete(Path::new("/"), &[]);
+        assert_eq!(line.input, "repeat ");
+        let mut seen = vec![];
+        for _ in 0..4 {
+            line.complete(Path::new("/"), &[]);
+            seen.push(line.input.clone());
+        }
+        assert_eq!(seen, ["repeat off", "repeat all", "repeat one", "repeat off"]);
+        assert_eq!(line.cursor, line.input.len());
+        // Typing starts over from the new line
+        line.backspace();
+        line.backspace();
+        line.complete(Path::new("/"), &[]);
+        line.complete(Path::new("/"), &[]);
+        assert_eq!(line.input, "repeat one");
+
+        let stations = [Station { name: "Jazz".to_string(), url: String::new() }];
+        assert_eq!(complete("radio J", Path::new("/"), &stations), ["radio Jazz"]);
+    }
+
+    #[test]
+    fn paths_complete_from_the_base_directory() {
+        let base = env::temp_dir().join(format!("tasco-{}-complete", process::id()));
+        fs::create_dir_all(base.join("albums")).unwrap();
+        for name in ["song10.mp3", "song9.mp3", ".hidden"] {
+            fs::write(base.join(name), b"").unwrap();
+        }
+        assert_eq!(complete("add so", &base, &[]), ["add song9.mp3", "add song10.mp3"]);
+        assert_eq!(complete("add a", &base, &[]), ["add albums/"]);
+        assert_eq!(complete("add .", &base, &[]), ["add .hidden"]);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn history_steps_back_and_forth() {
+        let mut line = line(&["add a", "vol 50"]);
+        line.history_next();
+        assert_eq!(line.input, "");
+        line.history_prev();
+        assert_eq!(line.input, "vol 50");
+        line.history_prev();
+        line.history_prev();
+        assert_eq!(line.input, "add a");
+        line.history_next();
+        assert_eq!(line.input, "vol 50");
+        line.history_next();
+        assert_eq!((line.input.as_str(), line.history_pos), ("", None));
+    }
+
+    #[test]
+    fn common_prefixes_end_on_characters() {
+        let candidates = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(common_prefix(&candidates(&["save ", "seek ", "sort "])), "s");
+        assert_eq!(common_prefix(&candidates(&["add café", "add cafè"])), "add caf");
+        assert_eq!(common_prefix(&candidates(&["one"])), "one");
+        assert_eq!(common_prefix(&candidates(&["abc", "xyz"])), "");
+    }
+}
@@ -1,19 +1,225 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::play_controller::RepeatMode;
+use crate::format::validate_template;
+use crate::keymap::Keymap;
+use crate::theme::{Theme, ThemeConfig};
+use crate::layout::{Layouts, Node};
+use crate::radio::is_stream;
 
 pub const Version: &str = "1.0";
-pub const Music_file_extensions: [&str;4] = ["mp3", "wav", "flac", "ts"];
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+    pub music_roots: Vec<PathBuf>,
+    /// Starting volume in percent, used when there is no saved session.
+    pub volume: u8,
+    /// How long to wait for input between redraws, in milliseconds.
+    #[serde(rename = "refresh_ms")]
     pub fresh_time: u64,
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
+    /// Name of a built-in theme or of one of the `[themes.<name>]` tables.
+    pub theme: String,
+    pub themes: BTreeMap<String, ThemeConfig>,
+    /// Name of a layout preset or of one of the `[layouts.<name>]` tables.
+    pub layout: String,
+    pub layouts: BTreeMap<String, Node>,
+    pub display: DisplayConfig,
+    pub keys: BTreeMap<String, String>,
+    /// Whether to show up on the session bus for media keys and `playerctl`.
+    pub mpris: bool,
+    pub mpd: MpdConfig,
+    pub http: HttpConfig,
+    /// Internet radio stations for `:radio`.
+    pub stations: Vec<Station>,
+    #[serde(skip)]
+    pub keymap: Keymap,
+    #[serde(skip)]
+    pub colors: Theme,
+    #[serde(skip)]
+    pub panels: Layouts,
+}
 
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub now_playing: String,
+    pub playlist: String,
 }
 
-impl Config {
-    pub fn default() -> Self {
+/// The built-in server for MPD clients, off unless `enabled` is set.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MpdConfig {
+    pub enabled: bool,
+    /// Where to listen. The default only takes local clients; `0.0.0.0`
+    /// lets in the rest of the network as well.
+    pub address: String,
+    pub port: u16,
+}
+
+/// The HTTP API, off unless `enabled` is set.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub address: String,
+    pub port: u16,
+}
+
+/// An internet radio station: an `http://` stream of MP3, Ogg Vorbis or AAC.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Station {
+    pub name: String,
+    pub url: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
         Self{
+            music_roots: vec![],
+            volume: 100,
             fresh_time:100,
+            repeat: RepeatMode::All,
+            shuffle: false,
+            theme: "default".to_string(),
+            themes: BTreeMap::new(),
+            layout: "classic".to_string(),
+            layouts: BTreeMap::new(),
+            display: DisplayConfig::default(),
+            keys: BTreeMap::new(),
+            mpris: true,
+            mpd: MpdConfig::default(),
+            http: HttpConfig::default(),
+            stations: vec![],
+            keymap: Keymap::default(),
+            colors: Theme::default(),
+            panels: Layouts::default(),
         }
     }
 }
 
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            now_playing: "{name}".to_string(),
+            playlist: "{name}".to_string(),
+        }
+    }
+}
+
+impl Default for MpdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1".to_string(),
+            port: 6600,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1".to_string(),
+            port: 8600,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(PathBuf, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "{}: {}", path.display(), err),
+            ConfigError::Invalid(path, msg) => write!(f, "{}: {}", path.display(), msg),
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/tasco/config.toml`, falling back to
+    /// `~/.config/tasco/config.toml`.
+    pub fn default_path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("tasco").join("config.toml"))
+    }
+
+    /// Loads the config from `path`, or from the default location when no
+    /// path is given. A missing default config is not an error.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Self::finish(Config::default(), PathBuf::new()),
+            },
+        };
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+                return Self::finish(Config::default(), path);
+            }
+            Err(err) => return Err(ConfigError::Io(path, err)),
+        };
+        let config: Config = toml::from_str(&data)
+            .map_err(|err| ConfigError::Parse(path.clone(), err))?;
+        Self::finish(config, path)
+    }
+
+    // Validates the config and builds the parts derived from it. The theme is
+    // resolved even without a config file so NO_COLOR and the terminal's
+    // color depth are always honoured.
+    fn finish(mut config: Config, path: PathBuf) -> Result<Config, ConfigError> {
+        config.validate().map_err(|msg| ConfigError::Invalid(path.clone(), msg))?;
+        config.keymap = Keymap::new(&config.keys).map_err(|msg| ConfigError::Invalid(path.clone(), msg))?;
+        config.colors = Theme::load(&config.theme, &config.themes).map_err(|msg| ConfigError::Invalid(path.clone(), msg))?;
+        config.panels = Layouts::new(&config.layout, &config.layouts).map_err(|msg| ConfigError::Invalid(path, msg))?;
+        config.music_roots = config.music_roots.iter().map(|root| expand_home(root)).collect();
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.volume > 100 {
+            return Err(format!("volume must be between 0 and 100, got {}", self.volume));
+        }
+        if !(10..=5000).contains(&self.fresh_time) {
+            return Err(format!("refresh_ms must be between 10 and 5000, got {}", self.fresh_time));
+        }
+        validate_template(&self.display.now_playing)?;
+        validate_template(&self.display.playlist)?;
+        for station in &self.stations {
+            if !is_stream(Path::new(&station.url)) {
+                return Err(format!("station \"{}\" needs an http:// URL, got \"{}\"", station.name, station.url));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
 pub enum RawKey {
@@ -44,3 +250,56 @@ pub enum KeyBinding {
     F(u8),
     Unsupported,
 }
+
+impl RawKey {
+    fn parse(name: &str) -> Option<RawKey> {
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Some(RawKey::Char(c));
+        }
+        let key = match name.to_lowercase().as_str() {
+            "space" => RawKey::Char(' '),
+            "up" => RawKey::Up,
+            "down" => RawKey::Down,
+            "left" => RawKey::Left,
+            "right" => RawKey::Right,
+            "backspace" => RawKey::Backspace,
+            "enter" => RawKey::Enter,
+            "tab" => RawKey::Tab,
+            "home" => RawKey::Home,
+            "end" => RawKey::End,
+            "pageup" => RawKey::PageUp,
+            "pagedown" => RawKey::PageDown,
+            "backtab" => RawKey::BackTab,
+            "delete" => RawKey::Delete,
+            "insert" => RawKey::Insert,
+            "esc" => RawKey::Esc,
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+impl KeyBinding {
+    /// Parses key specs such as `n`, `space`, `ctrl+n`, `shift+up` or `f5`.
+    pub fn parse(spec: &str) -> Option<KeyBinding> {
+        if let Some(rest) = strip_prefix_ignore_case(spec, "ctrl+") {
+            return RawKey::parse(rest).map(KeyBinding::Ctrl);
+        }
+        if let Some(rest) = strip_prefix_ignore_case(spec, "shift+") {
+            return RawKey::parse(rest).map(KeyBinding::Shift);
+        }
+        if let Some(n) = spec.strip_prefix(['f', 'F']).and_then(|n| n.parse().ok()) {
+            return Some(KeyBinding::F(n));
+        }
+        RawKey::parse(spec).map(KeyBinding::Raw)
+    }
+}
+
+// Lower-casing can change how long a string is, so the prefix is compared
+// in place rather than on a lower-cased copy.
+fn strip_prefix_ignore_case<'a>(spec: &'a str, prefix: &str) -> Option<&'a str> {
+    spec.get(..prefix.len())
+        .filter(|start| start.eq_ignore_ascii_case(prefix))
+        .map(|_| &spec[prefix.len()..])
+}
@@ -0,0 +1,56 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+use id3::frame::PictureType;
+use image::RgbImage;
+use symphonia::core::meta::StandardVisualKey;
+
+use crate::audio::{self, AudioFormat};
+
+// Cover art is never shown bigger than a panel, so it is shrunk to this many
+// pixels on the long side right after decoding.
+const MAX_SIZE: u32 = 128;
+
+const COVER_NAMES: [&str;4] = ["cover", "folder", "front", "album"];
+
+/// Finds cover art for `track`: a picture embedded in its ID3 tag or MP4
+/// metadata, or an image such as `cover.jpg` or `folder.png` in the same
+/// directory.
+pub fn load(track: &Path) -> Option<RgbImage> {
+    let data = embedded(track).or_else(|| beside(track))?;
+    let image = image::load_from_memory(&data).ok()?;
+    Some(image.thumbnail(MAX_SIZE, MAX_SIZE).to_rgb8())
+}
+
+// Goes by what the file holds, like playing it does, not by its name.
+fn embedded(track: &Path) -> Option<Vec<u8>> {
+    match AudioFormat::detect(track)? {
+        AudioFormat::Mp3 => {
+            let tag = id3::Tag::read_from_path(track).ok()?;
+            let front = tag.pictures().find(|p| p.picture_type == PictureType::CoverFront);
+            front.or_else(|| tag.pictures().next()).map(|p| p.data.clone())
+        },
+        AudioFormat::Mp4 => {
+            let mut format = audio::probe(track)?;
+            let metadata = format.metadata();
+            let visuals = metadata.current()?.visuals();
+            let front = visuals.iter().find(|v| v.usage == Some(StandardVisualKey::FrontCover));
+            front.or(visuals.first()).map(|v| v.data.to_vec())
+        },
+        _ => None,
+    }
+}
+
+fn beside(track: &Path) -> Option<Vec<u8>> {
+    for entry in fs::read_dir(track.parent()?).ok()?.flatten() {
+        let path = entry.path();
+        let stem = path.file_stem().and_then(OsStr::to_str).map(str::to_lowercase);
+        let ext = path.extension().and_then(OsStr::to_str).map(str::to_lowercase);
+        let is_image = matches!(ext.as_deref(), Some("jpg" | "jpeg" | "png"));
+        if is_image && stem.is_some_and(|s| COVER_NAMES.contains(&s.as_str())) {
+            return fs::read(path).ok();
+        }
+    }
+    None
+}
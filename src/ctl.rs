@@ -0,0 +1,198 @@
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches};
+
+use crate::client::Client;
+use crate::command::{parse_amount, parse_time};
+use crate::daemon::socket_path;
+use crate::format::{format_status, validate_status_template};
+use crate::play_controller::RepeatMode;
+use crate::protocol::{Playback, Request};
+use crate::radio::is_stream;
+
+// Exit codes, so scripts can tell a refused command from a missing player.
+// Bad arguments exit with 2, which is also what clap uses.
+const EXIT_OK: i32 = 0;
+const EXIT_REFUSED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_NOT_RUNNING: i32 = 3;
+
+const PLAYING_STATUS: &str = "[{state}] {artist} - {title}\n{position} / {duration}  #{index}/{count}\nvolume {volume}%  repeat {repeat}  shuffle {shuffle}";
+const STOPPED_STATUS: &str = "[{state}] {count} tracks\nvolume {volume}%  repeat {repeat}  shuffle {shuffle}";
+
+/// The `ctl` subcommand and its own subcommands.
+pub fn app() -> App<'static> {
+    App::new("ctl")
+        .about("Control a running Tasco, TUI or daemon")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(App::new("play")
+            .about("Play the track at INDEX (counting from 1), or resume")
+            .arg(Arg::with_name("index").value_name("INDEX")))
+        .subcommand(App::new("pause").about("Pause"))
+        .subcommand(App::new("toggle").about("Pause or resume"))
+        .subcommand(App::new("next").about("Skip to the next track"))
+        .subcommand(App::new("prev").about("Go back to the start of the track, or the one before"))
+        .subcommand(App::new("stop").about("Stop playing"))
+        .subcommand(App::new("vol")
+            .about("Set the volume in percent, or change it with +5 or -5")
+            .arg(Arg::with_name("amount").value_name("[+|-]PERCENT").required(true).allow_hyphen_values(true)))
+        .subcommand(App::new("seek")
+            .about("Jump to a position such as 1:30, or forward or back with +10 or -10")
+            .arg(Arg::with_name("amount").value_name("[+|-]TIME").required(true).allow_hyphen_values(true)))
+        .subcommand(App::new("add")
+            .about("Add music files, playlists, directories or http:// radio streams to the playlist")
+            .arg(Arg::with_name("path").value_name("PATH").required(true).multiple(true)))
+        .subcommand(App::new("repeat")
+            .about("Set the repeat mode, or cycle it")
+            .arg(Arg::with_name("mode").value_name("MODE").possible_values(["off", "all", "one"])))
+        .subcommand(App::new("shuffle")
+            .about("Turn shuffle on or off, or toggle it")
+            .arg(Arg::with_name("on").value_name("on|off").possible_values(["on", "off"])))
+        .subcommand(App::new("clear").about("Clear the playlist"))
+        .subcommand(App::new("status")
+            .about("Print what is playing")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .value_name("TEMPLATE")
+                .help("Print TEMPLATE with fields such as {state}, {artist}, {title}, {position} and {volume} filled in")))
+        .subcommand(App::new("quit").about("Stop the player"))
+}
+
+/// Sends the command in `args` to the running player and returns the exit
+/// code.
+pub fn run(args: &ArgMatches) -> i32 {
+    run_on(args, socket_path().as_deref())
+}
+
+fn run_on(args: &ArgMatches, socket: Option<&Path>) -> i32 {
+    let Some((name, args)) = args.subcommand() else { return EXIT_USAGE };
+    let format = if name == "status" { args.value_of("format") } else { None };
+    if let Some(Err(msg)) = format.map(validate_status_template) {
+        eprintln!("Invalid format: {}", msg);
+        return EXIT_USAGE;
+    }
+    let request = match request(name, args) {
+        Ok(request) => request,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return EXIT_USAGE;
+        }
+    };
+    let connected = socket.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound)).and_then(Client::connect_to);
+    let mut client = match connected {
+        Ok(client) => client,
+        Err(_) => {
+            eprintln!("Tasco is not running");
+            return EXIT_NOT_RUNNING;
+        }
+    };
+    let response = match client.send(&request) {
+        Ok(response) => response,
+        Err(err) => {
+            eprintln!("Lost the connection to Tasco: {}", err);
+            return EXIT_NOT_RUNNING;
+        }
+    };
+    match response.into_result() {
+        Ok(response) => {
+            if let Some(state) = response.state {
+                let template = format.unwrap_or(match state.playback {
+                    Playback::Stopped => STOPPED_STATUS,
+                    _ => PLAYING_STATUS,
+                });
+                println!("{}", format_status(template, &state));
+            }
+            EXIT_OK
+        },
+        Err(msg) => {
+            eprintln!("{}", msg);
+            EXIT_REFUSED
+        }
+    }
+}
+
+fn request(name: &str, args: &ArgMatches) -> Result<Request, String> {
+    let request = match name {
+        "play" => Request::Play {
+            index: match args.value_of("index") {
+                Some(index) => Some(index.parse::<usize>().ok()
+                    .and_then(|i| i.checked_sub(1))
+                    .ok_or_else(|| format!("invalid index \"{}\", tracks count from 1", index))?),
+                None => None,
+            },
+        },
+        "pause" => Request::Pause,
+        "toggle" => Request::Toggle,
+        "next" => Request::Next,
+        "prev" => Request::Prev,
+        "stop" => Request::Stop,
+        "vol" => {
+            let arg = args.value_of("amount").unwrap_or_default();
+            Request::volume(parse_amount(arg, |v| v.parse().ok().filter(|&v| v <= 100))
+                .ok_or_else(|| format!("invalid volume \"{}\", expected 0-100, +5 or -5", arg))?)
+        },
+        "seek" => {
+            let arg = args.value_of("amount").unwrap_or_default();
+            Request::seek(parse_amount(arg, parse_time)
+                .ok_or_else(|| format!("invalid position \"{}\", expected e.g. 1:30, +10 or -0:05", arg))?)
+        },
+        "add" => {
+            // The player has its own working directory, so relative paths
+            // are resolved here
+            let cwd = env::current_dir().map_err(|err| format!("no current directory: {}", err))?;
+            let paths: Vec<PathBuf> = args.values_of("path").into_iter().flatten()
+                .map(|path| match is_stream(Path::new(path)) {
+                    true => PathBuf::from(path),
+                    false => cwd.join(path),
+                })
+                .collect();
+            Request::Enqueue { paths }
+        },
+        "repeat" => Request::Repeat { mode: args.value_of("mode").and_then(RepeatMode::from_name) },
+        "shuffle" => Request::Shuffle { on: args.value_of("on").map(|on| on == "on") },
+        "clear" => Request::Clear,
+        "status" => Request::Status { since: None },
+        "quit" => Request::Shutdown,
+        other => return Err(format!("unknown command \"{}\"", other)),
+    };
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::daemon::listen_at;
+    use crate::service::{Handle, Service};
+
+    fn ctl(socket: &Path, args: &[&str]) -> i32 {
+        let matches = app().get_matches_from(["ctl"].iter().chain(args));
+        run_on(&matches, Some(socket))
+    }
+
+    #[test]
+    fn exit_codes() {
+        let path = env::temp_dir().join(format!("tasco-{}-ctl.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(ctl(&path, &["pause"]), EXIT_NOT_RUNNING);
+
+        let (handle, requests) = Handle::new();
+        let player = Service::spawn(&Config::default(), None, false, requests);
+        listen_at(handle, &path).unwrap();
+        assert_eq!(ctl(&path, &["vol", "40"]), EXIT_OK);
+        assert_eq!(ctl(&path, &["vol", "400"]), EXIT_USAGE);
+        assert_eq!(ctl(&path, &["play", "0"]), EXIT_USAGE);
+        assert_eq!(ctl(&path, &["play", "3"]), EXIT_REFUSED);
+        assert_eq!(ctl(&path, &["status"]), EXIT_OK);
+        assert_eq!(ctl(&path, &["quit"]), EXIT_OK);
+        assert_eq!(player.join().unwrap().volume, 40);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flume::RecvTimeoutError;
+
+use crate::config::Config;
+use crate::launch::Launch;
+use crate::protocol::{PlayerState, Request, Response};
+use crate::{http, mpd, mpris};
+use crate::service::{Handle, Service};
+use crate::session::{state_dir, Session};
+
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// `$XDG_RUNTIME_DIR/tasco.sock`, or the state directory when there is no
+/// runtime directory.
+pub fn socket_path() -> Option<PathBuf> {
+    Some(dirs::runtime_dir().or_else(state_dir)?.join("tasco.sock"))
+}
+
+/// Listens on the control socket at `path`. A socket file left behind by a
+/// daemon that didn't exit cleanly is replaced, but one that still answers
+/// is not.
+fn bind(path: &Path) -> Result<UnixListener, Error> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::new(ErrorKind::AddrInUse,
+                format!("Tasco is already running, its socket is {}", path.display())));
+        }
+        fs::remove_file(path)?;
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Starts answering on the control socket, passing requests on through
+/// `handle`. Returns the socket's path, to be removed on exit.
+pub fn listen(handle: Handle) -> Result<PathBuf, Error> {
+    let path = socket_path().ok_or_else(|| Error::new(ErrorKind::NotFound, "no directory for the control socket"))?;
+    listen_at(handle, &path)?;
+    Ok(path)
+}
+
+/// Like [`listen`], but on the socket at `path`.
+pub fn listen_at(handle: Handle, path: &Path) -> Result<(), Error> {
+    let listener = bind(path)?;
+    thread::spawn(move || serve(listener, handle));
+    Ok(())
+}
+
+/// Runs the player without the TUI until a `shutdown` request arrives,
+/// taking commands from the control socket.
+pub fn run(launch: &Launch, config: &Config) -> Result<(), Error> {
+    let (handle, requests) = Handle::new();
+    let path = listen(handle.clone())?;
+    let session = Session::load();
+    // The daemon has no explorer, so the directory the TUI was last browsing
+    // is just passed along
+    let current_dir = session.as_ref().and_then(|s| s.current_dir.clone());
+    let player = Service::spawn(config, session, launch.resume, requests);
+    let state = match status(&handle) {
+        Ok(state) => state,
+        Err(err) => {
+            let _ = fs::remove_file(&path);
+            return Err(err);
+        }
+    };
+    for request in launch.requests(&state) {
+        if let Err(msg) = handle.call(request).into_result() {
+            eprintln!("{}", msg);
+        }
+    }
+    eprintln!("Listening on {}", path.display());
+    // Kept alive for as long as the daemon runs
+    let _mpris = match config.mpris {
+        true => mpris::start(handle.clone()).map_err(|err| eprintln!("MPRIS is not available: {}", err)).ok(),
+        false => None,
+    };
+    if config.mpd.enabled {
+        match mpd::start(handle.clone(), &config.mpd, config.music_roots.first().cloned()) {
+            Ok(address) => eprintln!("MPD clients can connect to {}", address),
+            Err(err) => eprintln!("Could not start the MPD server: {}", err),
+        }
+    }
+    if config.http.enabled {
+        match http::start(handle.clone(), &config.http, config.music_roots.clone()) {
+            Ok(address) => eprintln!("The HTTP API is at http://{}/api/", address),
+            Err(err) => eprintln!("Could not start the HTTP API: {}", err),
+        }
+    }
+
+    // The player has its own thread; this one just saves the session now
+    // and then until the player shuts down
+    let events = handle.subscribe();
+    let mut last_save = Instant::now();
+    while !matches!(events.recv_timeout(SESSION_SAVE_INTERVAL), Err(RecvTimeoutError::Disconnected)) {
+        if last_save.elapsed() >= SESSION_SAVE_INTERVAL {
+            if let Ok(state) = status(&handle) {
+                let _ = Session::capture(&state, current_dir.clone()).save();
+            }
+            last_save = Instant::now();
+        }
+    }
+    let state = player.join().map_err(|_| Error::other("the player stopped working"))?;
+    let _ = Session::capture(&state, current_dir).save();
+    fs::remove_file(path)
+}
+
+fn status(handle: &Handle) -> Result<PlayerState, Error> {
+    handle.call(Request::Status { since: None }).into_result()
+        .map_err(Error::other)?
+        .state
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "the player sent no state"))
+}
+
+fn serve(listener: UnixListener, handle: Handle) {
+    for stream in listener.incoming().flatten() {
+        let handle = handle.clone();
+        thread::spawn(move || {
+            // The client hanging up is the normal way for this to end
+            let _ = serve_client(stream, handle);
+        });
+    }
+}
+
+// Answers each line the client sends with one line of JSON.
+fn serve_client(stream: UnixStream, handle: Handle) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle.call(request),
+            Err(err) => Response::error(format!("invalid request: {}", err)),
+        };
+        let mut data = serde_json::to_string(&response).map_err(Error::from)?;
+        data.push('\n');
+        writer.write_all(data.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::client::Client;
+
+    // A socket path of the test's own, with nothing left there from a
+    // previous run.
+    fn socket(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("tasco-{}-{}.sock", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn a_stale_socket_is_replaced_but_a_live_one_is_not() {
+        let path = socket("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = bind(&path).unwrap();
+        assert_eq!(bind(&path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requests_and_responses_round_trip() {
+        let path = socket("round-trip");
+        let (handle, requests) = Handle::new();
+        let player = Service::spawn(&Config::default(), None, false, requests);
+        listen_at(handle, &path).unwrap();
+
+        let mut client = Client::connect_to(&path).unwrap();
+        assert!(client.send(&Request::Volume { percent: 40 }).unwrap().ok);
+        let state = client.send(&Request::Status { since: None }).unwrap().state.unwrap();
+        assert_eq!(state.volume, 40);
+        let refused = client.send(&Request::Play { index: Some(2) }).unwrap();
+        assert_eq!(refused.error.as_deref(), Some("the playlist only has 0 entries"));
+
+        let mut raw = UnixStream::connect(&path).unwrap();
+        raw.write_all(b"{\"cmd\":\"dance\"}\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(raw).read_line(&mut line).unwrap();
+        let response: Response = serde_json::from_str(&line).unwrap();
+        assert!(response.error.unwrap().starts_with("invalid request"));
+
+        assert!(client.send(&Request::Shutdown).unwrap().ok);
+        assert_eq!(player.join().unwrap().volume, 40);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::Path;
+use std::time::Duration;
+
+use crate::protocol::{Playback, PlayerState};
+use crate::tags::Tags;
+
+const TAG_FIELDS: [&str;4] = ["{title}", "{artist}", "{album}", "{track}"];
+const TRACK_FIELDS: [&str;6] = ["{name}", "{path}", "{title}", "{artist}", "{album}", "{track}"];
+const STATUS_FIELDS: [&str;8] = ["{state}", "{position}", "{duration}", "{volume}", "{repeat}", "{shuffle}", "{index}", "{count}"];
+
+/// Fills in a display template such as `"{artist} - {title}"`. Supported
+/// fields are `{name}`, `{path}`, `{title}`, `{artist}`, `{album}` and
+/// `{track}`. When the template uses tags but the file has none of them, the
+/// plain file name is shown instead of an empty line.
+pub fn format_track(template: &str, name: &str, path: &Path) -> String {
+    let uses_tags = TAG_FIELDS.iter().any(|field| template.contains(field));
+    let mut text = template
+        .replace("{name}", name)
+        .replace("{path}", &path.to_string_lossy());
+    if uses_tags {
+        let tags = Tags::read(path);
+        if tags.title.is_none() && tags.artist.is_none() && tags.album.is_none() && tags.track.is_none() {
+            return name.to_string();
+        }
+        text = text
+            .replace("{title}", tags.title.as_deref().unwrap_or(name))
+            .replace("{artist}", tags.artist.as_deref().unwrap_or("Unknown Artist"))
+            .replace("{album}", tags.album.as_deref().unwrap_or("Unknown Album"))
+            .replace("{track}", &tags.track.map(|t| format!("{:0>2}", t)).unwrap_or_default());
+    }
+    text
+}
+
+/// Fills in a `ctl status --format` template. On top of the track fields of
+/// [`format_track`] it knows `{state}`, `{position}`, `{duration}`,
+/// `{volume}`, `{repeat}`, `{shuffle}`, `{index}` (counting from 1) and
+/// `{count}`. Track fields are empty when nothing is playing.
+pub fn format_status(template: &str, state: &PlayerState) -> String {
+    let playback = match state.playback {
+        Playback::Stopped => "stopped",
+        Playback::Playing => "playing",
+        Playback::Paused => "paused",
+    };
+    let text = template
+        .replace("{state}", playback)
+        .replace("{position}", &format_time(state.current_time))
+        .replace("{duration}", &format_time(state.total_time))
+        .replace("{volume}", &state.volume.to_string())
+        .replace("{repeat}", state.repeat.name())
+        .replace("{shuffle}", if state.shuffle { "on" } else { "off" })
+        .replace("{index}", &state.playing_index.map(|i| (i + 1).to_string()).unwrap_or_default())
+        .replace("{count}", &state.play_list.len().to_string());
+    let playing = state.playing_index.and_then(|i| state.play_list.get(i));
+    let (name, path) = match playing {
+        Some((name, path)) if state.is_playing_or_paused() => (name, path),
+        _ => return TRACK_FIELDS.iter().fold(text, |text, field| text.replace(field, "")),
+    };
+    // Unlike `format_track` this never swaps the whole line for the file
+    // name, since the template has more in it than the track.
+    let tags = Tags::read(path);
+    text.replace("{name}", name)
+        .replace("{path}", &path.to_string_lossy())
+        .replace("{title}", state.stream_title.as_deref().or(tags.title.as_deref()).unwrap_or(name))
+        .replace("{artist}", tags.artist.as_deref().unwrap_or("Unknown Artist"))
+        .replace("{album}", tags.album.as_deref().unwrap_or("Unknown Album"))
+        .replace("{track}", &tags.track.map(|t| format!("{:0>2}", t)).unwrap_or_default())
+}
+
+/// `m:ss`, or `h:mm:ss` from an hour up.
+pub fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:0>2}", secs / 60, secs % 60),
+        hours => format!("{}:{:0>2}:{:0>2}", hours, secs / 60 % 60, secs % 60),
+    }
+}
+
+/// Undoes the `%XX` escapes of URIs. Invalid UTF-8 is replaced rather than
+/// rejected.
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i+1..i+3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Checks that a template only uses fields `format_track` knows about.
+pub fn validate_template(template: &str) -> Result<(), String> {
+    check_fields(template, &TRACK_FIELDS)
+}
+
+/// Checks that a template only uses fields `format_status` knows about.
+pub fn validate_status_template(template: &str) -> Result<(), String> {
+    check_fields(template, &[&TRACK_FIELDS[..], &STATUS_FIELDS[..]].concat())
+}
+
+fn check_fields(template: &str, known: &[&str]) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}')
+            .ok_or_else(|| format!("unclosed \"{{\" in \"{}\"", template))?;
+        let field = &rest[start..start+end+1];
+        if !known.contains(&field) {
+            return Err(format!("unknown field {} in \"{}\"", field, template));
+        }
+        rest = &rest[start+end+1..];
+    }
+    Ok(())
+}
@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flume::RecvTimeoutError;
+use serde_json::{json, Value};
+
+use crate::config::HttpConfig;
+use crate::format::percent_decode;
+use crate::play_controller::song_duration;
+use crate::playlist_file::is_playlist_file;
+use crate::protocol::{Change, PlayerEvent, PlayerState, Request};
+use crate::scan::{is_music_file, spawn_scan_within, ScanEvent};
+use crate::service::Handle;
+use crate::tags::Tags;
+
+// How long an event stream may stay quiet before a comment goes out to see
+// if the client is still there.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// How long a scan of the roots answers searches before they are scanned
+// again.
+const LIBRARY_MAX_AGE: Duration = Duration::from_secs(60);
+
+// The request line and the headers together may take up `MAX_HEAD` bytes.
+const MAX_HEAD: u64 = 16 << 10;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY: usize = 1 << 20;
+const MAX_SEARCH_RESULTS: usize = 200;
+
+/// Starts the HTTP API on the configured address. `GET` requests read the
+/// player and the library under `roots`, `POST /api/<cmd>` sends any
+/// control socket request, and `/api/events` streams changes. Only requests
+/// for `localhost` or an IP address are answered, so web pages can't reach
+/// the API by pointing a name of their own at this machine.
+pub fn start(handle: Handle, config: &HttpConfig, roots: Vec<PathBuf>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((config.address.as_str(), config.port))?;
+    let address = listener.local_addr()?;
+    let library = Library::default();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let server = Server { handle: handle.clone(), roots: roots.clone(), library: library.clone() };
+            thread::spawn(move || {
+                // The client hanging up is the normal way for this to end
+                let _ = server.serve(stream);
+            });
+        }
+    });
+    Ok(address)
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    host: Option<String>,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+// An error status and what went wrong.
+type Failure = (u16, String);
+
+// The music files found by the last scan of the roots, and when it was
+// made. Shared by all connections.
+type Library = Arc<Mutex<Songs>>;
+
+#[derive(Default)]
+struct Songs {
+    scanned: Option<(Instant, Arc<Vec<PathBuf>>)>,
+    // Set while a connection scans the roots again
+    scanning: bool,
+}
+
+struct Server {
+    handle: Handle,
+    roots: Vec<PathBuf>,
+    library: Library,
+}
+
+impl Server {
+    // Answers one request per connection.
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let request = match read_request(&mut BufReader::new(stream)) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err((status, msg)) => {
+                write_reply(&mut writer, status, &error(msg))?;
+                // Closing with the rest of the request unread would reset
+                // the connection, and the client could lose the reply
+                writer.shutdown(Shutdown::Write)?;
+                writer.set_read_timeout(Some(Duration::from_secs(1)))?;
+                io::copy(&mut (&writer).take(MAX_BODY as u64), &mut io::sink())?;
+                return Ok(());
+            },
+        };
+        if !request.host.as_deref().is_some_and(is_local_host) {
+            return write_reply(&mut writer, 403, &error("requests must be for localhost or an IP address".to_string()));
+        }
+        if (request.method.as_str(), request.path.as_str()) == ("GET", "/api/events") {
+            return self.events(writer);
+        }
+        match self.route(&request) {
+            Ok(body) => write_reply(&mut writer, 200, &body),
+            Err((status, msg)) => write_reply(&mut writer, status, &error(msg)),
+        }
+    }
+
+    fn route(&self, request: &HttpRequest) -> Result<Value, Failure> {
+        let param = |name: &str| request.query.get(name).map(String::as_str);
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/api/status") => Ok(json!(self.status()?)),
+            ("GET", "/api/queue") => {
+                let entries: Vec<Value> = self.status()?.play_list.into_iter().enumerate()
+                    .map(|(index, (name, path))| json!({ "index": index, "name": name, "path": path }))
+                    .collect();
+                Ok(json!(entries))
+            },
+            ("GET", "/api/library") => self.browse(param("path")),
+            ("GET", "/api/search") => Ok(self.search(param("q").unwrap_or_default())),
+            ("POST", path) if path.starts_with("/api/") => self.command(&path["/api/".len()..], request),
+            (_, "/api/status" | "/api/queue" | "/api/library" | "/api/search" | "/api/events") => {
+                Err((405, format!("{} is not allowed here", request.method)))
+            },
+            (_, path) => Err((404, format!("no such endpoint: {}", path))),
+        }
+    }
+
+    fn status(&self) -> Result<PlayerState, Failure> {
+        let response = self.handle.call(Request::Status { since: None }).into_result().map_err(|msg| (500, msg))?;
+        response.state.ok_or_else(|| (500, "no status".to_string()))
+    }
+
+    // The body is a control socket request without its `cmd`, which comes
+    // from the path instead. Requiring JSON keeps web pages from sending
+    // commands with plain forms.
+    fn command(&self, cmd: &str, request: &HttpRequest) -> Result<Value, Failure> {
+        if request.content_type.as_deref().map(|t| t.split(';').next().unwrap_or_default().trim()) != Some("application/json") {
+            return Err((415, "commands must be sent as application/json".to_string()));
+        }
+        let mut body = match request.body.is_empty() {
+            true => json!({}),
+            false => serde_json::from_slice(&request.body).map_err(|err| (400, format!("invalid JSON: {}", err)))?,
+        };
+        let Some(fields) = body.as_object_mut() else {
+            return Err((400, "the body must be a JSON object".to_string()));
+        };
+        fields.insert("cmd".to_string(), json!(cmd));
+        let request: Request = serde_json::from_value(body).map_err(|err| (400, format!("invalid request: {}", err)))?;
+        let response = self.handle.call(request);
+        match response.ok {
+            true => Ok(json!(response)),
+            false => Err((400, response.error.unwrap_or_default())),
+        }
+    }
+
+    // The music roots, or what is in one of the directories under them.
+    fn browse(&self, dir: Option<&str>) -> Result<Value, Failure> {
+        let Some(dir) = dir else {
+            let roots: Vec<Value> = self.roots.iter().map(|root| entry("directory", root)).collect();
+            return Ok(json!(roots));
+        };
+        let dir = Path::new(dir);
+        let outside = || (403, format!("{} is not in a music root", dir.display()));
+        if dir.components().any(|c| c == Component::ParentDir) || !self.roots.iter().any(|root| dir.starts_with(root)) {
+            return Err(outside());
+        }
+        // Symlinks under a root can point anywhere, so where they lead is
+        // checked too
+        let roots: Vec<PathBuf> = self.roots.iter().filter_map(|root| fs::canonicalize(root).ok()).collect();
+        let inside = |path: &Path| fs::canonicalize(path).is_ok_and(|real| roots.iter().any(|root| real.starts_with(root)));
+        let read = fs::read_dir(dir).map_err(|err| (404, format!("could not read {}: {}", dir.display(), err)))?;
+        if !inside(dir) {
+            return Err(outside());
+        }
+        let mut paths: Vec<PathBuf> = read.flatten()
+            .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+            .map(|e| e.path())
+            .filter(|path| inside(path))
+            .collect();
+        paths.sort();
+        let entries: Vec<Value> = paths.iter().filter_map(|path| {
+            if path.is_dir() {
+                Some(entry("directory", path))
+            }
+            else if is_playlist_file(path) {
+                Some(entry("playlist", path))
+            }
+            else if is_music_file(path) {
+                Some(track(path))
+            }
+            else {
+                None
+            }
+        }).collect();
+        Ok(json!(entries))
+    }
+
+    // Music files under the roots whose path contains every word of
+    // `query`, ignoring case.
+    fn search(&self, query: &str) -> Value {
+        let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        let found: Vec<Value> = self.songs().iter()
+            .filter(|path| {
+                let path = path.to_string_lossy().to_lowercase();
+                words.iter().all(|word| path.contains(word))
+            })
+            .take(MAX_SEARCH_RESULTS)
+            .map(|path| track(path))
+            .collect();
+        json!(found)
+    }
+
+    // The music files under the roots, scanned again once the last scan is
+    // `LIBRARY_MAX_AGE` old. The lock isn't held while scanning, and
+    // searches that come in meanwhile are answered from the older scan.
+    fn songs(&self) -> Arc<Vec<PathBuf>> {
+        {
+            let mut library = self.library.lock().unwrap();
+            if let Some((scanned, songs)) = &library.scanned {
+                if scanned.elapsed() < LIBRARY_MAX_AGE || library.scanning {
+                    return songs.clone();
+                }
+            }
+            library.scanning = true;
+        }
+        // Each root is scanned on its own so symlinks can't lead out of it
+        let songs: Vec<PathBuf> = self.roots.iter()
+            .filter_map(|root| Some((root, fs::canonicalize(root).ok()?)))
+            .flat_map(|(root, real)| {
+                spawn_scan_within(vec![(String::new(), root.clone())], real).iter()
+                    .find_map(|event| match event {
+                        ScanEvent::Done(songs) => Some(songs),
+                        ScanEvent::Progress(_) => None,
+                    })
+                    .unwrap_or_default()
+            })
+            .map(|(_, path)| path)
+            .collect();
+        let songs = Arc::new(songs);
+        let mut library = self.library.lock().unwrap();
+        *library = Songs { scanned: Some((Instant::now(), Arc::clone(&songs))), scanning: false };
+        songs
+    }
+
+    // Server-sent events: the whole state once as `status`, then an event
+    // named after each kind of change as the player sends it.
+    fn events(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")?;
+        // Subscribed first, so nothing that happens after the status is missed
+        let events = self.handle.subscribe();
+        let Ok(mut seen) = self.status() else { return Ok(()) };
+        write_event(&mut stream, "status", &json!(seen))?;
+        loop {
+            match events.recv_timeout(KEEP_ALIVE) {
+                Ok(PlayerEvent::Changed(change)) => {
+                    let since = Some(seen.playlist_version);
+                    // The player has shut down
+                    let Some(newer) = self.handle.call(Request::Status { since }).state else { return Ok(()) };
+                    seen.update(newer);
+                    let (name, data) = event(change, &seen);
+                    write_event(&mut stream, name, &data)?;
+                },
+                Ok(PlayerEvent::Seeked(position)) => {
+                    write_event(&mut stream, "seeked", &json!({ "position": position.as_secs_f64() }))?;
+                },
+                Err(RecvTimeoutError::Timeout) => stream.write_all(b": ping\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
+
+fn event(change: Change, state: &PlayerState) -> (&'static str, Value) {
+    match change {
+        Change::Playlist => ("playlist", json!({ "version": state.playlist_version, "length": state.play_list.len() })),
+        Change::Track => {
+            let mut data = state.playing_path.as_deref().map_or(Value::Null, track);
+            if let Some(fields) = data.as_object_mut() {
+                fields.insert("index".to_string(), json!(state.playing_index));
+                if let Some(title) = &state.stream_title {
+                    fields.insert("title".to_string(), json!(title));
+                }
+            }
+            ("track", data)
+        },
+        Change::Playback => ("playback", json!({ "state": state.playback })),
+        Change::Volume => ("volume", json!({ "percent": state.volume })),
+        Change::Options => ("options", json!({ "repeat": state.repeat, "shuffle": state.shuffle })),
+        Change::Scan => ("scan", json!({ "found": state.scanning })),
+    }
+}
+
+fn entry(kind: &str, path: &Path) -> Value {
+    json!({ "type": kind, "name": file_name(path), "path": path })
+}
+
+// A music file with its tags and length.
+fn track(path: &Path) -> Value {
+    let tags = Tags::read(path);
+    json!({
+        "type": "file",
+        "name": file_name(path),
+        "path": path,
+        "title": tags.title,
+        "artist": tags.artist,
+        "album": tags.album,
+        "track": tags.track,
+        "duration": song_duration(path).map(|d| d.as_secs_f64()),
+    })
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn error(msg: String) -> Value {
+    json!({ "ok": false, "error": msg })
+}
+
+// Whether a `Host` header names this machine the way only local programs
+// and people who know its address would. A name that resolves here could
+// belong to anyone's web page.
+fn is_local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.rsplit_once(':').map_or(host, |(name, _)| name),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok()
+}
+
+// Reads the request line, the headers and the body. Returns `None` when the
+// client hangs up before sending anything.
+fn read_request(reader: &mut BufReader<TcpStream>) -> Result<Option<HttpRequest>, Failure> {
+    let bad = |msg: &str| (400, msg.to_string());
+    let too_long = || (431, format!("the request line and headers may be at most {} bytes", MAX_HEAD));
+    let mut head = reader.take(MAX_HEAD);
+    let mut line = String::new();
+    if head.read_line(&mut line).map_err(|_| bad("could not read the request"))? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(too_long());
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(bad("malformed request line"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: parse_query(query),
+        host: None,
+        content_type: None,
+        body: vec![],
+    };
+    let mut length = 0;
+    for _ in 0..MAX_HEADERS {
+        let mut header = String::new();
+        head.read_line(&mut header).map_err(|_| bad("could not read the headers"))?;
+        if !header.ends_with('\n') {
+            return Err(match head.limit() {
+                0 => too_long(),
+                _ => bad("the headers end early"),
+            });
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length > MAX_BODY {
+                return Err((413, format!("the body may be at most {} bytes", MAX_BODY)));
+            }
+            request.body = vec![0; length];
+            head.into_inner().read_exact(&mut request.body).map_err(|_| bad("the body is shorter than its Content-Length"))?;
+            return Ok(Some(request));
+        }
+        let Some((name, value)) = header.split_once(':') else { return Err(bad("malformed header")) };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => length = value.trim().parse().map_err(|_| bad("invalid Content-Length"))?,
+            "content-type" => request.content_type = Some(value.trim().to_ascii_lowercase()),
+            "host" => request.host = Some(value.trim().to_string()),
+            _ => {},
+        }
+    }
+    Err((431, "too many headers".to_string()))
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    let decode = |text: &str| percent_decode(&text.replace('+', " "));
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+fn write_reply(stream: &mut TcpStream, status: u16, body: &Value) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body)
+}
+
+fn write_event(stream: &mut TcpStream, name: &str, data: &Value) -> io::Result<()> {
+    write!(stream, "event: {}\ndata: {}\n\n", name, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::service::Service;
+
+    // A music root holding one silent WAV file.
+    fn music_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tasco-{}-http-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let wav = b"RIFF\x24\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0\x44\xac\0\0\x88\x58\x01\0\x02\0\x10\0data\0\0\0\0";
+        fs::write(dir.join("song.wav"), wav).unwrap();
+        dir
+    }
+
+    fn start_player(root: &Path) -> SocketAddr {
+        let (handle, requests) = Handle::new();
+        Service::spawn(&Config::default(), None, false, requests);
+        let config = HttpConfig { enabled: true, address: "127.0.0.1".to_string(), port: 0 };
+        start(handle, &config, vec![root.to_path_buf()]).unwrap()
+    }
+
+    // Sends `head` and `body` as they are, returning the status and the
+    // reply's body.
+    fn send(address: SocketAddr, head: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "{}Content-Length: {}\r\n\r\n{}", head, body.len(), body).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        let status = reply[9..12].parse().unwrap();
+        let (_, body) = reply.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    fn get(address: SocketAddr, target: &str) -> (u16, Value) {
+        send(address, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n", target), "")
+    }
+
+    fn post(address: SocketAddr, cmd: &str, body: &str) -> (u16, Value) {
+        let head = format!("POST /api/{} HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\n", cmd);
+        send(address, &head, body)
+    }
+
+    #[test]
+    fn endpoints_read_and_drive_the_player() {
+        let root = music_dir("rest");
+        let address = start_player(&root);
+        let song = root.join("song.wav");
+
+        let (status, state) = get(address, "/api/status");
+        assert_eq!((status, state["volume"].clone()), (200, json!(100)));
+        assert_eq!(post(address, "volume", r#"{"percent": 40}"#).0, 200);
+        assert_eq!(get(address, "/api/status").1["volume"], json!(40));
+        assert_eq!(post(address, "volume", r#"{"percent": "loud"}"#).0, 400);
+
+        let (status, entries) = get(address, &format!("/api/library?path={}", root.display()));
+        assert_eq!((status, entries[0]["name"].clone()), (200, json!("song.wav")));
+        assert_eq!(get(address, "/api/library?path=/etc").0, 403);
+        let (_, found) = get(address, "/api/search?q=SONG+wav");
+        assert_eq!(found[0]["path"], json!(song));
+        assert_eq!(get(address, "/api/search?q=nothing").1, json!([]));
+
+        assert_eq!(post(address, "enqueue", &json!({ "paths": [song] }).to_string()).0, 200);
+        let (_, queue) = get(address, "/api/queue");
+        assert_eq!(queue, json!([{ "index": 0, "name": "song.wav", "path": song }]));
+
+        assert_eq!(get(address, "/api/nothing").0, 404);
+        assert_eq!(send(address, "DELETE /api/queue HTTP/1.1\r\nHost: localhost\r\n", "").0, 405);
+        let form = "POST /api/stop HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-www-form-urlencoded\r\n";
+        assert_eq!(send(address, form, "").0, 415);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn symlinks_out_of_a_root_are_not_followed() {
+        let root = music_dir("links");
+        let elsewhere = music_dir("elsewhere");
+        fs::rename(elsewhere.join("song.wav"), elsewhere.join("secret.wav")).unwrap();
+        symlink(&elsewhere, root.join("away")).unwrap();
+        symlink(elsewhere.join("secret.wav"), root.join("leak.wav")).unwrap();
+        let address = start_player(&root);
+
+        let (_, entries) = get(address, &format!("/api/library?path={}", root.display()));
+        let names: Vec<&Value> = entries.as_array().unwrap().iter().map(|entry| &entry["name"]).collect();
+        assert_eq!(names, [&json!("song.wav")]);
+        assert_eq!(get(address, &format!("/api/library?path={}", root.join("away").display())).0, 403);
+        assert_eq!(get(address, "/api/search?q=secret").1, json!([]));
+        assert_eq!(get(address, "/api/search?q=wav").1.as_array().unwrap().len(), 1);
+        fs::remove_dir_all(root).unwrap();
+        fs::remove_dir_all(elsewhere).unwrap();
+    }
+
+    #[test]
+    fn requests_for_other_hosts_or_too_big_are_refused() {
+        let root = music_dir("refused");
+        let address = start_player(&root);
+        for host in ["localhost:8080", "127.0.0.1", "[::1]:80", "192.168.1.2:6680"] {
+            assert_eq!(send(address, &format!("GET /api/status HTTP/1.1\r\nHost: {}\r\n", host), "").0, 200, "{}", host);
+        }
+        for head in ["GET /api/status HTTP/1.1\r\nHost: evil.example\r\n", "GET /api/status HTTP/1.1\r\n"] {
+            assert_eq!(send(address, head, "").0, 403);
+        }
+        let padding = "x".repeat(MAX_HEAD as usize);
+        let (status, _) = send(address, &format!("GET /api/status HTTP/1.1\r\nHost: localhost\r\nX-Pad: {}\r\n", padding), "");
+        assert_eq!(status, 431);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn events_are_framed_as_they_happen() {
+        let root = music_dir("events");
+        let address = start_player(&root);
+        let stream = TcpStream::connect(address).unwrap();
+        write!(&stream, "GET /api/events HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert!(!line.is_empty());
+        }
+        // Each event is its name, its data on one line and a blank line
+        let mut next_event = || {
+            let mut lines = [String::new(), String::new(), String::new()];
+            for line in &mut lines {
+                reader.read_line(line).unwrap();
+            }
+            assert_eq!(lines[2], "\n");
+            let name = lines[0].strip_prefix("event: ").unwrap().trim_end().to_string();
+            let data: Value = serde_json::from_str(lines[1].strip_prefix("data: ").unwrap()).unwrap();
+            (name, data)
+        };
+        let (name, state) = next_event();
+        assert_eq!((name.as_str(), state["volume"].clone()), ("status", json!(100)));
+        assert_eq!(post(address, "volume", r#"{"percent": 30}"#).0, 200);
+        assert_eq!(next_event(), ("volume".to_string(), json!({ "percent": 30 })));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
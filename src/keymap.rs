@@ -0,0 +1,371 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::{KeyBinding, RawKey};
+
+// Longest run of characters that may be written without spaces as a key
+// sequence, like "gg"
+const MAX_RUN_TOGETHER: usize = 2;
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    GoTop,
+    GoBottom,
+    ExtendUp,
+    ExtendDown,
+    SwitchFocus,
+    ToggleMark,
+    ClearMarks,
+    Activate,
+    PlaySelected,
+    PlayNext,
+    AddRecursive,
+    NextBookmark,
+    TogglePause,
+    Next,
+    VolumeDown,
+    VolumeUp,
+    CycleRepeat,
+    ToggleShuffle,
+    Remove,
+    Cut,
+    Paste,
+    MoveEntryUp,
+    MoveEntryDown,
+    Dedup,
+    Sort,
+    ClearPlaylist,
+    CycleLayout,
+    Command,
+    Help,
+    Quit,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Context {
+    General,
+    Explorer,
+    Playlist,
+    Playback,
+}
+
+const CONTEXTS: [(Context, &str); 4] = [
+    (Context::General, "General"),
+    (Context::Explorer, "Explorer"),
+    (Context::Playlist, "Playlist"),
+    (Context::Playback, "Playback"),
+];
+
+// Name used in the config file, where the action applies, and a short
+// description for the help screen.
+const ACTIONS: [(Action, &str, Context, &str); 32] = [
+    (Action::MoveUp, "up", Context::General, "Move the cursor up"),
+    (Action::MoveDown, "down", Context::General, "Move the cursor down"),
+    (Action::GoTop, "top", Context::General, "Jump to the first entry"),
+    (Action::GoBottom, "bottom", Context::General, "Jump to the last entry"),
+    (Action::ExtendUp, "extend_up", Context::General, "Extend the selection up"),
+    (Action::ExtendDown, "extend_down", Context::General, "Extend the selection down"),
+    (Action::SwitchFocus, "switch_focus", Context::General, "Switch between explorer and playlist"),
+    (Action::ToggleMark, "mark", Context::General, "Mark or unmark the entry under the cursor"),
+    (Action::ClearMarks, "clear_marks", Context::General, "Unmark everything"),
+    (Action::CycleLayout, "layout", Context::General, "Switch to the next layout preset"),
+    (Action::Command, "command", Context::General, "Open the command line"),
+    (Action::Help, "help", Context::General, "Show this help"),
+    (Action::Quit, "quit", Context::General, "Quit"),
+    (Action::Activate, "activate", Context::Explorer, "Open directory or enqueue file; in the playlist, play it"),
+    (Action::PlayNext, "play_next", Context::Explorer, "Insert after the current track"),
+    (Action::AddRecursive, "add_recursive", Context::Explorer, "Enqueue a directory and its subdirectories"),
+    (Action::NextBookmark, "bookmark", Context::Explorer, "Jump to the next directory from the command line or music_roots"),
+    (Action::PlaySelected, "play", Context::Playlist, "Play the selected entry now"),
+    (Action::Remove, "remove", Context::Playlist, "Remove entries from the playlist"),
+    (Action::Cut, "cut", Context::Playlist, "Cut entries from the playlist"),
+    (Action::Paste, "paste", Context::Playlist, "Paste cut entries after the cursor"),
+    (Action::MoveEntryUp, "move_entry_up", Context::Playlist, "Move the playlist entry up"),
+    (Action::MoveEntryDown, "move_entry_down", Context::Playlist, "Move the playlist entry down"),
+    (Action::Dedup, "dedup", Context::Playlist, "Remove duplicate playlist entries"),
+    (Action::Sort, "sort", Context::Playlist, "Sort the playlist, cycling the sort key"),
+    (Action::ClearPlaylist, "clear", Context::Playlist, "Clear the playlist"),
+    (Action::TogglePause, "toggle", Context::Playback, "Pause or resume playback"),
+    (Action::Next, "next", Context::Playback, "Skip to the next track"),
+    (Action::VolumeDown, "volume_down", Context::Playback, "Lower the volume"),
+    (Action::VolumeUp, "volume_up", Context::Playback, "Raise the volume"),
+    (Action::CycleRepeat, "repeat", Context::Playback, "Cycle repeat mode: off, all, one"),
+    (Action::ToggleShuffle, "shuffle", Context::Playback, "Toggle shuffle"),
+];
+
+const DEFAULT_KEYS: [(&str, Action); 37] = [
+    ("up", Action::MoveUp),
+    ("down", Action::MoveDown),
+    ("gg", Action::GoTop),
+    ("G", Action::GoBottom),
+    ("shift+up", Action::ExtendUp),
+    ("shift+down", Action::ExtendDown),
+    ("tab", Action::SwitchFocus),
+    ("backtab", Action::SwitchFocus),
+    ("shift+backtab", Action::SwitchFocus),
+    ("space", Action::ToggleMark),
+    ("esc", Action::ClearMarks),
+    ("enter", Action::Activate),
+    ("s", Action::PlaySelected),
+    ("a", Action::PlayNext),
+    ("r", Action::AddRecursive),
+    ("b", Action::NextBookmark),
+    ("P", Action::TogglePause),
+    ("n", Action::Next),
+    ("left", Action::VolumeDown),
+    ("right", Action::VolumeUp),
+    ("R", Action::CycleRepeat),
+    ("S", Action::ToggleShuffle),
+    ("d", Action::Remove),
+    ("delete", Action::Remove),
+    ("x", Action::Cut),
+    ("p", Action::Paste),
+    ("ctrl+up", Action::MoveEntryUp),
+    ("ctrl+down", Action::MoveEntryDown),
+    ("u", Action::Dedup),
+    ("o", Action::Sort),
+    ("c", Action::ClearPlaylist),
+    ("L", Action::CycleLayout),
+    (":", Action::Command),
+    ("h", Action::Help),
+    ("?", Action::Help),
+    ("q", Action::Quit),
+    ("ctrl+c", Action::Quit),
+];
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        ACTIONS.iter().find(|a| a.0 == self).map(|a| a.1).unwrap_or_default()
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTIONS.iter().find(|a| a.1 == name).map(|a| a.0)
+    }
+}
+
+pub enum Lookup {
+    Action(Action),
+    /// The keys so far start a longer sequence.
+    Pending,
+    NoMatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: HashMap<Vec<KeyBinding>, Action>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = DEFAULT_KEYS.iter()
+            .map(|(spec, action)| (parse_sequence(spec).unwrap(), *action))
+            .collect();
+        Self { bindings }
+    }
+}
+
+impl Keymap {
+    /// Builds the default keymap with the user's `[keys]` table applied on
+    /// top. Binding a key to `"none"` removes the default binding.
+    pub fn new(overrides: &BTreeMap<String, String>) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        let mut seen: HashMap<Vec<KeyBinding>, &str> = HashMap::new();
+        for (spec, name) in overrides {
+            let keys = parse_sequence(spec)
+                .map_err(|err| format!("invalid key \"{}\" in [keys]: {}", spec, err))?;
+            if let Some(other) = seen.insert(keys.clone(), spec) {
+                return Err(format!("\"{}\" and \"{}\" in [keys] are the same key", other, spec));
+            }
+            if name == "none" {
+                keymap.bindings.remove(&keys);
+                continue;
+            }
+            let action = Action::from_name(name)
+                .ok_or_else(|| format!("unknown action \"{}\" for key \"{}\"", name, spec))?;
+            keymap.bindings.insert(keys, action);
+        }
+        keymap.check_prefixes()?;
+        Ok(keymap)
+    }
+
+    // A binding that is a prefix of another would fire before the longer
+    // sequence could ever be completed. The bindings are gone through in
+    // order so that the same conflict is reported every time.
+    fn check_prefixes(&self) -> Result<(), String> {
+        let mut bindings: Vec<_> = self.bindings.iter().collect();
+        bindings.sort_by_key(|(keys, _)| sequence_name(keys));
+        for &(short, short_action) in &bindings {
+            for &(long, long_action) in &bindings {
+                if long.len() > short.len() && long.starts_with(short) {
+                    return Err(format!(
+                        "key \"{}\" ({}) conflicts with \"{}\" ({}); bind one of them to \"none\"",
+                        sequence_name(short), short_action.name(),
+                        sequence_name(long), long_action.name()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The keymap as help text: one `(keys, description)` row per bound
+    /// action, grouped under the context headings.
+    pub fn help(&self) -> Vec<(&'static str, Vec<(String, &'static str)>)> {
+        CONTEXTS.iter().map(|(context, heading)| {
+            let rows = ACTIONS.iter()
+                .filter(|a| a.2 == *context)
+                .filter_map(|(action, _, _, description)| {
+                    let keys = self.keys_for(*action);
+                    if keys.is_empty() { None } else { Some((keys.join(", "), *description)) }
+                })
+                .collect();
+            (*heading, rows)
+        }).collect()
+    }
+
+    /// The shortest key bound to `action`, for hints on screen. Letters win
+    /// over symbols of the same length.
+    pub fn short_key(&self, action: Action) -> Option<String> {
+        self.keys_for(action).into_iter()
+            .min_by_key(|key| (key.chars().count(), !key.starts_with(char::is_alphanumeric)))
+    }
+
+    fn keys_for(&self, action: Action) -> Vec<String> {
+        let mut keys: Vec<String> = self.bindings.iter()
+            .filter(|(_, a)| **a == action)
+            .map(|(seq, _)| sequence_name(seq))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    pub fn lookup(&self, keys: &[KeyBinding]) -> Lookup {
+        if let Some(action) = self.bindings.get(keys) {
+            return Lookup::Action(*action);
+        }
+        if self.bindings.keys().any(|seq| seq.starts_with(keys)) {
+            return Lookup::Pending;
+        }
+        Lookup::NoMatch
+    }
+}
+
+/// Parses `"ctrl+n"`, `"g g"` or `"gg"` into a key sequence. A token that
+/// isn't a key name is read as one key per character, but only up to
+/// `MAX_RUN_TOGETHER` of them, so a misspelt name like `"pgdn"` is an error
+/// instead of four keys.
+pub fn parse_sequence(spec: &str) -> Result<Vec<KeyBinding>, String> {
+    let mut keys = vec![];
+    for token in spec.split_whitespace() {
+        match KeyBinding::parse(token) {
+            Some(key) => keys.push(normalize(key)),
+            None if !token.contains('+') && token.chars().count() <= MAX_RUN_TOGETHER => {
+                keys.extend(token.chars().map(|c| KeyBinding::Raw(RawKey::Char(c))));
+            }
+            None => return Err(format!("unknown key \"{}\"", token)),
+        }
+    }
+    if keys.is_empty() { Err("no keys given".to_string()) } else { Ok(keys) }
+}
+
+/// Terminals report shifted letters as the upper case character, with or
+/// without the shift modifier, so `shift+g`, `G` and shift+`G` all become `G`.
+/// Control combinations only ever arrive lower case.
+pub fn normalize(key: KeyBinding) -> KeyBinding {
+    match key {
+        KeyBinding::Shift(RawKey::Char(c)) => KeyBinding::Raw(RawKey::Char(c.to_ascii_uppercase())),
+        KeyBinding::Ctrl(RawKey::Char(c)) => KeyBinding::Ctrl(RawKey::Char(c.to_ascii_lowercase())),
+        key => key,
+    }
+}
+
+fn sequence_name(keys: &[KeyBinding]) -> String {
+    keys.iter().map(key_name).collect::<Vec<_>>().join(" ")
+}
+
+pub fn key_name(key: &KeyBinding) -> String {
+    let raw = |key: &RawKey| match key {
+        RawKey::Char(' ') => "space".to_string(),
+        RawKey::Char(c) => c.to_string(),
+        other => format!("{:?}", other).to_lowercase(),
+    };
+    match key {
+        KeyBinding::Ctrl(key) => format!("ctrl+{}", raw(key)),
+        KeyBinding::Shift(key) => format!("shift+{}", raw(key)),
+        KeyBinding::Raw(key) => raw(key),
+        KeyBinding::F(n) => format!("f{}", n),
+        KeyBinding::Unsupported => "?".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<KeyBinding> {
+        s.chars().map(|c| KeyBinding::Raw(RawKey::Char(c))).collect()
+    }
+
+    fn overrides(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn sequences_with_and_without_spaces() {
+        assert_eq!(parse_sequence("gg"), Ok(chars("gg")));
+        assert_eq!(parse_sequence("g g"), Ok(chars("gg")));
+        assert_eq!(parse_sequence("ctrl+n"), Ok(vec![KeyBinding::Ctrl(RawKey::Char('n'))]));
+        assert_eq!(parse_sequence("g pageup"), Ok(vec![KeyBinding::Raw(RawKey::Char('g')), KeyBinding::Raw(RawKey::PageUp)]));
+    }
+
+    #[test]
+    fn shifted_letters_are_upper_case() {
+        assert_eq!(parse_sequence("shift+g"), Ok(chars("G")));
+        assert_eq!(parse_sequence("ctrl+N"), Ok(vec![KeyBinding::Ctrl(RawKey::Char('n'))]));
+    }
+
+    #[test]
+    fn misspelt_key_names_are_errors() {
+        assert_eq!(parse_sequence("pgdn"), Err("unknown key \"pgdn\"".to_string()));
+        assert_eq!(parse_sequence("g pgdn"), Err("unknown key \"pgdn\"".to_string()));
+        assert_eq!(parse_sequence("ctrl+foo"), Err("unknown key \"ctrl+foo\"".to_string()));
+        assert!(parse_sequence("  ").is_err());
+        // The Kelvin sign is three bytes but lower-cases to a one-byte k
+        assert!(parse_sequence("ctrl+\u{212A}x").is_err());
+        assert!(parse_sequence("\u{212A}trl+k").is_err());
+    }
+
+    #[test]
+    fn overrides_replace_and_remove_defaults() {
+        let keymap = Keymap::new(&overrides(&[("ctrl+n", "next"), ("d", "none")])).unwrap();
+        assert!(matches!(keymap.lookup(&[KeyBinding::Ctrl(RawKey::Char('n'))]), Lookup::Action(Action::Next)));
+        assert!(matches!(keymap.lookup(&chars("d")), Lookup::NoMatch));
+        assert!(matches!(keymap.lookup(&chars("g")), Lookup::Pending));
+        assert!(matches!(keymap.lookup(&chars("gg")), Lookup::Action(Action::GoTop)));
+    }
+
+    #[test]
+    fn unknown_actions_are_errors() {
+        let err = Keymap::new(&overrides(&[("z", "dance")])).unwrap_err();
+        assert_eq!(err, "unknown action \"dance\" for key \"z\"");
+    }
+
+    #[test]
+    fn the_same_prefix_conflict_is_reported_every_time() {
+        let keys = overrides(&[("g", "next"), ("z", "quit"), ("z z", "help")]);
+        for _ in 0..20 {
+            assert_eq!(Keymap::new(&keys).unwrap_err(),
+                "key \"g\" (next) conflicts with \"g g\" (top); bind one of them to \"none\"");
+        }
+    }
+
+    #[test]
+    fn short_keys_follow_overrides() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.short_key(Action::Quit), Some("q".to_string()));
+        assert_eq!(keymap.short_key(Action::Help), Some("h".to_string()));
+        let keymap = Keymap::new(&overrides(&[("q", "none"), ("ctrl+c", "none"), ("x", "next")])).unwrap();
+        assert_eq!(keymap.short_key(Action::Quit), None);
+        assert_eq!(keymap.short_key(Action::Next), Some("n".to_string()));
+    }
+}
@@ -0,0 +1,124 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use rand::Rng;
+
+use crate::play_controller::RepeatMode;
+use crate::playlist_file::{self, is_playlist_file};
+use crate::protocol::{PlayerState, Request};
+use crate::radio::is_stream;
+use crate::scan::is_music_file;
+
+/// What the command line asked for: directories to browse, tracks to queue
+/// and how to start playing.
+#[derive(Debug, Default)]
+pub struct Launch {
+    /// Directory arguments in order. The first is opened in the explorer and
+    /// all of them can be jumped between as bookmarks.
+    pub dirs: Vec<PathBuf>,
+    /// Audio files, radio streams and the contents of playlist files,
+    /// appended to the queue.
+    pub queue: Vec<(String, PathBuf)>,
+    pub play: bool,
+    pub shuffle: bool,
+    pub repeat: Option<RepeatMode>,
+    pub resume: bool,
+}
+
+impl Launch {
+    pub fn from_args(args: &ArgMatches) -> Result<Launch, String> {
+        let mut launch = Launch {
+            play: args.is_present("play"),
+            shuffle: args.is_present("shuffle"),
+            resume: args.is_present("resume"),
+            ..Launch::default()
+        };
+        if let Some(mode) = args.value_of("repeat") {
+            launch.repeat = Some(RepeatMode::from_name(mode)
+                .ok_or_else(|| format!("invalid repeat mode \"{}\", expected off, all or one", mode))?);
+        }
+        // The player may be a daemon started in another directory, so
+        // relative paths are resolved here
+        let cwd = env::current_dir().map_err(|err| format!("no current directory: {}", err));
+        for arg in args.values_of("dir").into_iter().flatten() {
+            let path = match is_stream(Path::new(arg)) {
+                true => PathBuf::from(arg),
+                false => cwd.clone()?.join(arg),
+            };
+            if is_stream(&path) {
+                launch.queue.push((arg.to_string(), path));
+            }
+            else if path.is_dir() {
+                launch.dirs.push(path);
+            }
+            else if !path.exists() {
+                return Err(format!("No such file or directory: {}", arg));
+            }
+            else if is_playlist_file(&path) {
+                let entries = playlist_file::read(&path)
+                    .map_err(|err| format!("Could not read playlist {}: {}", arg, err))?;
+                launch.queue.extend(entries);
+            }
+            else if is_music_file(&path) {
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                launch.queue.push((name, path));
+            }
+            else {
+                return Err(format!("Not a directory, music file or playlist: {}", arg));
+            }
+        }
+        Ok(launch)
+    }
+
+    /// The directory the explorer opens in: the first directory argument,
+    /// the folder of the first queued file, the directory browsed last time,
+    /// then the music roots, `$XDG_MUSIC_DIR`, `~/Music` and finally the
+    /// current directory. Candidates that are no longer directories are
+    /// skipped.
+    pub fn start_dir(&self, music_roots: &[PathBuf], last_dir: Option<PathBuf>) -> PathBuf {
+        let queued_dir = self.queue.first().and_then(|(_, path)| path.parent()).map(Path::to_path_buf);
+        self.dirs.first().cloned()
+            .into_iter()
+            .chain(queued_dir)
+            .chain(last_dir)
+            .chain(music_roots.iter().cloned())
+            .chain(dirs::audio_dir())
+            .chain(dirs::home_dir().map(|home| home.join("Music")))
+            .find(|dir| dir.is_dir())
+            .or_else(|| env::current_dir().ok())
+            .unwrap_or_else(|| PathBuf::from("."))
+    }
+
+    /// The requests that queue the files and apply --shuffle, --repeat and
+    /// --play on top of the player as it is in `state`.
+    pub fn requests(&self, state: &PlayerState) -> Vec<Request> {
+        let mut requests = vec![];
+        let first_new = state.play_list.len();
+        if !self.queue.is_empty() {
+            requests.push(Request::Append { entries: self.queue.clone() });
+        }
+        if self.shuffle {
+            requests.push(Request::Shuffle { on: Some(true) });
+        }
+        if let Some(mode) = self.repeat {
+            requests.push(Request::Repeat { mode: Some(mode) });
+        }
+        let len = first_new + self.queue.len();
+        if !self.play || len == 0 {
+            return requests;
+        }
+        let candidates = if first_new < len { first_new..len } else { 0..len };
+        let index = if state.shuffle || self.shuffle {
+            rand::thread_rng().gen_range(candidates)
+        }
+        else if first_new < len {
+            first_new
+        }
+        else {
+            state.playlist_index
+        };
+        requests.push(Request::Play { index: Some(index) });
+        requests
+    }
+}
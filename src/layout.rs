@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+
+pub const PRESETS: [&str;3] = ["classic", "minimal", "library"];
+
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Panel {
+    Directory,
+    Controls,
+    NowPlaying,
+    Explorer,
+    Playlist,
+    Wave,
+    Progress,
+    Lyrics,
+    Cover,
+    Library,
+}
+
+impl Panel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Panel::Directory => "directory",
+            Panel::Controls => "controls",
+            Panel::NowPlaying => "now_playing",
+            Panel::Explorer => "explorer",
+            Panel::Playlist => "playlist",
+            Panel::Wave => "wave",
+            Panel::Progress => "progress",
+            Panel::Lyrics => "lyrics",
+            Panel::Cover => "cover",
+            Panel::Library => "library",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Split {
+    Horizontal,
+    Vertical,
+}
+
+/// One box of a layout: either a single panel, or a split whose `panels` are
+/// laid out side by side (horizontal) or on top of each other (vertical).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Node {
+    pub panel: Option<Panel>,
+    #[serde(default = "default_split")]
+    pub split: Split,
+    #[serde(default)]
+    pub panels: Vec<Node>,
+    /// Share of the parent in percent. Nodes without a size or length split
+    /// whatever is left evenly.
+    pub size: Option<u16>,
+    /// Fixed number of rows or columns, used instead of `size`.
+    pub length: Option<u16>,
+}
+
+fn default_split() -> Split {
+    Split::Vertical
+}
+
+impl Node {
+    fn panel(panel: Panel) -> Node {
+        Self { panel: Some(panel), split: Split::Vertical, panels: vec![], size: None, length: None }
+    }
+
+    fn split(split: Split, panels: Vec<Node>) -> Node {
+        Self { panel: None, split, panels, size: None, length: None }
+    }
+
+    fn size(mut self, size: u16) -> Node {
+        self.size = Some(size);
+        self
+    }
+
+    fn length(mut self, length: u16) -> Node {
+        self.length = Some(length);
+        self
+    }
+
+    fn validate(&self, seen: &mut Vec<Panel>) -> Result<(), String> {
+        if let Some(size) = self.size.filter(|&s| s > 100) {
+            return Err(format!("size must be a percentage between 0 and 100, got {}", size));
+        }
+        match self.panel {
+            Some(_) if !self.panels.is_empty() => {
+                Err("a layout entry has either a panel or panels, not both".to_string())
+            }
+            Some(panel) if seen.contains(&panel) => {
+                Err(format!("panel {} appears more than once", panel.name()))
+            }
+            Some(panel) => {
+                seen.push(panel);
+                Ok(())
+            }
+            None if self.panels.is_empty() => {
+                Err("a layout entry needs a panel or a list of panels".to_string())
+            }
+            None => {
+                let total: u16 = self.panels.iter().filter_map(|p| p.size).sum();
+                if total > 100 {
+                    return Err(format!("sizes in one split add up to {}%", total));
+                }
+                self.panels.iter().try_for_each(|p| p.validate(seen))
+            }
+        }
+    }
+
+    // Splits `area` between the children and records where each panel ends
+    // up. Panels in `skip` are left out and their space goes to the others.
+    fn place(&self, area: Rect, skip: &[Panel], areas: &mut HashMap<Panel, Rect>) {
+        if let Some(panel) = self.panel {
+            areas.insert(panel, area);
+            return;
+        }
+        let children: Vec<&Node> = self.panels.iter().filter(|p| !p.only_skipped(skip)).collect();
+        let sized: u16 = children.iter().filter(|c| c.length.is_none()).filter_map(|c| c.size).sum();
+        let flexible = children.iter().filter(|c| c.length.is_none() && c.size.is_none()).count() as u16;
+        let rest = 100u16.saturating_sub(sized).checked_div(flexible).unwrap_or(0);
+        let constraints: Vec<Constraint> = children.iter()
+            .map(|c| match (c.length, c.size) {
+                (Some(length), _) => Constraint::Length(length),
+                (None, Some(size)) => Constraint::Percentage(size),
+                (None, None) => Constraint::Percentage(rest),
+            })
+            .collect();
+        let direction = match self.split {
+            Split::Horizontal => Direction::Horizontal,
+            Split::Vertical => Direction::Vertical,
+        };
+        let chunks = Layout::default()
+            .direction(direction)
+            .constraints(constraints)
+            .split(area);
+        for (child, chunk) in children.iter().zip(chunks) {
+            child.place(chunk, skip, areas);
+        }
+    }
+
+    fn only_skipped(&self, skip: &[Panel]) -> bool {
+        match self.panel {
+            Some(panel) => skip.contains(&panel),
+            None => self.panels.iter().all(|p| p.only_skipped(skip)),
+        }
+    }
+
+    /// Where each panel of the layout goes in `area`. Panels that aren't in
+    /// the layout, or are in `skip`, are missing from the map.
+    pub fn areas(&self, area: Rect, skip: &[Panel]) -> HashMap<Panel, Rect> {
+        let mut areas = HashMap::new();
+        self.place(area, skip, &mut areas);
+        areas
+    }
+}
+
+// The directory, controls and now playing panels in a row along the top.
+fn header() -> Node {
+    Node::split(Split::Horizontal, vec![
+        Node::panel(Panel::Directory).size(20),
+        Node::panel(Panel::Controls).size(60),
+        Node::panel(Panel::NowPlaying).size(20),
+    ]).length(3)
+}
+
+fn preset(name: &str) -> Option<Node> {
+    let node = match name {
+        "classic" => Node::split(Split::Vertical, vec![
+            header(),
+            Node::split(Split::Horizontal, vec![
+                Node::panel(Panel::Explorer).size(20),
+                Node::split(Split::Vertical, vec![
+                    Node::panel(Panel::Wave).size(90),
+                    Node::panel(Panel::Progress).length(10),
+                ]).size(60),
+                Node::panel(Panel::Playlist).size(20),
+            ]),
+        ]),
+        "minimal" => Node::split(Split::Vertical, vec![
+            Node::panel(Panel::NowPlaying).length(3),
+            Node::panel(Panel::Playlist),
+            Node::panel(Panel::Progress).length(3),
+        ]),
+        "library" => Node::split(Split::Vertical, vec![
+            header(),
+            Node::split(Split::Horizontal, vec![
+                Node::panel(Panel::Library).size(25),
+                Node::panel(Panel::Explorer).size(25),
+                Node::panel(Panel::Playlist).size(30),
+                Node::split(Split::Vertical, vec![
+                    Node::panel(Panel::Cover).size(50),
+                    Node::panel(Panel::Lyrics),
+                ]).size(20),
+            ]),
+            Node::panel(Panel::Progress).length(3),
+        ]),
+        _ => return None,
+    };
+    Some(node)
+}
+
+/// The layout used on narrow terminals whatever the preset: one column with
+/// now playing, explorer, playlist and the progress bar.
+pub fn compact() -> Node {
+    Node::split(Split::Vertical, vec![
+        Node::panel(Panel::NowPlaying).length(3),
+        Node::panel(Panel::Explorer),
+        Node::panel(Panel::Playlist),
+        Node::panel(Panel::Progress).length(3),
+    ])
+}
+
+/// The built-in presets followed by the user's `[layouts]`, in the order
+/// they are cycled through.
+#[derive(Debug, Clone)]
+pub struct Layouts {
+    layouts: Vec<(String, Node)>,
+    current: usize,
+}
+
+impl Default for Layouts {
+    fn default() -> Self {
+        let layouts = PRESETS.iter().map(|name| (name.to_string(), preset(name).unwrap())).collect();
+        Self { layouts, current: 0 }
+    }
+}
+
+impl Layouts {
+    /// Adds the user's layouts to the presets and starts on `selected`. A
+    /// user layout with a preset's name replaces the preset.
+    pub fn new(selected: &str, user_layouts: &BTreeMap<String, Node>) -> Result<Layouts, String> {
+        let mut layouts = Layouts::default();
+        for (name, node) in user_layouts {
+            node.validate(&mut vec![])
+                .map_err(|msg| format!("layout \"{}\": {}", name, msg))?;
+            match layouts.layouts.iter_mut().find(|(n, _)| n == name) {
+                Some(existing) => existing.1 = node.clone(),
+                None => layouts.layouts.push((name.clone(), node.clone())),
+            }
+        }
+        layouts.current = layouts.layouts.iter().position(|(n, _)| n == selected)
+            .ok_or_else(|| {
+                let names: Vec<&str> = layouts.layouts.iter().map(|(n, _)| n.as_str()).collect();
+                format!("unknown layout \"{}\", expected one of: {}", selected, names.join(", "))
+            })?;
+        Ok(layouts)
+    }
+
+    pub fn current(&self) -> &Node {
+        &self.layouts[self.current].1
+    }
+
+    /// Switches to the next layout and returns its name.
+    pub fn cycle(&mut self) -> &str {
+        self.current = (self.current + 1) % self.layouts.len();
+        &self.layouts[self.current].0
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::thread;
+
+use flume::Receiver;
+
+use crate::scan::{spawn_scan, ScanEvent};
+use crate::tags::Tags;
+
+/// An album's name and how many of its tracks were found.
+pub type Album = (String, usize);
+
+/// Finds the music files under `roots` and reads their tags on a background
+/// thread, sending the albums once it is done.
+pub fn spawn(roots: Vec<PathBuf>) -> Receiver<Vec<Album>> {
+    let (tx, rx) = flume::bounded(1);
+    thread::spawn(move || {
+        let roots = roots.into_iter().map(|root| (String::new(), root)).collect();
+        let songs = spawn_scan(roots).iter().find_map(|event| match event {
+            ScanEvent::Done(songs) => Some(songs),
+            ScanEvent::Progress(_) => None,
+        });
+        if let Some(songs) = songs {
+            let _ = tx.send(albums(songs.iter().map(|(_, path)| Tags::read(path))));
+        }
+    });
+    rx
+}
+
+// Groups tracks by artist and album, sorted by name.
+fn albums(tracks: impl Iterator<Item = Tags>) -> Vec<Album> {
+    let mut albums: BTreeMap<String, usize> = BTreeMap::new();
+    for tags in tracks {
+        let name = match (&tags.artist, &tags.album) {
+            (None, None) => "Untagged".to_string(),
+            (artist, album) => format!("{} - {}",
+                artist.as_deref().unwrap_or("Unknown Artist"),
+                album.as_deref().unwrap_or("Unknown Album")),
+        };
+        *albums.entry(name).or_default() += 1;
+    }
+    albums.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(artist: Option<&str>, album: Option<&str>) -> Tags {
+        Tags { artist: artist.map(str::to_owned), album: album.map(str::to_owned), ..Tags::default() }
+    }
+
+    #[test]
+    fn tracks_are_counted_per_album() {
+        let tracks = vec![
+            tags(Some("B"), Some("Two")),
+            tags(Some("A"), Some("One")),
+            tags(Some("B"), Some("Two")),
+            tags(None, Some("Loose")),
+            tags(None, None),
+        ];
+        assert_eq!(albums(tracks.into_iter()), vec![
+            ("A - One".to_string(), 1),
+            ("B - Two".to_string(), 2),
+            ("Unknown Artist - Loose".to_string(), 1),
+            ("Untagged".to_string(), 1),
+        ]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// Lyrics from a `.lrc` or `.txt` file next to the track. Lines from an LRC
+/// file carry the time they are sung at; plain text has no times.
+#[derive(Debug, Clone)]
+pub struct Lyrics {
+    pub lines: Vec<(Option<Duration>, String)>,
+}
+
+impl Lyrics {
+    pub fn load(track: &Path) -> Option<Lyrics> {
+        if let Ok(text) = fs::read_to_string(track.with_extension("lrc")) {
+            return Some(Self::parse_lrc(&text));
+        }
+        let text = fs::read_to_string(track.with_extension("txt")).ok()?;
+        Some(Self { lines: text.lines().map(|line| (None, line.to_string())).collect() })
+    }
+
+    // A line may start with several `[mm:ss.xx]` stamps when it is repeated.
+    // Tags such as `[ar:Artist]` don't parse as a time and are dropped.
+    fn parse_lrc(text: &str) -> Lyrics {
+        let mut lines = vec![];
+        for line in text.lines() {
+            let mut rest = line.trim();
+            let mut times = vec![];
+            while let Some(stamp) = rest.strip_prefix('[') {
+                let Some(end) = stamp.find(']') else { break };
+                if let Some(time) = parse_time(&stamp[..end]) {
+                    times.push(time);
+                }
+                rest = &stamp[end+1..];
+            }
+            if times.is_empty() && rest.len() != line.trim().len() {
+                continue;
+            }
+            if times.is_empty() {
+                lines.push((None, rest.to_string()));
+            }
+            lines.extend(times.into_iter().map(|time| (Some(time), rest.trim().to_string())));
+        }
+        lines.sort_by_key(|(time, _)| *time);
+        Self { lines }
+    }
+
+    /// The line being sung at `position`, if the lyrics are timed.
+    pub fn current_line(&self, position: Duration) -> Option<usize> {
+        self.lines.iter()
+            .rposition(|(time, _)| time.is_some_and(|t| t <= position))
+    }
+}
+
+// `None` for anything that isn't a time, including negative, infinite and
+// out of range seconds.
+fn parse_time(stamp: &str) -> Option<Duration> {
+    let (minutes, seconds) = stamp.split_once(':')?;
+    let minutes: u64 = minutes.parse().ok()?;
+    let seconds: f64 = seconds.parse().ok()?;
+    Duration::from_secs(minutes.checked_mul(60)?)
+        .checked_add(Duration::try_from_secs_f64(seconds).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: f64) -> Option<Duration> {
+        Some(Duration::from_secs_f64(s))
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("01:02.50"), secs(62.5));
+        assert_eq!(parse_time("0:07"), secs(7.0));
+        assert_eq!(parse_time("ar:Artist"), None);
+    }
+
+    #[test]
+    fn bad_times_are_dropped() {
+        for stamp in ["00:-1", "00:nan", "00:inf", "00:1e300", "999999999999999999:00", "-1:00"] {
+            assert_eq!(parse_time(stamp), None, "{}", stamp);
+        }
+    }
+
+    #[test]
+    fn lines_are_sorted_and_repeated() {
+        let lyrics = Lyrics::parse_lrc("[ar:Someone]\n[00:10.00][00:30.00]Chorus\n[00:20.00] Verse \n");
+        assert_eq!(lyrics.lines, vec![
+            (secs(10.0), "Chorus".to_string()),
+            (secs(20.0), "Verse".to_string()),
+            (secs(30.0), "Chorus".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn a_bad_stamp_does_not_hide_the_line() {
+        let lyrics = Lyrics::parse_lrc("[00:nan][00:05.00]Still here\n[00:-1]Gone\n");
+        assert_eq!(lyrics.lines, vec![(secs(5.0), "Still here".to_string())]);
+    }
+
+    #[test]
+    fn untimed_lines_are_kept() {
+        let lyrics = Lyrics::parse_lrc("Just words\n");
+        assert_eq!(lyrics.lines, vec![(None, "Just words".to_string())]);
+        assert_eq!(lyrics.current_line(Duration::from_secs(3)), None);
+    }
+
+    #[test]
+    fn current_line_follows_the_position() {
+        let lyrics = Lyrics::parse_lrc("[00:01.00]One\n[00:03.00]Two\n");
+        assert_eq!(lyrics.current_line(Duration::from_millis(500)), None);
+        assert_eq!(lyrics.current_line(Duration::from_secs(2)), Some(0));
+        assert_eq!(lyrics.current_line(Duration::from_secs(9)), Some(1));
+    }
+}
@@ -2,14 +2,41 @@ mod config;
 mod ui;
 mod play_controller;
 mod music_player;
+mod tags;
+mod audio;
+mod mpegts;
+mod opus;
+mod selection;
+mod scan;
+mod library;
+mod session;
+mod format;
+mod keymap;
+mod theme;
+mod layout;
+mod lyrics;
+mod cover;
+mod command;
+mod playlist_file;
+mod launch;
+mod protocol;
+mod service;
+mod daemon;
+mod client;
+mod ctl;
+mod mpris;
+mod mpd;
+mod http;
+mod radio;
 
 use clap::{App, Arg};
-use std::{io, thread, time::Duration};
+use std::{io, thread, time::Duration, path::Path, process};
 use config::Config;
 
 use ui::UI;
 use music_player::MusicPlayer;
 use config::Version;
+use launch::Launch;
 
 fn main() -> Result<(), io::Error> {
 
@@ -21,18 +48,90 @@ fn main() -> Result<(), io::Error> {
             Arg::with_name("dir")
             .multiple(true)           
                 .takes_value(true)
+                .value_name("PATH")
                 .help(
-                    r#"The directory of music files"#,
+                    r#"Directories to browse, and music files, playlists or http:// radio streams to queue"#,
                 )
-        );
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Read the configuration from FILE instead of ~/.config/tasco/config.toml")
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("Resume the track that was playing when Tasco last exited")
+        )
+        .arg(
+            Arg::with_name("play")
+                .long("play")
+                .help("Start playing right away, from the first queued file if any were given")
+        )
+        .arg(
+            Arg::with_name("shuffle")
+                .long("shuffle")
+                .help("Turn shuffle on")
+        )
+        .arg(
+            Arg::with_name("repeat")
+                .long("repeat")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(["off", "all", "one"])
+                .help("Set the repeat mode")
+        )
+        .arg(
+            Arg::with_name("daemon")
+                .long("daemon")
+                .help("Play without the TUI and take commands on a control socket. Running Tasco again attaches to it")
+        )
+        .subcommand(ctl::app());
+
+
+    let args = cli.get_matches();
+    if let Some(("ctl", ctl_args)) = args.subcommand() {
+        process::exit(ctl::run(ctl_args));
+    }
+    let config = match Config::load(args.value_of("config").map(Path::new)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let launch = match Launch::from_args(&args) {
+        Ok(launch) => launch,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
 
+    if args.is_present("daemon") {
+        if let Err(err) = daemon::run(&launch, &config) {
+            eprintln!("Could not start the daemon: {}", err);
+            process::exit(1);
+        }
+        return Ok(());
+    }
 
-    match MusicPlayer::new(cli, Config::default()) {
+    match MusicPlayer::new(&launch, config) {
         Ok(mut app) => {
-            app.run()?;
+            let result = app.run();
             app.destruct()?;
+            if let Err(err) = result {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
         },
-        Err(_) => {println!("Invalid directory path!")}
+        Err(err) => {
+            eprintln!("Could not start: {}", err);
+            process::exit(1);
+        }
     }
     
     Ok(())
@@ -0,0 +1,828 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write as _};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flume::{Receiver, RecvError, Selector};
+
+use crate::config::MpdConfig;
+use crate::play_controller::{song_duration, RepeatMode};
+use crate::playlist_file::{self, is_playlist_file};
+use crate::protocol::{Change, Playback, PlayerEvent, PlayerState, Request, Response};
+use crate::radio::is_stream;
+use crate::scan::{is_music_file, spawn_scan_within, ScanEvent};
+use crate::service::Handle;
+use crate::tags::Tags;
+
+// The protocol version we claim. Clients use it to decide which commands
+// they may send; everything we leave out is answered with an error.
+const GREETING: &str = "OK MPD 0.23.5\n";
+
+// Error codes from MPD's ack.h
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_PERMISSION: u32 = 4;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+const ACK_ERROR_SYSTEM: u32 = 52;
+
+const COMMANDS: &[&str] = &[
+    "add", "addid", "clear", "clearerror", "close", "command_list_begin",
+    "command_list_end", "command_list_ok_begin", "commands", "consume",
+    "currentsong", "decoders", "delete", "deleteid", "getvol", "idle",
+    "listplaylists", "lsinfo", "move", "next", "noidle", "notcommands",
+    "outputs", "pause", "ping", "play", "playid", "playlistid",
+    "playlistinfo", "plchanges", "plchangesposid", "previous", "random",
+    "repeat", "replay_gain_status", "seek", "seekcur", "seekid", "setvol",
+    "single", "stats", "status", "stop", "tagtypes", "urlhandlers",
+    "volume", "binarylimit",
+];
+
+const TAG_TYPES: &[&str] = &["Artist", "Album", "Title", "Track"];
+
+/// Starts answering MPD clients on the configured address, so phone apps,
+/// `mpc` and `ncmpcpp` can drive the player through `handle`. Files are
+/// named relative to `root`, which plays the part of MPD's music directory,
+/// and clients can't reach anything outside it.
+pub fn start(handle: Handle, config: &MpdConfig, root: Option<PathBuf>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((config.address.as_str(), config.port))?;
+    let address = listener.local_addr()?;
+    let started = Instant::now();
+    // Resolved paths are checked against the real directory
+    let root = root.and_then(|root| fs::canonicalize(root).ok());
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let events = handle.subscribe();
+            // The player has shut down
+            let Some(state) = handle.call(Request::Status { since: None }).state else { return };
+            let mut connection = Connection::new(handle.clone(), root.clone(), started, state, events);
+            thread::spawn(move || {
+                // The client hanging up is the normal way for this to end
+                let _ = connection.serve(stream);
+            });
+        }
+    });
+    Ok(address)
+}
+
+struct Ack {
+    code: u32,
+    msg: String,
+}
+
+impl Ack {
+    fn new(code: u32, msg: impl Into<String>) -> Ack {
+        Self { code, msg: msg.into() }
+    }
+
+    fn arg(msg: impl Into<String>) -> Ack {
+        Self::new(ACK_ERROR_ARG, msg)
+    }
+}
+
+struct SongInfo {
+    tags: Tags,
+    duration: Option<Duration>,
+}
+
+// One client. Song ids are the player's entry ids, which stay with their
+// entries as the playlist changes.
+struct Connection {
+    handle: Handle,
+    root: Option<PathBuf>,
+    started: Instant,
+    state: PlayerState,
+    // What the player says has changed, and the `idle` subsystems it has
+    // come to since the client last heard from `idle`
+    events: Receiver<PlayerEvent>,
+    pending: BTreeSet<&'static str>,
+    // Tags and durations of the files this client has asked about
+    songs: HashMap<PathBuf, SongInfo>,
+}
+
+// What an `idle` client was woken by.
+enum Wake {
+    Event(Result<PlayerEvent, RecvError>),
+    Line(Result<String, RecvError>),
+}
+
+impl Connection {
+    fn new(handle: Handle, root: Option<PathBuf>, started: Instant, state: PlayerState, events: Receiver<PlayerEvent>) -> Connection {
+        Self { handle, root, started, state, events, pending: BTreeSet::new(), songs: HashMap::new() }
+    }
+
+    fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let lines = read_lines(stream);
+        let result = self.converse(&lines, &mut writer);
+        // The thread reading lines holds on to the socket until then
+        let _ = writer.shutdown(Shutdown::Both);
+        result
+    }
+
+    fn converse(&mut self, lines: &Receiver<String>, writer: &mut TcpStream) -> io::Result<()> {
+        writer.write_all(GREETING.as_bytes())?;
+        // A command list being collected, and whether each command in it
+        // gets its own `list_OK`
+        let mut list: Option<(bool, Vec<String>)> = None;
+        // Ends when the client hangs up
+        for line in lines.iter() {
+            let line = line.trim_end_matches('\r');
+            let reply = match (line, &mut list) {
+                ("command_list_begin", None) => { list = Some((false, vec![])); continue },
+                ("command_list_ok_begin", None) => { list = Some((true, vec![])); continue },
+                ("command_list_end", Some(_)) => {
+                    let (list_ok, lines) = list.take().unwrap_or_default();
+                    self.run_list(&lines, list_ok)
+                },
+                (line, Some((_, lines))) => { lines.push(line.to_string()); continue },
+                ("close", None) => return Ok(()),
+                // Left over from an `idle` that had already returned
+                ("noidle", None) => continue,
+                (line, None) if line == "idle" || line.starts_with("idle ") => {
+                    let subsystems: Vec<&str> = line.split_whitespace().skip(1).collect();
+                    match self.idle(&subsystems, lines) {
+                        Some(reply) => reply,
+                        None => return Ok(()),
+                    }
+                },
+                (line, None) => self.run_list(&[line.to_string()], false),
+            };
+            writer.write_all(reply.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Runs commands until one fails, which ends the reply with an `ACK`
+    // naming its place in the list.
+    fn run_list(&mut self, lines: &[String], list_ok: bool) -> String {
+        let mut reply = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            let result = split_args(line).and_then(|args| match args.split_first() {
+                Some((name, args)) => self.run(name, args).map_err(|ack| (name.clone(), ack)),
+                None => Err((String::new(), Ack::new(ACK_ERROR_UNKNOWN, "No command given"))),
+            });
+            match result {
+                Ok(output) => reply.push_str(&output),
+                Err((name, ack)) => {
+                    let _ = writeln!(reply, "ACK [{}@{}] {{{}}} {}", ack.code, i, name, ack.msg);
+                    return reply;
+                }
+            }
+            if list_ok {
+                reply.push_str("list_OK\n");
+            }
+        }
+        reply.push_str("OK\n");
+        reply
+    }
+
+    // Waits for the player to change in one of `subsystems`, or any when
+    // none are named, or for the client to send `noidle`. Changes since the
+    // last `idle` are reported straight away, as MPD does. Returns `None`
+    // when the client hangs up.
+    fn idle(&mut self, subsystems: &[&str], lines: &Receiver<String>) -> Option<String> {
+        let wanted = |name: &&str| subsystems.is_empty() || subsystems.contains(name);
+        self.take_events();
+        let mut changed: BTreeSet<&str> = self.pending.iter().copied().filter(wanted).collect();
+        while changed.is_empty() {
+            let wake = Selector::new()
+                .recv(&self.events, Wake::Event)
+                .recv(lines, Wake::Line)
+                .wait();
+            match wake {
+                Wake::Event(Ok(event)) => {
+                    let name = subsystem(event);
+                    self.pending.insert(name);
+                    changed.extend(Some(name).filter(wanted));
+                },
+                // The player has shut down, or the client sent `noidle`, or
+                // broke the rules and sent something else, which MPD
+                // doesn't put up with either
+                Wake::Event(Err(_)) | Wake::Line(Ok(_)) => break,
+                Wake::Line(Err(_)) => return None,
+            }
+        }
+        self.pending.retain(|name| !changed.contains(name));
+        let mut reply = String::new();
+        for name in changed {
+            let _ = writeln!(reply, "changed: {}", name);
+        }
+        reply.push_str("OK\n");
+        Some(reply)
+    }
+
+    // Keeps what the player has said so far as `idle` subsystems, so the
+    // events don't pile up while the client isn't idle.
+    fn take_events(&mut self) {
+        self.pending.extend(self.events.drain().map(subsystem));
+    }
+
+    fn call(&self, request: Request) -> Result<Response, Ack> {
+        self.handle.call(request).into_result().map_err(|msg| Ack::new(ACK_ERROR_SYSTEM, msg))
+    }
+
+    // Brings `state` up to date, fetching the playlist only if it changed.
+    fn refresh(&mut self) -> Result<(), Ack> {
+        let since = Some(self.state.playlist_version);
+        let newer = self.call(Request::Status { since })?.state
+            .ok_or_else(|| Ack::new(ACK_ERROR_SYSTEM, "no status"))?;
+        self.state.update(newer);
+        Ok(())
+    }
+
+    fn run(&mut self, name: &str, args: &[String]) -> Result<String, Ack> {
+        self.take_events();
+        self.refresh()?;
+        let state = self.state.clone();
+        let len = state.play_list.len();
+        let mut out = String::new();
+        match (name, args) {
+            ("ping" | "clearerror", []) => {},
+            ("commands", []) => {
+                for command in COMMANDS {
+                    let _ = writeln!(out, "command: {}", command);
+                }
+            },
+            ("notcommands", []) | ("listplaylists", []) | ("decoders", []) => {},
+            ("tagtypes", []) => {
+                for tag in TAG_TYPES {
+                    let _ = writeln!(out, "tagtype: {}", tag);
+                }
+            },
+            // Clients turn tags on and off, but they always get all four
+            ("tagtypes", [_, ..]) | ("binarylimit", [_]) => {},
+            ("urlhandlers", []) => out.push_str("handler: http://\n"),
+            ("outputs", []) => out.push_str("outputid: 0\noutputname: default\noutputenabled: 1\n"),
+            ("replay_gain_status", []) => out.push_str("replay_gain_mode: off\n"),
+            ("stats", []) => {
+                let _ = write!(out, "uptime: {}\nplaytime: 0\nartists: 0\nalbums: 0\nsongs: {}\ndb_playtime: 0\ndb_update: 0\n",
+                    self.started.elapsed().as_secs(), len);
+            },
+            ("status", []) => write_status(&mut out, &state),
+            ("currentsong", []) => {
+                if let (Some(path), true) = (&state.playing_path, state.is_playing_or_paused()) {
+                    let place = state.playing_index
+                        .and_then(|index| Some((index, *state.playlist_ids.get(index)?)));
+                    self.write_song(&mut out, path, place);
+                    if let Some(title) = &state.stream_title {
+                        let _ = writeln!(out, "Title: {}", title);
+                    }
+                }
+            },
+            ("play", []) => { self.call(Request::Play { index: None })?; },
+            ("play", [index]) => {
+                let index = position(index, len)?;
+                self.call(Request::Play { index: Some(index) })?;
+            },
+            ("playid", [id]) => {
+                let index = position_of_id(id, &state)?;
+                self.call(Request::Play { index: Some(index) })?;
+            },
+            ("pause", []) => { self.call(Request::Toggle)?; },
+            ("pause", [on]) => match on.as_str() {
+                "1" => { self.call(Request::Pause)?; },
+                "0" => { self.call(Request::Play { index: None })?; },
+                _ => return Err(Ack::arg(format!("Boolean (0/1) expected: {}", on))),
+            },
+            ("next", []) => { self.call(Request::Next)?; },
+            ("previous", []) => { self.call(Request::Prev)?; },
+            ("stop", []) => { self.call(Request::Stop)?; },
+            ("seekcur", [time]) => {
+                let request = match time.strip_prefix('+') {
+                    Some(seconds) => Request::SeekBy { seconds: number(seconds)? },
+                    None if time.starts_with('-') => Request::SeekBy { seconds: number(time)? },
+                    None => Request::Seek { position: number(time)? },
+                };
+                self.call(request)?;
+            },
+            ("seek" | "seekid", [song, time]) => {
+                let index = match name {
+                    "seek" => position(song, len)?,
+                    _ => position_of_id(song, &state)?,
+                };
+                let position = number(time)?;
+                if state.playing_index != Some(index) || !state.is_playing_or_paused() {
+                    self.call(Request::Play { index: Some(index) })?;
+                }
+                self.call(Request::Seek { position })?;
+            },
+            ("setvol", [percent]) => {
+                let percent = percent.parse().ok().filter(|&p| p <= 100)
+                    .ok_or_else(|| Ack::arg(format!("Invalid volume value: {}", percent)))?;
+                self.call(Request::Volume { percent })?;
+            },
+            ("volume", [change]) => {
+                let percent = change.parse()
+                    .map_err(|_| Ack::arg(format!("Invalid volume value: {}", change)))?;
+                self.call(Request::VolumeBy { percent })?;
+            },
+            ("getvol", []) => { let _ = writeln!(out, "volume: {}", state.volume); },
+            ("repeat", [on]) => {
+                let mode = match (boolean(on)?, state.repeat) {
+                    (false, _) => RepeatMode::Off,
+                    (true, RepeatMode::One) => RepeatMode::One,
+                    (true, _) => RepeatMode::All,
+                };
+                self.call(Request::Repeat { mode: Some(mode) })?;
+            },
+            // MPD's single mode without repeat stops after the track, which
+            // the player can't do, so it is taken as repeat one either way
+            ("single", [on]) => {
+                let mode = match (boolean(on)?, state.repeat) {
+                    (true, _) => RepeatMode::One,
+                    (false, RepeatMode::One) => RepeatMode::All,
+                    (false, mode) => mode,
+                };
+                self.call(Request::Repeat { mode: Some(mode) })?;
+            },
+            ("random", [on]) => { self.call(Request::Shuffle { on: Some(boolean(on)?) })?; },
+            ("consume", [on]) => {
+                if boolean(on)? {
+                    return Err(Ack::arg("consume mode is not supported"));
+                }
+            },
+            ("add", [uri]) => {
+                let path = self.resolve(uri)?;
+                match self.contents(&path, uri)? {
+                    Some(entries) => self.call(Request::Append { entries })?,
+                    None => self.call(Request::Enqueue { paths: vec![path] })?,
+                };
+            },
+            ("addid", [uri, rest @ ..]) if rest.len() <= 1 => {
+                let path = self.resolve(uri)?;
+                if !path.is_file() || !is_music_file(&path) {
+                    return Err(Ack::new(ACK_ERROR_NO_EXIST, format!("Not a music file: {}", uri)));
+                }
+                let at = match rest.first() {
+                    Some(at) => number::<usize>(at)?.min(len),
+                    None => len,
+                };
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                self.call(Request::Insert { at, entries: vec![(name, path)] })?;
+                self.refresh()?;
+                if let Some(id) = self.state.playlist_ids.get(at) {
+                    let _ = writeln!(out, "Id: {}", id);
+                }
+            },
+            ("delete", [range]) => {
+                let indices = positions(range, len)?.collect();
+                self.call(Request::Remove { indices })?;
+            },
+            ("deleteid", [id]) => {
+                let index = position_of_id(id, &state)?;
+                self.call(Request::Remove { indices: [index].into() })?;
+            },
+            ("move", [from, to]) => {
+                let from = position(from, len)?;
+                let to = position(to, len)?;
+                for index in (to + 1..=from).rev() {
+                    self.call(Request::MoveUp { index })?;
+                }
+                for index in from..to {
+                    self.call(Request::MoveDown { index })?;
+                }
+            },
+            ("clear", []) => { self.call(Request::Clear)?; },
+            ("playlistinfo", []) | ("playlistid", []) => self.write_songs(&mut out, &state, 0..len),
+            ("playlistinfo", [range]) => self.write_songs(&mut out, &state, positions(range, len)?),
+            ("playlistid", [id]) => {
+                let index = position_of_id(id, &state)?;
+                self.write_songs(&mut out, &state, index..index + 1);
+            },
+            // There is no record of what changed when, so anything but the
+            // current version gets the whole playlist
+            ("plchanges", [version, rest @ ..]) if rest.len() <= 1 => {
+                if number::<u64>(version)? != mpd_version(&state) {
+                    let range = match rest.first() {
+                        Some(range) => positions(range, len)?,
+                        None => 0..len,
+                    };
+                    self.write_songs(&mut out, &state, range);
+                }
+            },
+            ("plchangesposid", [version]) => {
+                if number::<u64>(version)? != mpd_version(&state) {
+                    for (index, id) in state.playlist_ids.iter().enumerate() {
+                        let _ = write!(out, "cpos: {}\nId: {}\n", index, id);
+                    }
+                }
+            },
+            ("lsinfo", []) => {
+                if let Some(root) = self.root.clone() {
+                    self.write_directory(&mut out, &root)?;
+                }
+            },
+            ("lsinfo", [uri]) => {
+                let path = self.resolve(uri)?;
+                match path.is_dir() {
+                    true => self.write_directory(&mut out, &path)?,
+                    false if path.is_file() => self.write_song(&mut out, &path, None),
+                    false => return Err(Ack::new(ACK_ERROR_NO_EXIST, "No such directory")),
+                }
+            },
+            (name, _) if COMMANDS.contains(&name) => {
+                return Err(Ack::arg(format!("wrong number of arguments for \"{}\"", name)));
+            },
+            (name, _) => return Err(Ack::new(ACK_ERROR_UNKNOWN, format!("unknown command \"{}\"", name))),
+        }
+        Ok(out)
+    }
+
+    // Turns a URI from a client into a file under the music directory, as
+    // MPD does. Absolute paths, `..` and symlinks that lead out of it are
+    // refused, so clients on the network can't get at anything else.
+    // Streams are passed on as they are.
+    fn resolve(&self, uri: &str) -> Result<PathBuf, Ack> {
+        let path = Path::new(uri);
+        if is_stream(path) {
+            return Ok(path.to_path_buf());
+        }
+        let root = self.root.as_ref()
+            .ok_or_else(|| Ack::new(ACK_ERROR_NO_EXIST, "no music directory is configured"))?;
+        let outside = || Ack::new(ACK_ERROR_PERMISSION, format!("Outside the music directory: {}", uri));
+        if !path.components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir)) {
+            return Err(outside());
+        }
+        let path = fs::canonicalize(root.join(path))
+            .map_err(|_| Ack::new(ACK_ERROR_NO_EXIST, format!("No such file or directory: {}", uri)))?;
+        match path.starts_with(root) {
+            true => Ok(path),
+            false => Err(outside()),
+        }
+    }
+
+    // What a directory or playlist file `add`s, without what symlinks or
+    // playlist entries lead to outside the music directory. `None` for
+    // anything else, which `resolve` has already checked.
+    fn contents(&self, path: &Path, uri: &str) -> Result<Option<Vec<(String, PathBuf)>>, Ack> {
+        let Some(root) = self.root.as_ref().filter(|_| !is_stream(path)) else { return Ok(None) };
+        if path.is_dir() {
+            let scan = spawn_scan_within(vec![(String::new(), path.to_path_buf())], root.clone());
+            let songs = scan.iter().find_map(|event| match event {
+                ScanEvent::Done(songs) => Some(songs),
+                ScanEvent::Progress(_) => None,
+            });
+            return Ok(Some(songs.unwrap_or_default()));
+        }
+        if !is_playlist_file(path) {
+            return Ok(None);
+        }
+        let entries = playlist_file::read(path)
+            .map_err(|err| Ack::new(ACK_ERROR_SYSTEM, format!("Could not read {}: {}", uri, err)))?;
+        Ok(Some(entries.into_iter()
+            .filter(|(_, entry)| is_stream(entry) || fs::canonicalize(entry).is_ok_and(|real| real.starts_with(root)))
+            .collect()))
+    }
+
+    // The opposite of `resolve`.
+    fn uri(&self, path: &Path) -> String {
+        let relative = self.root.as_ref().and_then(|root| path.strip_prefix(root).ok());
+        relative.unwrap_or(path).to_string_lossy().into_owned()
+    }
+
+    fn write_songs(&mut self, out: &mut String, state: &PlayerState, range: Range<usize>) {
+        for index in range {
+            let id = state.playlist_ids.get(index).copied();
+            self.write_song(out, &state.play_list[index].1, id.map(|id| (index, id)));
+        }
+    }
+
+    // `place` is the position and id of a playlist entry.
+    fn write_song(&mut self, out: &mut String, path: &Path, place: Option<(usize, u64)>) {
+        let _ = writeln!(out, "file: {}", self.uri(path));
+        let info = self.songs.entry(path.to_path_buf()).or_insert_with(|| SongInfo {
+            tags: Tags::read(path),
+            duration: song_duration(path),
+        });
+        let tags = &info.tags;
+        for (key, value) in [("Artist", &tags.artist), ("Album", &tags.album), ("Title", &tags.title)] {
+            if let Some(value) = value {
+                let _ = writeln!(out, "{}: {}", key, value);
+            }
+        }
+        if let Some(track) = tags.track {
+            let _ = writeln!(out, "Track: {}", track);
+        }
+        if let Some(duration) = info.duration {
+            let _ = write!(out, "Time: {}\nduration: {:.3}\n", duration.as_secs(), duration.as_secs_f64());
+        }
+        if let Some((index, id)) = place {
+            let _ = write!(out, "Pos: {}\nId: {}\n", index, id);
+        }
+    }
+
+    fn write_directory(&mut self, out: &mut String, dir: &Path) -> Result<(), Ack> {
+        let entries = fs::read_dir(dir).map_err(|err| Ack::new(ACK_ERROR_NO_EXIST, err.to_string()))?;
+        let mut paths: Vec<PathBuf> = entries.flatten()
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .map(|entry| entry.path())
+            .collect();
+        paths.sort();
+        for path in paths {
+            if path.is_dir() {
+                let _ = writeln!(out, "directory: {}", self.uri(&path));
+            }
+            else if is_playlist_file(&path) {
+                let _ = writeln!(out, "playlist: {}", self.uri(&path));
+            }
+            else if is_music_file(&path) {
+                self.write_song(out, &path, None);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Reads the client's lines on a thread of its own, so `idle` can wait for
+// them and the player's events at once. The channel closes when the client
+// hangs up.
+fn read_lines(stream: TcpStream) -> Receiver<String> {
+    let (tx, rx) = flume::unbounded();
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else { return };
+            if tx.send(line).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+// The `idle` subsystem an event belongs to.
+fn subsystem(event: PlayerEvent) -> &'static str {
+    match event {
+        PlayerEvent::Changed(Change::Playlist) => "playlist",
+        PlayerEvent::Changed(Change::Track | Change::Playback) | PlayerEvent::Seeked(_) => "player",
+        PlayerEvent::Changed(Change::Volume) => "mixer",
+        PlayerEvent::Changed(Change::Options) => "options",
+        PlayerEvent::Changed(Change::Scan) => "update",
+    }
+}
+
+fn write_status(out: &mut String, state: &PlayerState) {
+    let flag = |on: bool| if on { 1 } else { 0 };
+    let playback = match state.playback {
+        Playback::Stopped => "stop",
+        Playback::Playing => "play",
+        Playback::Paused => "pause",
+    };
+    let _ = write!(out, "volume: {}\nrepeat: {}\nrandom: {}\nsingle: {}\nconsume: 0\nplaylist: {}\nplaylistlength: {}\nstate: {}\n",
+        state.volume,
+        flag(state.repeat != RepeatMode::Off),
+        flag(state.shuffle),
+        flag(state.repeat == RepeatMode::One),
+        mpd_version(state),
+        state.play_list.len(),
+        playback);
+    if let Some(index) = state.playing_index {
+        let _ = writeln!(out, "song: {}", index);
+        if let Some(id) = state.playlist_ids.get(index) {
+            let _ = writeln!(out, "songid: {}", id);
+        }
+    }
+    if state.is_playing_or_paused() {
+        let (current, total) = (state.current_time, state.total_time);
+        let _ = write!(out, "time: {}:{}\nelapsed: {:.3}\nduration: {:.3}\n",
+            current.as_secs(), total.as_secs(), current.as_secs_f64(), total.as_secs_f64());
+    }
+}
+
+// Clients start out at version 0 and ask for what changed since, so ours
+// are counted from 1 to make sure they get the playlist.
+fn mpd_version(state: &PlayerState) -> u64 {
+    state.playlist_version + 1
+}
+
+fn number<T: std::str::FromStr>(arg: &str) -> Result<T, Ack> {
+    arg.parse().map_err(|_| Ack::arg(format!("Number expected: {}", arg)))
+}
+
+fn boolean(arg: &str) -> Result<bool, Ack> {
+    match arg {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(Ack::arg(format!("Boolean (0/1) expected: {}", arg))),
+    }
+}
+
+fn position(arg: &str, len: usize) -> Result<usize, Ack> {
+    let index = number(arg)?;
+    match index < len {
+        true => Ok(index),
+        false => Err(Ack::arg("Bad song index")),
+    }
+}
+
+// The position of the entry whose song id is `arg`.
+fn position_of_id(arg: &str, state: &PlayerState) -> Result<usize, Ack> {
+    let id: u64 = number(arg)?;
+    state.playlist_ids.iter().position(|&entry| entry == id)
+        .ok_or_else(|| Ack::new(ACK_ERROR_NO_EXIST, "No such song"))
+}
+
+// A position or a `START:END` range, where a missing end means the end of
+// the playlist.
+fn positions(arg: &str, len: usize) -> Result<Range<usize>, Ack> {
+    let Some((start, end)) = arg.split_once(':') else {
+        let index = position(arg, len)?;
+        return Ok(index..index + 1);
+    };
+    let start = number(start)?;
+    let end = match end {
+        "" => len,
+        end => number(end)?,
+    };
+    match start <= end && end <= len {
+        true => Ok(start..end),
+        false => Err(Ack::arg("Bad song index")),
+    }
+}
+
+// Splits a command line into words, honouring double quotes and the
+// backslash escapes inside them.
+fn split_args(line: &str) -> Result<Vec<String>, (String, Ack)> {
+    let mut args = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else { return Ok(args) };
+        let mut arg = String::new();
+        if first == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => arg.extend(chars.next()),
+                    Some(c) => arg.push(c),
+                    None => {
+                        let name = args.first().cloned().unwrap_or_default();
+                        return Err((name, Ack::arg("Missing closing '\"'")));
+                    }
+                }
+            }
+        }
+        else {
+            arg.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+        args.push(arg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::service::Service;
+
+    // A client speaking the protocol over a real connection.
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Client {
+            let writer = TcpStream::connect(address).unwrap();
+            let mut reader = BufReader::new(writer.try_clone().unwrap());
+            let mut greeting = String::new();
+            reader.read_line(&mut greeting).unwrap();
+            assert_eq!(greeting, GREETING);
+            Client { reader, writer }
+        }
+
+        fn send(&mut self, line: &str) {
+            writeln!(self.writer, "{}", line).unwrap();
+        }
+
+        // Everything up to and including the `OK` or `ACK` line.
+        fn reply(&mut self) -> String {
+            let mut reply = String::new();
+            loop {
+                let start = reply.len();
+                assert_ne!(self.reader.read_line(&mut reply).unwrap(), 0);
+                if reply[start..].starts_with("OK") || reply[start..].starts_with("ACK") {
+                    return reply;
+                }
+            }
+        }
+
+        fn command(&mut self, line: &str) -> String {
+            self.send(line);
+            self.reply()
+        }
+    }
+
+    // A music directory holding two silent WAV files, and a link out of it.
+    fn music_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("tasco-{}-mpd-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let wav = b"RIFF\x24\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0\x44\xac\0\0\x88\x58\x01\0\x02\0\x10\0data\0\0\0\0";
+        fs::write(dir.join("a.wav"), wav).unwrap();
+        fs::write(dir.join("b.wav"), wav).unwrap();
+        symlink(env::temp_dir(), dir.join("out")).unwrap();
+        dir
+    }
+
+    fn start_player(root: &Path) -> SocketAddr {
+        let (handle, requests) = Handle::new();
+        Service::spawn(&Config::default(), None, false, requests);
+        let config = MpdConfig { enabled: true, address: "127.0.0.1".to_string(), port: 0 };
+        start(handle, &config, Some(root.to_path_buf())).unwrap()
+    }
+
+    #[test]
+    fn the_playlist_keeps_its_song_ids() {
+        let root = music_dir("ids");
+        let mut client = Client::connect(start_player(&root));
+        let status = client.command("status");
+        assert!(status.contains("volume: 100\n") && status.contains("playlistlength: 0\n"));
+        assert!(status.contains("state: stop\n") && status.ends_with("OK\n"));
+
+        assert_eq!(client.command("add a.wav"), "OK\n");
+        assert_eq!(client.command("addid b.wav"), "Id: 1\nOK\n");
+        let songs = client.command("playlistinfo");
+        assert!(songs.contains("file: a.wav\n") && songs.contains("Pos: 0\nId: 0\n"));
+        assert!(songs.contains("file: b.wav\n") && songs.contains("Pos: 1\nId: 1\n"));
+
+        assert_eq!(client.command("delete 0"), "OK\n");
+        let songs = client.command("playlistinfo");
+        assert!(!songs.contains("a.wav") && songs.contains("Pos: 0\nId: 1\n"));
+        assert!(client.command("playlistid 1").contains("file: b.wav\n"));
+        assert_eq!(client.command("deleteid 0"), "ACK [50@0] {deleteid} No such song\n");
+        assert_eq!(client.command("deleteid 1"), "OK\n");
+        assert!(client.command("status").contains("playlistlength: 0\n"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn nothing_outside_the_music_directory_is_reachable() {
+        let root = music_dir("outside");
+        let mut client = Client::connect(start_player(&root));
+        for uri in ["/etc/passwd", "../a.wav", "./../a.wav", "out"] {
+            let reply = client.command(&format!("lsinfo \"{}\"", uri));
+            assert!(reply.starts_with("ACK [4@0] {lsinfo} Outside the music directory"), "{}", reply);
+            assert!(client.command(&format!("add \"{}\"", uri)).starts_with("ACK [4@0]"));
+        }
+        assert!(client.command("add missing.wav").starts_with("ACK [50@0]"));
+        assert!(client.command("lsinfo").contains("file: a.wav\n"));
+        assert!(client.command("status").contains("playlistlength: 0\n"));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn adding_directories_and_playlists_stays_inside_the_music_directory() {
+        let root = music_dir("links");
+        let elsewhere = music_dir("elsewhere");
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::copy(root.join("a.wav"), root.join("sub/c.wav")).unwrap();
+        symlink(&elsewhere, root.join("sub/away")).unwrap();
+        symlink(root.join("b.wav"), root.join("sub/b-link.wav")).unwrap();
+        fs::write(root.join("list.m3u"), format!("a.wav\n{}\n", elsewhere.join("a.wav").display())).unwrap();
+        let mut client = Client::connect(start_player(&root));
+
+        assert_eq!(client.command("add \"\""), "OK\n");
+        let files: Vec<String> = client.command("playlistinfo").lines()
+            .filter_map(|line| line.strip_prefix("file: ").map(str::to_string))
+            .collect();
+        assert_eq!(files, ["a.wav", "b.wav", "sub/b-link.wav", "sub/c.wav"]);
+        assert_eq!(client.command("add list.m3u"), "OK\n");
+        assert!(client.command("status").contains("playlistlength: 5\n"));
+        assert!(!client.command("playlistinfo").contains(&elsewhere.display().to_string()));
+        fs::remove_dir_all(root).unwrap();
+        fs::remove_dir_all(elsewhere).unwrap();
+    }
+
+    #[test]
+    fn idle_waits_for_the_subsystems_asked_for() {
+        let root = music_dir("idle");
+        let address = start_player(&root);
+        let mut idler = Client::connect(address);
+        let mut other = Client::connect(address);
+
+        idler.send("idle mixer");
+        assert_eq!(other.command("add a.wav"), "OK\n");
+        assert_eq!(other.command("setvol 50"), "OK\n");
+        assert_eq!(idler.reply(), "changed: mixer\nOK\n");
+        // The playlist change was kept for the next `idle`
+        assert_eq!(idler.command("idle"), "changed: playlist\nOK\n");
+
+        idler.send("idle");
+        idler.send("noidle");
+        assert_eq!(idler.reply(), "OK\n");
+        assert!(idler.command("status").contains("volume: 50\n"));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,531 @@
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::audio::ProbedSource;
+
+const PACKET_LEN: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+// The program tables come first, so a file that has none in this many
+// packets isn't going to have any
+const MAX_TABLE_PACKETS: usize = 1000;
+// The last PES packet of the audio stream starts well within this many
+// bytes of the end of the file
+const TAIL_LEN: u64 = 1024 * 1024;
+// Timestamps count a 90 kHz clock in 33 bits
+const PTS_RATE: f64 = 90_000.0;
+const PTS_WRAP: u64 = 1 << 33;
+
+/// How a transport stream's audio is coded, from the stream type in its
+/// program map table.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AudioCodec {
+    /// MPEG-1 or MPEG-2 audio, layer II or III.
+    Mpeg,
+    Aac,
+}
+
+/// The first audio stream of the first program in the transport stream at
+/// `path`: its packet id and codec.
+pub fn find_audio(path: &Path) -> Option<(u16, AudioCodec)> {
+    let mut file = BufReader::new(File::open(path).ok()?);
+    let mut packet = [0; PACKET_LEN];
+    let mut pmt_pid = None;
+    for _ in 0..MAX_TABLE_PACKETS {
+        file.read_exact(&mut packet).ok()?;
+        let Some(header) = Header::parse(&packet) else { continue };
+        if !header.unit_start {
+            continue;
+        }
+        let payload = &packet[header.payload..];
+        // Table sections start after a pointer field, and are taken to fit
+        // in one packet as they do in practice
+        let Some(section) = payload.get(1 + usize::from(*payload.first()?)..) else { continue };
+        match (header.pid, pmt_pid) {
+            (PAT_PID, _) => pmt_pid = pmt_pid.or_else(|| first_program(section)),
+            (pid, Some(pmt)) if pid == pmt => return audio_stream(section),
+            _ => {},
+        }
+    }
+    None
+}
+
+/// The length of the audio in the transport stream at `path`, from the
+/// timestamps of its first and last PES packets plus what is in the last.
+pub fn duration(path: &Path) -> Option<Duration> {
+    let (pid, codec) = find_audio(path)?;
+    let mut file = BufReader::new(File::open(path).ok()?);
+    let (_, first) = next_timestamp(&mut file, pid)?;
+
+    let end = file.seek(SeekFrom::End(0)).ok()?;
+    let tail = end.saturating_sub(TAIL_LEN) / PACKET_LEN as u64 * PACKET_LEN as u64;
+    file.seek(SeekFrom::Start(tail)).ok()?;
+    let mut last = None;
+    while let Some(found) = next_timestamp(&mut file, pid) {
+        last = Some(found);
+    }
+    let (offset, last) = last?;
+
+    // The last PES packet is decoded to see how long it plays for
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let rest: f64 = Frames::new(PesReader::new(file, pid), codec).ok()?
+        .map(|(channels, rate, samples)| (samples.len() / usize::from(channels)) as f64 / f64::from(rate))
+        .sum();
+    let ticks = last.wrapping_sub(first) % PTS_WRAP;
+    Some(Duration::from_secs_f64(ticks as f64 / PTS_RATE + rest))
+}
+
+// The PAT's first program, skipping program 0 which points at the network
+// table instead.
+fn first_program(section: &[u8]) -> Option<u16> {
+    if *section.first()? != 0x00 {
+        return None;
+    }
+    table_entries(section, 8)?.chunks_exact(4)
+        .find(|entry| entry[0] != 0 || entry[1] != 0)
+        .map(|entry| u16::from(entry[2] & 0x1f) << 8 | u16::from(entry[3]))
+}
+
+fn audio_stream(section: &[u8]) -> Option<(u16, AudioCodec)> {
+    if *section.first()? != 0x02 {
+        return None;
+    }
+    let info_len = usize::from(section.get(10)? & 0x0f) << 8 | usize::from(*section.get(11)?);
+    let mut streams = table_entries(section, 12 + info_len)?;
+    while let [stream_type, pid_hi, pid_lo, len_hi, len_lo, rest @ ..] = streams {
+        let codec = match stream_type {
+            0x03 | 0x04 => Some(AudioCodec::Mpeg),
+            0x0f | 0x11 => Some(AudioCodec::Aac),
+            _ => None,
+        };
+        if let Some(codec) = codec {
+            return Some((u16::from(pid_hi & 0x1f) << 8 | u16::from(*pid_lo), codec));
+        }
+        let descriptors = usize::from(len_hi & 0x0f) << 8 | usize::from(*len_lo);
+        streams = rest.get(descriptors..)?;
+    }
+    None
+}
+
+// The entries of a table section from `start`, without the CRC at the end.
+fn table_entries(section: &[u8], start: usize) -> Option<&[u8]> {
+    let len = usize::from(section.get(1)? & 0x0f) << 8 | usize::from(*section.get(2)?);
+    section.get(start..(3 + len).checked_sub(4)?)
+}
+
+// Reads on to the next PES packet of `pid` that has a timestamp, giving
+// the file offset of the TS packet it starts in along with the timestamp.
+fn next_timestamp(file: &mut BufReader<File>, pid: u16) -> Option<(u64, u64)> {
+    let mut packet = [0; PACKET_LEN];
+    loop {
+        let offset = file.stream_position().ok()?;
+        file.read_exact(&mut packet).ok()?;
+        let Some(header) = Header::parse(&packet) else { continue };
+        if header.pid == pid && header.unit_start {
+            if let Some(pts) = Pes::parse(&packet[header.payload..]).and_then(|pes| pes.pts) {
+                return Some((offset, pts));
+            }
+        }
+    }
+}
+
+struct Header {
+    pid: u16,
+    /// Whether a PES packet or table section starts in this packet.
+    unit_start: bool,
+    /// Where the payload starts.
+    payload: usize,
+}
+
+impl Header {
+    // `None` for packets out of sync or without a payload.
+    fn parse(packet: &[u8; PACKET_LEN]) -> Option<Header> {
+        if packet[0] != SYNC_BYTE {
+            return None;
+        }
+        let adaptation = (packet[3] >> 4) & 3;
+        if adaptation & 1 == 0 {
+            return None;
+        }
+        let payload = match adaptation & 2 {
+            0 => 4,
+            _ => 5 + usize::from(packet[4]),
+        };
+        (payload < PACKET_LEN).then_some(Header {
+            pid: u16::from(packet[1] & 0x1f) << 8 | u16::from(packet[2]),
+            unit_start: packet[1] & 0x40 != 0,
+            payload,
+        })
+    }
+}
+
+struct Pes {
+    pts: Option<u64>,
+    /// Where the elementary stream data starts.
+    data: usize,
+}
+
+impl Pes {
+    fn parse(payload: &[u8]) -> Option<Pes> {
+        if !payload.starts_with(&[0, 0, 1]) || payload.len() < 9 {
+            return None;
+        }
+        let pts = match payload[7] & 0x80 {
+            0 => None,
+            _ => payload.get(9..14).map(|p| {
+                u64::from((p[0] >> 1) & 7) << 30 | u64::from(p[1]) << 22
+                    | u64::from(p[2] >> 1) << 15 | u64::from(p[3]) << 7 | u64::from(p[4] >> 1)
+            }),
+        };
+        Some(Pes { pts, data: 9 + usize::from(payload[8]) })
+    }
+}
+
+// The elementary stream carried by `pid`, with the TS and PES headers
+// taken out.
+struct PesReader<R> {
+    inner: R,
+    pid: u16,
+    packet: [u8; PACKET_LEN],
+    start: usize,
+    end: usize,
+}
+
+impl<R: Read> PesReader<R> {
+    fn new(inner: R, pid: u16) -> PesReader<R> {
+        PesReader { inner, pid, packet: [0; PACKET_LEN], start: 0, end: 0 }
+    }
+
+    // Reads on to the next packet of the stream with any data in it. False
+    // at the end of the file.
+    fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            match self.inner.read_exact(&mut self.packet) {
+                Ok(()) => {},
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                Err(err) => return Err(err),
+            }
+            let Some(header) = Header::parse(&self.packet) else { continue };
+            if header.pid != self.pid {
+                continue;
+            }
+            self.start = header.payload;
+            if header.unit_start {
+                match Pes::parse(&self.packet[header.payload..]) {
+                    Some(pes) => self.start += pes.data,
+                    None => continue,
+                }
+            }
+            self.end = PACKET_LEN;
+            if self.start < self.end {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+// minimp3 takes a partial frame at the end of what it was given for junk
+// and skips it, so reads fill the whole buffer rather than stopping at the
+// end of a packet.
+impl<R: Read> Read for PesReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() {
+            if self.start == self.end && !self.fill()? {
+                break;
+            }
+            let len = (buf.len() - read).min(self.end - self.start);
+            buf[read..read + len].copy_from_slice(&self.packet[self.start..self.start + len]);
+            self.start += len;
+            read += len;
+        }
+        Ok(read)
+    }
+}
+
+// The audio of a PES stream, decoded a frame at a time into its channels,
+// sample rate and samples. AAC comes in ADTS frames, which symphonia reads.
+enum Frames {
+    Mpeg(Box<minimp3::Decoder<PesReader<BufReader<File>>>>),
+    Aac(ProbedSource),
+}
+
+impl Frames {
+    fn new(reader: PesReader<BufReader<File>>, codec: AudioCodec) -> io::Result<Frames> {
+        match codec {
+            AudioCodec::Mpeg => Ok(Frames::Mpeg(Box::new(minimp3::Decoder::new(reader)))),
+            AudioCodec::Aac => ProbedSource::adts(reader).map(Frames::Aac),
+        }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = (u16, u32, Vec<i16>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let decoder = match self {
+            Frames::Mpeg(decoder) => decoder,
+            Frames::Aac(decoder) => return decoder.take_packet(),
+        };
+        loop {
+            match decoder.next_frame() {
+                Ok(frame) if frame.channels > 0 && frame.sample_rate > 0 && !frame.data.is_empty() => {
+                    return Some((frame.channels as u16, frame.sample_rate as u32, frame.data));
+                },
+                Ok(_) | Err(minimp3::Error::SkippedData | minimp3::Error::InsufficientData) => {},
+                Err(minimp3::Error::Eof | minimp3::Error::Io(_)) => return None,
+            }
+        }
+    }
+}
+
+/// The decoded audio of a transport stream, for the sink.
+pub struct TsSource {
+    frames: Frames,
+    samples: Vec<i16>,
+    channels: u16,
+    rate: u32,
+    pos: usize,
+}
+
+impl TsSource {
+    /// Opens the transport stream at `path`, which has to carry MPEG audio
+    /// or AAC.
+    pub fn open(path: &Path) -> io::Result<TsSource> {
+        let (pid, codec) = find_audio(path).ok_or_else(|| Error::new(ErrorKind::InvalidData, "no audio stream"))?;
+        let reader = PesReader::new(BufReader::new(File::open(path)?), pid);
+        let mut source = TsSource {
+            frames: Frames::new(reader, codec)?,
+            samples: vec![],
+            channels: 0,
+            rate: 0,
+            pos: 0,
+        };
+        source.advance();
+        if source.samples.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "no audio could be decoded"));
+        }
+        Ok(source)
+    }
+
+    // Decodes the next frame, leaving no samples at the end.
+    fn advance(&mut self) {
+        self.pos = 0;
+        match self.frames.next() {
+            Some((channels, rate, samples)) => {
+                self.channels = channels;
+                self.rate = rate;
+                self.samples = samples;
+            },
+            None => self.samples.clear(),
+        }
+    }
+}
+
+impl Iterator for TsSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = *self.samples.get(self.pos)?;
+        self.pos += 1;
+        if self.pos == self.samples.len() {
+            self.advance();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for TsSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.samples.len() - self.pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels.max(1)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.rate.max(1)
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::*;
+
+    const PMT_PID: u16 = 0x100;
+    const AUDIO_PID: u16 = 0x102;
+    // An ADTS frame of mono AAC at 44.1 kHz with no spectral data: 1024
+    // samples of silence
+    const SILENT_FRAME: [u8; 11] = [0xff, 0xf1, 0x50, 0x40, 0x01, 0x7f, 0xfc, 0x00, 0xc8, 0x00, 0x07];
+    // The ticks of PTS clock that four of them play for, rounded down
+    const FOUR_FRAMES: u64 = 4 * 1024 * 90_000 / 44_100;
+
+    // A transport stream, built a packet at a time.
+    #[derive(Default)]
+    struct Fixture(Vec<u8>);
+
+    impl Fixture {
+        // A packet of `pid` holding `payload`, padded out in front with an
+        // adaptation field as muxers do.
+        fn packet(&mut self, pid: u16, unit_start: bool, payload: &[u8]) {
+            let mut packet = vec![SYNC_BYTE, u8::from(unit_start) << 6 | (pid >> 8) as u8, pid as u8];
+            match PACKET_LEN - 4 - payload.len() {
+                0 => packet.push(0x10),
+                pad => {
+                    packet.extend([0x30, (pad - 1) as u8, 0]);
+                    packet.resize(4 + pad, 0xff);
+                },
+            }
+            packet.extend(payload);
+            self.0.extend(packet);
+        }
+
+        // A PAT with the network table and one program, and a PMT with a
+        // video stream ahead of the audio one.
+        fn tables(mut self, audio_type: u8) -> Fixture {
+            let pat = [0, 0x00, 0xb0, 17, 0, 1, 0xc1, 0, 0, 0, 0, 0xe0, 0x10, 0, 1, 0xe1, 0x00, 0, 0, 0, 0];
+            self.packet(PAT_PID, true, &pat);
+            let pmt = [
+                0, 0x02, 0xb0, 26, 0, 1, 0xc1, 0, 0, 0xe1, 0x01, 0xf0, 0,
+                0x1b, 0xe1, 0x01, 0xf0, 3, 0x0a, 1, 0,
+                audio_type, 0xe1, 0x02, 0xf0, 0,
+                0, 0, 0, 0,
+            ];
+            self.packet(PMT_PID, true, &pmt);
+            self
+        }
+
+        // A PES packet of the audio stream, over as many packets as it takes.
+        fn pes(mut self, pts: Option<u64>, data: &[u8]) -> Fixture {
+            let mut pes = vec![0, 0, 1, 0xc0, 0, 0, 0x80];
+            match pts {
+                Some(pts) => {
+                    pes.extend([0x80, 5]);
+                    pes.extend(encode_pts(pts));
+                },
+                None => pes.extend([0, 0]),
+            }
+            pes.extend(data);
+            for (i, chunk) in pes.chunks(PACKET_LEN - 4).enumerate() {
+                self.packet(AUDIO_PID, i == 0, chunk);
+            }
+            self
+        }
+
+        fn write(&self, name: &str) -> PathBuf {
+            let path = env::temp_dir().join(format!("tasco-{}-{}.ts", process::id(), name));
+            fs::write(&path, &self.0).unwrap();
+            path
+        }
+    }
+
+    fn encode_pts(pts: u64) -> [u8; 5] {
+        [
+            0x21 | (((pts >> 30) & 7) as u8) << 1,
+            (pts >> 22) as u8,
+            (((pts >> 15) & 0x7f) as u8) << 1 | 1,
+            (pts >> 7) as u8,
+            ((pts & 0x7f) as u8) << 1 | 1,
+        ]
+    }
+
+    fn frames(count: usize) -> Vec<u8> {
+        SILENT_FRAME.repeat(count)
+    }
+
+    #[test]
+    fn tables_lead_to_the_first_audio_stream() {
+        let path = Fixture::default().tables(0x0f).write("aac-tables");
+        assert_eq!(find_audio(&path), Some((AUDIO_PID, AudioCodec::Aac)));
+        fs::remove_file(&path).unwrap();
+        let path = Fixture::default().tables(0x03).write("mpeg-tables");
+        assert_eq!(find_audio(&path), Some((AUDIO_PID, AudioCodec::Mpeg)));
+        fs::remove_file(&path).unwrap();
+        let path = Fixture::default().tables(0x02).write("no-audio");
+        assert_eq!(find_audio(&path), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn adaptation_fields_move_the_payload() {
+        let mut fixture = Fixture::default();
+        fixture.packet(0x1234, true, &[1; 100]);
+        fixture.packet(0x0042, false, &[2; PACKET_LEN - 4]);
+        fixture.packet(0x0042, false, &[3; PACKET_LEN - 5]);
+        let packets: Vec<[u8; PACKET_LEN]> = fixture.0.chunks(PACKET_LEN).map(|p| p.try_into().unwrap()).collect();
+        let header = Header::parse(&packets[0]).unwrap();
+        assert_eq!((header.pid, header.unit_start, header.payload), (0x1234, true, PACKET_LEN - 100));
+        assert_eq!(Header::parse(&packets[1]).unwrap().payload, 4);
+        // An adaptation field of just its length byte
+        assert_eq!(Header::parse(&packets[2]).unwrap().payload, 5);
+
+        let mut adaptation_only = packets[0];
+        adaptation_only[3] = 0x20;
+        assert!(Header::parse(&adaptation_only).is_none());
+        let mut all_adaptation = packets[0];
+        all_adaptation[4] = 183;
+        assert!(Header::parse(&all_adaptation).is_none());
+        let mut out_of_sync = packets[1];
+        out_of_sync[0] = 0;
+        assert!(Header::parse(&out_of_sync).is_none());
+    }
+
+    #[test]
+    fn timestamps_are_33_bits() {
+        for pts in [0, 1, 90_000, PTS_WRAP - 1] {
+            let mut payload = vec![0, 0, 1, 0xc0, 0, 0, 0x80, 0x80, 5];
+            payload.extend(encode_pts(pts));
+            let pes = Pes::parse(&payload).unwrap();
+            assert_eq!((pes.pts, pes.data), (Some(pts), 14));
+        }
+        let pes = Pes::parse(&[0, 0, 1, 0xc0, 0, 0, 0x80, 0, 3, 0xff, 0xff, 0xff]).unwrap();
+        assert_eq!((pes.pts, pes.data), (None, 12));
+        assert!(Pes::parse(&[0, 0, 2, 0xc0, 0, 0, 0x80, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn pes_packets_are_put_back_together() {
+        let first: Vec<u8> = (0..=255).cycle().take(400).collect();
+        let mut fixture = Fixture::default().tables(0x0f).pes(Some(0), &first).pes(None, b"second");
+        // A null packet in the middle of the first PES packet
+        let mut null = Fixture::default();
+        null.packet(0x1fff, false, &[0xff; 10]);
+        fixture.0.splice(3 * PACKET_LEN..3 * PACKET_LEN, null.0);
+        let mut data = vec![];
+        PesReader::new(fixture.0.as_slice(), AUDIO_PID).read_to_end(&mut data).unwrap();
+        assert_eq!(data, [first.as_slice(), b"second"].concat());
+    }
+
+    #[test]
+    fn aac_plays_for_as_long_as_its_timestamps_say() {
+        // The clock wraps between the first PES packet and the last
+        let first = PTS_WRAP - 1000;
+        let path = Fixture::default().tables(0x0f)
+            .pes(Some(first), &frames(4))
+            .pes(Some(first + FOUR_FRAMES), &frames(4))
+            .pes(Some((first + 2 * FOUR_FRAMES) % PTS_WRAP), &frames(4))
+            .write("aac");
+        let expected = (2 * FOUR_FRAMES) as f64 / PTS_RATE + 4.0 * 1024.0 / 44_100.0;
+        let duration = duration(&path).unwrap().as_secs_f64();
+        assert!((duration - expected).abs() < 1e-6, "{} != {}", duration, expected);
+
+        let source = TsSource::open(&path).unwrap();
+        assert_eq!((source.channels(), source.sample_rate()), (1, 44_100));
+        assert_eq!(source.count(), 12 * 1024);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+
+use flume::Receiver;
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use zbus::{dbus_interface, fdo, SignalContext};
+
+use crate::format::percent_decode;
+use crate::play_controller::RepeatMode;
+use crate::protocol::{Playback, PlayerEvent, PlayerState, Request, Response};
+use crate::radio::is_stream;
+use crate::service::Handle;
+use crate::tags::Tags;
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.tasco";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Puts the player on the session bus as `org.mpris.MediaPlayer2.tasco`,
+/// so media keys, desktop widgets and `playerctl` can control it. Dropping
+/// the returned connection takes it off the bus again.
+pub fn start(handle: Handle) -> zbus::Result<Connection> {
+    serve(handle, ConnectionBuilder::session()?)
+}
+
+// Serves the player on the bus `builder` connects to.
+fn serve(handle: Handle, builder: ConnectionBuilder) -> zbus::Result<Connection> {
+    // Subscribed first so nothing that happens after the state is taken
+    // gets missed
+    let events = handle.subscribe();
+    let state = handle.call(Request::Status { since: None }).state
+        .ok_or_else(|| zbus::Error::Failure("the player has shut down".to_string()))?;
+    let player = Player { handle: handle.clone(), metadata: metadata(&state), state };
+    let connection = builder
+        .serve_at(OBJECT_PATH, Root { handle: handle.clone() })?
+        .serve_at(OBJECT_PATH, player)?
+        .build()?;
+    // The spec's way for a second instance to show up next to the first
+    if connection.request_name(BUS_NAME).is_err() {
+        connection.request_name(format!("{}.instance{}", BUS_NAME, process::id()))?;
+    }
+    let watched = connection.clone();
+    thread::spawn(move || watch(watched, handle, events));
+    Ok(connection)
+}
+
+fn call(handle: &Handle, request: Request) -> fdo::Result<Response> {
+    handle.call(request).into_result().map_err(fdo::Error::Failed)
+}
+
+struct Root {
+    handle: Handle,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) -> fdo::Result<()> {
+        call(&self.handle, Request::Shutdown).map(|_| ())
+    }
+
+    #[dbus_interface(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[dbus_interface(property)]
+    fn identity(&self) -> &str {
+        "Tasco"
+    }
+
+    #[dbus_interface(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".to_string(), "http".to_string()]
+    }
+
+    #[dbus_interface(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        ["audio/mpeg", "audio/x-wav", "audio/flac", "audio/ogg", "audio/opus", "audio/aac", "audio/mp4", "audio/x-m4a", "video/mp2t"]
+            .map(String::from).to_vec()
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player`. Properties are answered from the last
+/// state `watch` fetched, which is also how it knows what changed.
+struct Player {
+    handle: Handle,
+    state: PlayerState,
+    // Tags are read once per track rather than on every property read
+    metadata: HashMap<String, OwnedValue>,
+}
+
+#[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) -> fdo::Result<()> {
+        call(&self.handle, Request::Next).map(|_| ())
+    }
+
+    fn previous(&self) -> fdo::Result<()> {
+        call(&self.handle, Request::Prev).map(|_| ())
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        call(&self.handle, Request::Pause).map(|_| ())
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        call(&self.handle, Request::Toggle).map(|_| ())
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        call(&self.handle, Request::Stop).map(|_| ())
+    }
+
+    fn play(&self) -> fdo::Result<()> {
+        call(&self.handle, Request::Play { index: None }).map(|_| ())
+    }
+
+    /// `offset` is in microseconds and may be negative.
+    fn seek(&self, offset: i64) -> fdo::Result<()> {
+        call(&self.handle, Request::SeekBy { seconds: offset as f64 / 1e6 }).map(|_| ())
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        // A position for a track that is no longer playing is ignored, as
+        // the spec asks
+        if track_id.as_str() != track_id_of(&self.state).as_str() || position < 0 {
+            return Ok(());
+        }
+        call(&self.handle, Request::Seek { position: position as f64 / 1e6 }).map(|_| ())
+    }
+
+    fn open_uri(&self, uri: &str) -> fdo::Result<()> {
+        let path = match uri.strip_prefix("file://") {
+            Some(path) => PathBuf::from(percent_decode(path)),
+            None if is_stream(Path::new(uri)) => PathBuf::from(uri),
+            None => return Err(fdo::Error::NotSupported(format!("only file:// and http:// URIs can be opened, not {}", uri))),
+        };
+        call(&self.handle, Request::Open { path }).map(|_| ())
+    }
+
+    #[dbus_interface(signal)]
+    async fn seeked(ctxt: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[dbus_interface(property)]
+    fn playback_status(&self) -> &str {
+        match self.state.playback {
+            Playback::Playing => "Playing",
+            Playback::Paused => "Paused",
+            Playback::Stopped => "Stopped",
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn loop_status(&self) -> &str {
+        match self.state.repeat {
+            RepeatMode::Off => "None",
+            RepeatMode::One => "Track",
+            RepeatMode::All => "Playlist",
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn set_loop_status(&mut self, status: &str) -> fdo::Result<()> {
+        let mode = match status {
+            "None" => RepeatMode::Off,
+            "Track" => RepeatMode::One,
+            "Playlist" => RepeatMode::All,
+            other => return Err(fdo::Error::InvalidArgs(format!("unknown loop status {}", other))),
+        };
+        call(&self.handle, Request::Repeat { mode: Some(mode) })?;
+        self.state.repeat = mode;
+        Ok(())
+    }
+
+    #[dbus_interface(property)]
+    fn shuffle(&self) -> bool {
+        self.state.shuffle
+    }
+
+    #[dbus_interface(property)]
+    fn set_shuffle(&mut self, on: bool) -> fdo::Result<()> {
+        call(&self.handle, Request::Shuffle { on: Some(on) })?;
+        self.state.shuffle = on;
+        Ok(())
+    }
+
+    #[dbus_interface(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        self.metadata.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> f64 {
+        self.state.volume as f64 / 100.0
+    }
+
+    #[dbus_interface(property)]
+    fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        let percent = (volume.clamp(0.0, 1.0) * 100.0).round() as u8;
+        call(&self.handle, Request::Volume { percent })?;
+        self.state.volume = percent;
+        Ok(())
+    }
+
+    /// Asked for fresh, since it changes all the time and isn't signalled.
+    #[dbus_interface(property)]
+    fn position(&self) -> i64 {
+        let state = self.handle.call(Request::Status { since: Some(self.state.playlist_version) }).state;
+        let position = state.map_or(self.state.current_time, |state| state.current_time);
+        position.as_micros() as i64
+    }
+
+    #[dbus_interface(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_next(&self) -> bool {
+        !self.state.play_list.is_empty()
+    }
+
+    #[dbus_interface(property)]
+    fn can_go_previous(&self) -> bool {
+        !self.state.play_list.is_empty()
+    }
+
+    #[dbus_interface(property)]
+    fn can_play(&self) -> bool {
+        !self.state.play_list.is_empty()
+    }
+
+    #[dbus_interface(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    // Streams have no length to seek in
+    #[dbus_interface(property)]
+    fn can_seek(&self) -> bool {
+        !self.state.playing_path.as_deref().is_some_and(is_stream)
+    }
+
+    #[dbus_interface(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+// Sends PropertiesChanged and Seeked as the player's events come in, until
+// the player goes away.
+fn watch(connection: Connection, handle: Handle, events: Receiver<PlayerEvent>) {
+    let Ok(iface) = connection.object_server().interface::<_, Player>(OBJECT_PATH) else { return };
+    let ctxt = iface.signal_context();
+    while let Ok(event) = events.recv() {
+        // Events come in bunches, which are looked at together
+        let mut seeked = matches!(event, PlayerEvent::Seeked(_));
+        for event in events.try_iter() {
+            seeked |= matches!(event, PlayerEvent::Seeked(_));
+        }
+        let since = iface.get().state.playlist_version;
+        let Some(state) = handle.call(Request::Status { since: Some(since) }).state else { return };
+
+        let mut player = iface.get_mut();
+        let old = &player.state;
+        let track_changed = old.playing_path != state.playing_path
+            || old.stream_title != state.stream_title
+            || old.playing_index != state.playing_index
+            || old.is_playing_or_paused() != state.is_playing_or_paused();
+        let playback_changed = old.playback != state.playback;
+        let volume_changed = old.volume != state.volume;
+        let repeat_changed = old.repeat != state.repeat;
+        let shuffle_changed = old.shuffle != state.shuffle;
+        let was_empty = old.play_list.is_empty();
+        player.state.update(state);
+        let list_changed = was_empty != player.state.play_list.is_empty();
+        if track_changed {
+            player.metadata = metadata(&player.state);
+        }
+        drop(player);
+
+        let player = iface.get();
+        let result = zbus::block_on(async {
+            if track_changed {
+                player.metadata_changed(ctxt).await?;
+                player.can_seek_changed(ctxt).await?;
+            }
+            if playback_changed {
+                player.playback_status_changed(ctxt).await?;
+            }
+            if volume_changed {
+                player.volume_changed(ctxt).await?;
+            }
+            if repeat_changed {
+                player.loop_status_changed(ctxt).await?;
+            }
+            if shuffle_changed {
+                player.shuffle_changed(ctxt).await?;
+            }
+            if list_changed {
+                player.can_go_next_changed(ctxt).await?;
+                player.can_go_previous_changed(ctxt).await?;
+                player.can_play_changed(ctxt).await?;
+            }
+            if seeked {
+                Player::seeked(ctxt, player.state.current_time.as_micros() as i64).await?;
+            }
+            Ok::<(), zbus::Error>(())
+        });
+        // Losing the bus leaves nothing to signal to
+        if result.is_err() {
+            return;
+        }
+    }
+}
+
+fn track_id_of(state: &PlayerState) -> ObjectPath<'static> {
+    match state.playing_index {
+        Some(index) if state.is_playing_or_paused() => {
+            ObjectPath::from_string_unchecked(format!("/org/tasco/track/{}", index))
+        }
+        _ => ObjectPath::from_static_str_unchecked(NO_TRACK),
+    }
+}
+
+// The `xesam:` and `mpris:` fields for the current track.
+fn metadata(state: &PlayerState) -> HashMap<String, OwnedValue> {
+    let mut map = HashMap::new();
+    map.insert("mpris:trackid".to_string(), Value::from(track_id_of(state)).into());
+    let Some(path) = state.playing_path.as_ref().filter(|_| state.is_playing_or_paused()) else {
+        return map;
+    };
+    let tags = Tags::read(path);
+    let name = state.playing_song.clone().unwrap_or_default();
+    // Radio streams have no length, and their URL is already one
+    let url = match is_stream(path) {
+        true => path.to_string_lossy().into_owned(),
+        false => {
+            map.insert("mpris:length".to_string(), Value::from(state.total_time.as_micros() as i64).into());
+            file_uri(path)
+        },
+    };
+    map.insert("xesam:url".to_string(), Value::from(url).into());
+    let title = state.stream_title.clone().or(tags.title).unwrap_or(name);
+    map.insert("xesam:title".to_string(), Value::from(title).into());
+    if let Some(artist) = tags.artist {
+        map.insert("xesam:artist".to_string(), Value::from(vec![artist]).into());
+    }
+    if let Some(album) = tags.album {
+        map.insert("xesam:album".to_string(), Value::from(album).into());
+    }
+    if let Some(track) = tags.track {
+        map.insert("xesam:trackNumber".to_string(), Value::from(track as i32).into());
+    }
+    map
+}
+
+// `file://` followed by the path with everything but unreserved characters
+// and slashes percent-encoded.
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::Duration;
+
+    use flume::Sender;
+    use zbus::blocking::fdo::PropertiesProxy;
+    use zbus::names::InterfaceName;
+
+    use super::*;
+    use crate::protocol::Change;
+    use crate::service::{Call, Requests};
+
+    const PLAYER: &str = "org.mpris.MediaPlayer2.Player";
+    const WAIT: Duration = Duration::from_secs(5);
+
+    // A bus of our own, which goes away with the test.
+    struct Bus(Child);
+
+    impl Bus {
+        fn spawn() -> Option<(Bus, String)> {
+            let mut child = Command::new("dbus-daemon")
+                .args(["--session", "--print-address", "--nofork"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(child.stdout.take()?).read_line(&mut address).ok()?;
+            Some((Bus(child), address.trim().to_string()))
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn state() -> PlayerState {
+        PlayerState {
+            play_list: vec![("a.mp3".to_string(), PathBuf::from("/music/a.mp3"))],
+            playlist_ids: vec![0],
+            playlist_version: 1,
+            playlist_index: 0,
+            playing_index: Some(0),
+            playing_song: Some("a.mp3".to_string()),
+            playing_path: Some(PathBuf::from("/music/a.mp3")),
+            stream_title: None,
+            playback: Playback::Paused,
+            current_time: Duration::from_secs(30),
+            total_time: Duration::from_secs(200),
+            volume: 100,
+            repeat: RepeatMode::All,
+            shuffle: false,
+            scanning: None,
+        }
+    }
+
+    // Stands in for the player thread: answers with `state`, applies the
+    // few requests the test sends and passes each of them on to `seen`.
+    fn fake_player(requests: Requests, seen: Sender<Request>) {
+        let mut state = state();
+        let mut subscribers: Vec<Sender<PlayerEvent>> = vec![];
+        for call in requests.iter() {
+            let (request, reply) = match call {
+                Call::Subscribe(events) => {
+                    subscribers.push(events);
+                    continue;
+                },
+                Call::Request(request, reply) => (request, reply),
+            };
+            let change = match &request {
+                Request::Toggle => {
+                    state.playback = match state.playback {
+                        Playback::Playing => Playback::Paused,
+                        _ => Playback::Playing,
+                    };
+                    Some(PlayerEvent::Changed(Change::Playback))
+                },
+                Request::Volume { percent } => {
+                    state.volume = *percent;
+                    Some(PlayerEvent::Changed(Change::Volume))
+                },
+                Request::SeekBy { seconds } => {
+                    state.current_time = Duration::from_secs_f64(state.current_time.as_secs_f64() + seconds);
+                    Some(PlayerEvent::Seeked(state.current_time))
+                },
+                _ => None,
+            };
+            let response = match request {
+                Request::Status { .. } => Response { state: Some(state.clone()), ..Response::ok() },
+                _ => Response::ok(),
+            };
+            let _ = reply.send(response);
+            if let Some(event) = change {
+                subscribers.retain(|events| events.send(event).is_ok());
+            }
+            if !matches!(request, Request::Status { .. }) {
+                let _ = seen.send(request);
+            }
+        }
+    }
+
+    #[test]
+    fn controls_and_signals_on_a_private_bus() {
+        let Some((_bus, address)) = Bus::spawn() else {
+            eprintln!("dbus-daemon is not installed, skipping");
+            return;
+        };
+
+        let (handle, requests) = Handle::new();
+        let (seen_tx, seen) = flume::unbounded();
+        thread::spawn(move || fake_player(requests, seen_tx));
+        let _server = serve(handle, ConnectionBuilder::address(address.as_str()).unwrap()).unwrap();
+
+        let client = ConnectionBuilder::address(address.as_str()).unwrap().build().unwrap();
+        let properties = PropertiesProxy::builder(&client)
+            .destination(BUS_NAME).unwrap()
+            .path(OBJECT_PATH).unwrap()
+            .build().unwrap();
+        let (changed_tx, changed) = flume::unbounded();
+        let mut signals = properties.receive_properties_changed().unwrap();
+        thread::spawn(move || {
+            for signal in &mut signals {
+                let Ok(args) = signal.args() else { continue };
+                for (name, value) in args.changed_properties() {
+                    let _ = changed_tx.send((name.to_string(), OwnedValue::from(value.clone())));
+                }
+            }
+        });
+        let next_change = || changed.recv_timeout(WAIT).expect("no PropertiesChanged signal");
+
+        client.call_method(Some(BUS_NAME), OBJECT_PATH, Some(PLAYER), "PlayPause", &()).unwrap();
+        assert!(matches!(seen.recv_timeout(WAIT).unwrap(), Request::Toggle));
+        let (name, value) = next_change();
+        assert_eq!(name, "PlaybackStatus");
+        assert_eq!(value, OwnedValue::from(Value::from("Playing")));
+
+        client.call_method(Some(BUS_NAME), OBJECT_PATH, Some(PLAYER), "Seek", &(-5_000_000i64)).unwrap();
+        match seen.recv_timeout(WAIT).unwrap() {
+            Request::SeekBy { seconds } => assert_eq!(seconds, -5.0),
+            other => panic!("expected a seek, got {:?}", other),
+        }
+
+        let player = InterfaceName::try_from(PLAYER).unwrap();
+        properties.set(player.clone(), "Volume", &Value::from(0.4)).unwrap();
+        assert!(matches!(seen.recv_timeout(WAIT).unwrap(), Request::Volume { percent: 40 }));
+        let (name, value) = next_change();
+        assert_eq!(name, "Volume");
+        assert_eq!(value, OwnedValue::from(Value::from(0.4)));
+        assert_eq!(properties.get(player, "PlaybackStatus").unwrap(), OwnedValue::from(Value::from("Playing")));
+    }
+}
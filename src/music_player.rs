@@ -1,83 +1,369 @@
-use std::io::{self, Error, ErrorKind, Stdout};
+use std::io::{self, Error, ErrorKind, Stdout, Write};
 use std::fs::{self, DirEntry, ReadDir};
 use std::thread;
-use std::collections::HashMap;
+use std::collections::BTreeSet;
 use std::path::{Path, PathBuf};
-use std::ffi::{OsStr, OsString};
-use std::time::Duration;
-use clap::App;
+use std::time::{Duration, Instant};
+use async_io::Timer;
+use flume::Receiver;
+use futures::{executor, future, select, FutureExt, StreamExt};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event as InputEvent, KeyCode, KeyEvent, KeyModifiers},
+    cursor::Show,
+    event::{self, DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture, Event as InputEvent, EventStream, KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use tui::{backend::CrosstermBackend, Terminal};
-use flume::{Sender, Receiver};
 
-use crate::config::{Music_file_extensions, Config, RawKey, KeyBinding};
-use crate::ui::UI;
-use crate::play_controller::{PlayController, PlayStatus};
+use crate::config::{Config, RawKey, KeyBinding};
+use crate::keymap::{normalize, Action, Lookup};
+use crate::ui::{Dirty, UI, Hit, View};
+use crate::layout::Panel;
+use crate::selection::{Focus, Selection};
+use crate::scan::looks_like_music_file;
+use crate::session::Session;
+use crate::command::{Command, CommandLine, Message};
+use crate::format::format_time;
+use crate::playlist_file::{self, is_playlist_file};
+use crate::launch::Launch;
+use crate::client::Client;
+use crate::protocol::{PlayerEvent, PlayerState, Request, Response};
+use crate::play_controller::SortKey;
+use crate::service::{Handle, Service};
+use crate::daemon;
+use crate::library::{self, Album};
+use crate::{http, mpd, mpris};
+
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+const KEY_SEQUENCE_TIMEOUT: Duration = Duration::from_secs(1);
+const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(400);
+// How often a daemon that isn't playing is asked whether anything changed
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How often the position is drawn while playing in a terminal that isn't
+// focused, unless `refresh_ms` is slower still
+const UNFOCUSED_REFRESH: Duration = Duration::from_secs(1);
+
+enum Backend {
+    /// No daemon is running, so the player runs on a thread of this process.
+    Local(Handle),
+    /// Attached to a daemon, which keeps playing after the TUI quits.
+    Remote(Client),
+}
+
+impl Backend {
+    fn send(&mut self, request: Request) -> Result<Response, Error> {
+        match self {
+            Backend::Local(handle) => Ok(handle.call(request)),
+            Backend::Remote(client) => client.send(&request)
+                .map_err(|err| Error::new(err.kind(), format!("Lost the connection to the daemon: {}", err))),
+        }
+    }
+
+    fn status(&mut self, since: Option<u64>) -> Result<PlayerState, Error> {
+        self.send(Request::Status { since })?
+            .state
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "the player sent no state"))
+    }
+}
 
 pub struct MusicPlayer {
     config: Config,
     terminal: Terminal<CrosstermBackend<Stdout>>,
     term_ui: UI,
-    player: PlayController,
+    // What has changed on screen since the last draw
+    dirty: Dirty,
+    backend: Backend,
+    // What the TUI knows of the player, refreshed after every request
+    player: PlayerState,
+    // The player's thread and what it says has changed, while the player
+    // runs in this process
+    player_thread: Option<thread::JoinHandle<PlayerState>>,
+    events: Option<Receiver<PlayerEvent>>,
+    // The library scan, from when the library panel is first shown until
+    // its albums come in
+    library: Option<Receiver<Vec<Album>>>,
+    library_started: bool,
+    socket_path: Option<PathBuf>,
+    mpris: Option<zbus::blocking::Connection>,
     current_dir: PathBuf,
     explorer_list: Vec<(String, PathBuf)>,   
     explorer_index: usize,
+    clipboard: Vec<(String, PathBuf)>,
+    sort_key: SortKey,
+    focus: Focus,
+    explorer_marks: Selection,
+    playlist_marks: Selection,
+    last_save: Instant,
+    // When the sleep timer pauses playback
+    sleep_at: Option<Instant>,
+    // Whether the terminal has the focus, for terminals that report it
+    focused: bool,
+    pending_keys: Vec<KeyBinding>,
+    last_key: Instant,
+    help_scroll: Option<u16>,
+    last_click: Option<(Instant, Hit)>,
+    command_line: CommandLine,
+    // Directories given on the command line and the configured music roots
+    bookmarks: Vec<PathBuf>,
     quit: bool,
 }
 
 impl MusicPlayer {
-    pub fn new(args:App,config: Config) -> Result<MusicPlayer, Error> {
-        let args = args.get_matches();
-        let music_dir = args.value_of("dir").unwrap_or_default();
-        let current_dir = PathBuf::from(music_dir);
-        if !current_dir.is_dir() || !current_dir.exists() {
-            return Err(Error::from(io::ErrorKind::InvalidInput));
-        }
-        enable_raw_mode()?;
-        let mut stdout = io::stdout();
-        execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-        let backend = CrosstermBackend::new(stdout);
-        let mut terminal = Terminal::new(backend)?;
-        terminal.hide_cursor()?;
-        let  term_ui = UI::new(&config, &terminal)?;
+    pub fn new(launch: &Launch, config: Config) -> Result<MusicPlayer, Error> {
+        let session = Session::load();
+        let last_dir = session.as_ref().and_then(|s| s.current_dir.clone());
+        let current_dir = launch.start_dir(&config.music_roots, last_dir);
+        let mut player_thread = None;
+        let mut events = None;
+        let mut socket_path = None;
+        let mut mpris = None;
+        let mut command_line = CommandLine::new();
+        let mut backend = match Client::connect() {
+            Ok(client) => Backend::Remote(client),
+            Err(_) => {
+                let (handle, calls) = Handle::new();
+                player_thread = Some(Service::spawn(&config, session, launch.resume, calls));
+                events = Some(handle.subscribe());
+                // Being controllable from outside is a bonus, so the player
+                // starts even if the socket or the bus can't be set up
+                socket_path = daemon::listen(handle.clone()).ok();
+                if config.mpris {
+                    mpris = mpris::start(handle.clone()).ok();
+                }
+                // Unlike the socket and the bus, these servers have to be
+                // asked for, so failing to start them is worth a word
+                if config.mpd.enabled {
+                    if let Err(err) = mpd::start(handle.clone(), &config.mpd, config.music_roots.first().cloned()) {
+                        command_line.message = Some(Message::Error(format!("Could not start the MPD server: {}", err)));
+                    }
+                }
+                if config.http.enabled {
+                    if let Err(err) = http::start(handle.clone(), &config.http, config.music_roots.clone()) {
+                        command_line.message = Some(Message::Error(format!("Could not start the HTTP API: {}", err)));
+                    }
+                }
+                Backend::Local(handle)
+            },
+        };
+        let setup = queue_launch(launch, &mut backend)
+            .and_then(|player| Ok((player, open_terminal(&config)?)));
+        let (player, (terminal, term_ui)) = match setup {
+            Ok(setup) => setup,
+            Err(err) => {
+                drop(mpris);
+                stop_backend(&backend, player_thread, socket_path);
+                return Err(err);
+            },
+        };
+        let mut bookmarks = launch.dirs.clone();
+        for root in &config.music_roots {
+            if !bookmarks.contains(root) {
+                bookmarks.push(root.clone());
+            }
+        }
         Ok(Self { 
             config, 
             terminal,
             term_ui,
+            dirty: Dirty::everything(),
             current_dir,
             explorer_list: vec![],
             explorer_index: 0,
+            clipboard: vec![],
+            sort_key: SortKey::Name,
+            focus: Focus::Explorer,
+            explorer_marks: Selection::default(),
+            playlist_marks: Selection::default(),
+            last_save: Instant::now(),
+            sleep_at: None,
+            focused: true,
+            pending_keys: vec![],
+            last_key: Instant::now(),
+            help_scroll: None,
+            last_click: None,
+            command_line,
+            bookmarks,
             quit: false,
-            player: PlayController::new(),
+            backend,
+            player,
+            player_thread,
+            events,
+            library: None,
+            library_started: false,
+            socket_path,
+            mpris,
         })
     }
 
     pub fn run(&mut self) -> Result<(), Error> {
         let (path_list, name_list) =  self.read_dir_and_music(self.current_dir.as_path())?;
         self.explorer_list = name_list.into_iter().zip(path_list.into_iter()).collect();
-        self.draw_ui();      
+        executor::block_on(self.event_loop())?;
+        self.save_session();
+        Ok(())      
+    }
+
+    // Waits for input, the player's events and timers all at once, and
+    // only draws again once one of them has come in.
+    async fn event_loop(&mut self) -> Result<(), Error> {
+        let mut input = EventStream::new();
+        let events = self.events.clone();
         while !self.quit {
-            self.process_input()?;
-            self.player.tick();
-            self.draw_ui()?;
+            if !self.dirty.is_clean() {
+                self.draw_ui()?;
+                self.dirty = Dirty::default();
+            }
+            if !self.library_started && self.term_ui.shows(Panel::Library) {
+                self.library_started = true;
+                self.library = Some(library::spawn(self.library_roots()));
+            }
+            let scan = self.library.clone();
+            let albums = async {
+                match &scan {
+                    Some(scan) => scan.recv_async().await.ok(),
+                    None => future::pending().await,
+                }
+            }.fuse();
+            // A daemon sends no events, so it is polled by the timer instead
+            let player_event = async {
+                match &events {
+                    Some(events) => events.recv_async().await,
+                    None => future::pending().await,
+                }
+            }.fuse();
+            let mut timer = FutureExt::fuse(Timer::after(self.next_wakeup()));
+            futures::pin_mut!(player_event, albums);
+            select! {
+                event = input.next().fuse() => match event {
+                    // Moving the mouse over the window changes nothing
+                    Some(Ok(InputEvent::Mouse(MouseEvent { kind: MouseEventKind::Moved, .. }))) => {},
+                    // Out of focus the wave stops and the position slows
+                    // down, so losing it needs no redraw
+                    Some(Ok(InputEvent::FocusLost)) => self.focused = false,
+                    Some(Ok(InputEvent::FocusGained)) => {
+                        self.focused = true;
+                        self.dirty.mark(Panel::Progress);
+                        self.dirty.mark(Panel::Wave);
+                    },
+                    // Keys can change anything, so it is all drawn again
+                    Some(event) => {
+                        self.dirty.mark_all();
+                        self.process_input(event?)?;
+                    },
+                    None => self.quit = true,
+                },
+                event = player_event => {
+                    match event {
+                        Ok(_) => {
+                            // Whatever else has queued up is covered by the same refresh
+                            if let Some(events) = &events {
+                                events.drain();
+                            }
+                            self.refresh()?;
+                        },
+                        // The player shutting down ends the TUI too
+                        Err(_) => self.quit = true,
+                    }
+                },
+                albums = albums => {
+                    self.library = None;
+                    if let Some(albums) = albums {
+                        self.term_ui.set_albums(albums);
+                        self.dirty.mark(Panel::Library);
+                    }
+                },
+                _ = timer => {
+                    // The position moves on by itself while playing
+                    if self.player.is_playing() || events.is_none() {
+                        self.refresh()?;
+                    }
+                    if self.player.is_playing() && self.focused {
+                        self.dirty.mark(Panel::Wave);
+                    }
+                },
+            };
+            if self.sleep_at.is_some_and(|at| at <= Instant::now()) {
+                self.sleep_at = None;
+                self.send(Request::Pause)?;
+                self.command_line.message = Some(Message::Info("Paused by the sleep timer".to_string()));
+                self.dirty.mark_all();
+            }
+            if self.last_save.elapsed() >= SESSION_SAVE_INTERVAL {
+                self.save_session();
+            }
         }
-        Ok(())      
+        Ok(())
+    }
+
+    // The library covers the directories given on the command line and the
+    // music roots, or the start directory when there are none.
+    fn library_roots(&self) -> Vec<PathBuf> {
+        match self.bookmarks.is_empty() {
+            true => vec![self.current_dir.clone()],
+            false => self.bookmarks.clone(),
+        }
+    }
+
+    // How long the event loop can wait with nothing coming in: until the
+    // next redraw of the position, the sleep timer or saving the session.
+    // A daemon is polled, but only now and then while it isn't playing.
+    fn next_wakeup(&self) -> Duration {
+        let mut wait = SESSION_SAVE_INTERVAL.saturating_sub(self.last_save.elapsed());
+        let refresh = Duration::from_millis(self.config.fresh_time);
+        if let Some(interval) = redraw_interval(self.player.is_playing(), self.focused, refresh) {
+            wait = wait.min(interval);
+        }
+        else if self.events.is_none() {
+            wait = wait.min(IDLE_POLL_INTERVAL);
+        }
+        if let Some(at) = self.sleep_at {
+            wait = wait.min(at.saturating_duration_since(Instant::now()));
+        }
+        wait
+    }
+
+    // Sends a request to the player and brings our snapshot up to date.
+    fn send(&mut self, request: Request) -> Result<Response, Error> {
+        let response = self.backend.send(request)?;
+        self.refresh()?;
+        Ok(response)
+    }
+
+    // Like `send`, but a refused request is an error too, for the command line.
+    fn request(&mut self, request: Request) -> Result<Response, String> {
+        self.send(request).map_err(|err| err.to_string())?.into_result()
+    }
+
+    fn refresh(&mut self) -> Result<(), Error> {
+        let state = self.backend.status(Some(self.player.playlist_version))?;
+        for change in self.player.changes(&state) {
+            self.dirty.change(change);
+        }
+        if state.current_time != self.player.current_time {
+            self.dirty.mark(Panel::Progress);
+            self.dirty.mark(Panel::Lyrics);
+        }
+        // The playlist may have been edited by another client, which would
+        // leave the marks on the wrong entries
+        if state.playlist_version != self.player.playlist_version {
+            self.playlist_marks.remap(&self.player.playlist_ids, &state.playlist_ids);
+        }
+        self.player.update(state);
+        Ok(())
     }
 
     fn draw_ui(&mut self) -> Result<(), Error> {
-        self.term_ui.draw_ui(&mut self.terminal, 
-            &self.explorer_list, 
-            &self.player.play_list,
-            self.current_dir.to_str(),
-            self.player.playing_song.as_ref(),
-            self.explorer_index,
-            self.player.playlist_index,
-            &self.player
-        )
+        let view = View {
+            explorer_list: &self.explorer_list,
+            dir_name: self.current_dir.to_str(),
+            explore_index: self.explorer_index,
+            focus: self.focus,
+            explorer_marks: &self.explorer_marks,
+            playlist_marks: &self.playlist_marks,
+            help_scroll: self.help_scroll,
+            command_line: &self.command_line,
+        };
+        self.term_ui.draw_ui(&mut self.terminal, &self.player, &view, &self.dirty)
     }
 
     fn read_dir_and_music(&self, dir_path: &Path) -> Result<(Vec<PathBuf>, Vec<String>), Error>{
@@ -87,108 +373,438 @@ impl MusicPlayer {
             let entry = entry?;
             let tp =  entry.file_type()?;
             if let Ok(name) = entry.file_name().into_string() {
-                if tp.is_dir() {
+                if tp.is_dir() || (tp.is_file() && looks_like_music_file(&entry.path())) {
                     path_list.push(entry.path());
                     name_list.push(name);
                 }
-                else if tp.is_file() {
-                    if let Some(file_extension) = entry.path().extension().and_then(OsStr::to_str){
-                        if Music_file_extensions.contains(&file_extension) {
-                            path_list.push(entry.path());
-                            name_list.push(name);
-                        }
-                    }
-                }
             }
             
         }
         Ok((path_list, name_list))
     }
 
-    fn process_input(&mut self) -> Result<(), Error>{
-        match self.read_event() {
-            Some(InputEvent::Key(key)) => self.process_key(key)?,
+    // A daemon keeps its own session, so only a player running in this
+    // process is saved.
+    fn save_session(&mut self) {
+        if let Backend::Local(_) = &self.backend {
+            // Losing the session isn't worth interrupting playback over
+            let _ = Session::capture(&self.player, Some(self.current_dir.clone())).save();
+        }
+        self.last_save = Instant::now();
+    }
+
+    fn process_input(&mut self, event: InputEvent) -> Result<(), Error>{
+        match event {
+            InputEvent::Key(key) => self.process_key(key)?,
+            InputEvent::Mouse(mouse) if self.help_scroll.is_none() => self.process_mouse(mouse)?,
             _ =>()
         }
         Ok(())
     }
 
-    fn read_event(&mut self) -> Option<InputEvent> {
-    
-        if let Ok(true) = crossterm::event::poll(Duration::from_millis(self.config.fresh_time)) {
-            if let Ok(key) = crossterm::event::read() {
-                return Some(key);
+    fn process_key(&mut self, key: KeyEvent) -> Result<(), Error>{
+        if self.command_line.active {
+            return self.process_command_key(key);
+        }
+        self.command_line.message = None;
+        let key_bind = normalize(Self::key_event_to_player_key(key.code, key.modifiers));
+        if let Some(scroll) = self.help_scroll {
+            self.process_help_key(key_bind, scroll);
+            return Ok(());
+        }
+        if self.last_key.elapsed() > KEY_SEQUENCE_TIMEOUT {
+            self.pending_keys.clear();
+        }
+        self.last_key = Instant::now();
+        self.pending_keys.push(key_bind);
+        let mut lookup = self.config.keymap.lookup(&self.pending_keys);
+        if let Lookup::NoMatch = lookup {
+            // An abandoned sequence shouldn't swallow the key that broke it
+            if self.pending_keys.len() > 1 {
+                self.pending_keys = vec![key_bind];
+                lookup = self.config.keymap.lookup(&self.pending_keys);
             }
-        } 
-        None
+        }
+        match lookup {
+            Lookup::Action(action) => {
+                self.pending_keys.clear();
+                self.process_action(action)
+            },
+            Lookup::Pending => Ok(()),
+            Lookup::NoMatch => {
+                self.pending_keys.clear();
+                Ok(())
+            },
+        }
     }
 
-    fn process_key(&mut self, key: KeyEvent) -> Result<(), Error>{
-        let key_bind = Self::key_event_to_player_key(key.code, key.modifiers);
-        match key_bind {
-            KeyBinding::Raw(RawKey::Up) => {
-                self.explorer_up(); 
+    fn process_mouse(&mut self, mouse: MouseEvent) -> Result<(), Error> {
+        let hit = match self.term_ui.hit(mouse.column, mouse.row, self.explorer_index, self.explorer_list.len(), &self.player) {
+            Some(hit) => hit,
+            None => return Ok(()),
+        };
+        match (mouse.kind, hit) {
+            (MouseEventKind::Down(MouseButton::Left), Hit::Explorer(row)) => {
+                self.focus = Focus::Explorer;
+                if let Some(row) = row {
+                    self.explorer_index = row;
+                    if self.is_double_click(hit) {
+                        // Row 0 is "Go Back"
+                        match row.checked_sub(1).and_then(|i| self.explorer_list.get(i)).cloned() {
+                            Some(selected) if selected.1.is_file() => {
+                                let at = self.send(Request::PlayNext { entries: vec![selected] })?.index.unwrap_or(0);
+                                self.playlist_marks.clear();
+                                self.change_song(at)?;
+                            },
+                            _ => self.process_action(Action::Activate)?,
+                        }
+                    }
+                }
+            },
+            (MouseEventKind::Down(MouseButton::Left), Hit::PlayList(row)) => {
+                self.focus = Focus::PlayList;
+                if let Some(row) = row {
+                    self.send(Request::Select { index: row })?;
+                    if self.is_double_click(hit) {
+                        self.change_song(row)?;
+                    }
+                }
+            },
+            (MouseEventKind::Down(MouseButton::Left), Hit::Progress(ratio)) => {
+                let position = self.player.total_time.mul_f64(ratio).as_secs_f64();
+                self.send(Request::Seek { position })?;
+            },
+            (MouseEventKind::Down(MouseButton::Left), Hit::Volume(ratio))
+            | (MouseEventKind::Drag(MouseButton::Left), Hit::Volume(ratio)) => {
+                self.send(Request::Volume { percent: (ratio * 100.0).round() as u8 })?;
+            },
+            (MouseEventKind::ScrollUp, Hit::Explorer(_)) => self.explorer_up(),
+            (MouseEventKind::ScrollDown, Hit::Explorer(_)) => self.explorer_down(),
+            (MouseEventKind::ScrollUp, Hit::PlayList(_)) => self.playerlist_up()?,
+            (MouseEventKind::ScrollDown, Hit::PlayList(_)) => self.playerlist_down()?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // A second click on the same row shortly after the first
+    fn is_double_click(&mut self, hit: Hit) -> bool {
+        let double = matches!(self.last_click, Some((at, last)) if last == hit && at.elapsed() < DOUBLE_CLICK_TIME);
+        // A third click starts over rather than counting as another double
+        self.last_click = if double { None } else { Some((Instant::now(), hit)) };
+        double
+    }
+
+    // The help popup is modal: it takes every key until it is closed.
+    fn process_help_key(&mut self, key: KeyBinding, scroll: u16) {
+        let max = self.term_ui.help_max_scroll();
+        self.help_scroll = match key {
+            KeyBinding::Raw(RawKey::Esc) | KeyBinding::Raw(RawKey::Char('q')) | KeyBinding::Raw(RawKey::Char('h')) => None,
+            KeyBinding::Raw(RawKey::Up) | KeyBinding::Raw(RawKey::Char('k')) => Some(scroll.saturating_sub(1)),
+            KeyBinding::Raw(RawKey::Down) | KeyBinding::Raw(RawKey::Char('j')) => Some((scroll + 1).min(max)),
+            KeyBinding::Raw(RawKey::PageUp) => Some(scroll.saturating_sub(10)),
+            KeyBinding::Raw(RawKey::PageDown) => Some((scroll + 10).min(max)),
+            KeyBinding::Raw(RawKey::Home) => Some(0),
+            KeyBinding::Raw(RawKey::End) => Some(max),
+            _ => Some(scroll),
+        };
+    }
+
+    // Line editing for the `:` prompt, which also takes every key while open.
+    fn process_command_key(&mut self, key: KeyEvent) -> Result<(), Error> {
+        let line = &mut self.command_line;
+        match key.code {
+            KeyCode::Esc => line.close(),
+            KeyCode::Enter => {
+                let input = line.submit();
+                let result = match Command::parse(&input, &self.current_dir) {
+                    Ok(command) => self.run_command(command),
+                    Err(msg) => Err(msg),
+                };
+                self.command_line.message = match result {
+                    Ok(info) => info.map(Message::Info),
+                    Err(msg) => Some(Message::Error(msg)),
+                };
             },
-            KeyBinding::Raw(RawKey::Down) => {
-                self.explorer_down();       
+            KeyCode::Tab => line.complete(&self.current_dir, &self.config.stations),
+            KeyCode::Backspace if line.input.is_empty() => line.close(),
+            KeyCode::Backspace => line.backspace(),
+            KeyCode::Delete => line.delete(),
+            KeyCode::Left => line.left(),
+            KeyCode::Right => line.right(),
+            KeyCode::Home => line.home(),
+            KeyCode::End => line.end(),
+            KeyCode::Up => line.history_prev(),
+            KeyCode::Down => line.history_next(),
+            KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => line.clear(),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => line.close(),
+            KeyCode::Char(c) => line.insert(c),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // Runs a `:` command, returning what to tell the user.
+    fn run_command(&mut self, command: Command) -> Result<Option<String>, String> {
+        match command {
+            Command::Add(path) => {
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                let is_dir = path.is_dir();
+                let is_playlist = is_playlist_file(&path);
+                let added = self.request(Request::Enqueue { paths: vec![path] })?.added.unwrap_or(0);
+                match (is_dir, is_playlist) {
+                    (true, _) => Ok(None),
+                    (false, true) => Ok(Some(format!("Added {} tracks from {}", added, name))),
+                    (false, false) => Ok(Some(format!("Added {}", name))),
+                }
             },
-            KeyBinding::Raw(RawKey::Left) => {
-                self.player.dec_vol();
+            Command::Save(path) => {
+                playlist_file::write_m3u(&path, &self.player.play_list)
+                    .map_err(|err| format!("could not write {}: {}", path.display(), err))?;
+                Ok(Some(format!("Saved {} tracks to {}", self.player.play_list.len(), path.display())))
             },
-            KeyBinding::Raw(RawKey::Right) => {
-                self.player.inc_vol()
+            Command::Seek(amount) => {
+                self.request(Request::seek(amount))?;
+                Ok(None)
             },
-            KeyBinding::Raw(RawKey::Char(' ')) => {
+            Command::Volume(amount) => {
+                self.request(Request::volume(amount))?;
+                Ok(Some(format!("Volume {}%", self.player.volume)))
+            },
+            Command::Repeat(mode) => {
+                self.request(Request::Repeat { mode })?;
+                Ok(Some(format!("Repeat {}", self.player.repeat.name())))
+            },
+            Command::Shuffle(on) => {
+                self.request(Request::Shuffle { on })?;
+                Ok(Some(format!("Shuffle {}", if self.player.shuffle { "on" } else { "off" })))
+            },
+            Command::Sort(key) => {
+                self.request(Request::Sort { key })?;
+                self.sort_key = key.next();
+                self.playlist_marks.clear();
+                Ok(Some(format!("Sorted by {}", key.name())))
+            },
+            Command::Sleep(Some(after)) => {
+                let at = Instant::now().checked_add(after)
+                    .ok_or_else(|| format!("{} is too long to wait", format_time(after)))?;
+                self.sleep_at = Some(at);
+                Ok(Some(format!("Pausing in {}", format_time(after))))
+            },
+            Command::Sleep(None) => {
+                self.sleep_at = None;
+                Ok(Some("Sleep timer off".to_string()))
+            },
+            Command::Radio(name) => {
+                let station = self.config.stations.iter()
+                    .find(|s| s.name.eq_ignore_ascii_case(&name))
+                    .cloned()
+                    .ok_or_else(|| format!("no station called \"{}\" in the config", name))?;
+                let entry = (station.name.clone(), PathBuf::from(station.url));
+                // Queued after the current track, so the playlist carries on after it
+                let index = self.request(Request::PlayNext { entries: vec![entry] })?.index.unwrap_or(0);
+                self.request(Request::Play { index: Some(index) })?;
+                Ok(Some(format!("Playing {}", station.name)))
+            },
+            Command::Clear => self.run_action(Action::ClearPlaylist),
+            Command::Layout => self.run_action(Action::CycleLayout),
+            Command::Help => self.run_action(Action::Help),
+            Command::Quit => self.run_action(Action::Quit),
+        }
+    }
+
+    fn run_action(&mut self, action: Action) -> Result<Option<String>, String> {
+        self.process_action(action).map(|_| None).map_err(|err| err.to_string())
+    }
+
+    fn process_action(&mut self, action: Action) -> Result<(), Error>{
+        match action {
+            Action::MoveUp => {
+                match self.focus {
+                    Focus::Explorer => self.explorer_up(),
+                    Focus::PlayList => self.playerlist_up()?,
+                }
+            },
+            Action::MoveDown => {
+                match self.focus {
+                    Focus::Explorer => self.explorer_down(),
+                    Focus::PlayList => self.playerlist_down()?,
+                }
+            },
+            Action::ExtendUp => {
+                self.extend_selection(true)?;
+            },
+            Action::ExtendDown => {
+                self.extend_selection(false)?;
+            },
+            Action::SwitchFocus => {
+                if self.term_ui.is_visible(self.focus.toggle()) {
+                    self.focus = self.focus.toggle();
+                }
+            },
+            Action::ToggleMark => {
+                match self.focus {
+                    Focus::Explorer if self.explorer_index > 0 => self.explorer_marks.toggle(self.explorer_index-1),
+                    Focus::PlayList if !self.player.play_list.is_empty() => self.playlist_marks.toggle(self.player.playlist_index),
+                    _ => (),
+                }
+            },
+            Action::ClearMarks => {
+                match self.focus {
+                    Focus::Explorer => self.explorer_marks.clear(),
+                    Focus::PlayList => self.playlist_marks.clear(),
+                }
+            },
+            Action::VolumeDown => {
+                self.send(Request::VolumeBy { percent: -1 })?;
+            },
+            Action::VolumeUp => {
+                self.send(Request::VolumeBy { percent: 1 })?;
+            },
+            Action::TogglePause => {
                 if self.player.is_playing_or_paused() {
-                    if self.player.is_playing {
-                        self.player.pause();
-                    }
-                    else{
-                        self.player.play();
+                    self.send(Request::Toggle)?;
+                }
+            },
+            Action::PlaySelected => {
+                if self.focus == Focus::Explorer && !self.explorer_marks.is_empty() {
+                    let songs = self.explorer_files();
+                    self.explorer_marks.clear();
+                    if !songs.is_empty() {
+                        let at = self.send(Request::PlayNext { entries: songs })?.index.unwrap_or(0);
+                        self.playlist_marks.clear();
+                        self.change_song(at)?;
                     }
                 }
+                else if let Some(&index) = self.playlist_targets().iter().next() {
+                    self.change_song(index)?;
+                }
+            },
+            Action::ClearPlaylist => {
+                self.send(Request::Clear)?;
+                self.playlist_marks.clear();
+            },
+            Action::Remove => {
+                let indices = self.playlist_targets();
+                self.send(Request::Remove { indices })?;
+                self.playlist_marks.clear();
+            },
+            Action::Cut => {
+                let indices = self.playlist_targets();
+                self.clipboard = self.send(Request::Remove { indices })?.entries.unwrap_or_default();
+                self.playlist_marks.clear();
+            },
+            Action::Paste => {
+                let at = if self.player.play_list.is_empty() {0} else {self.player.playlist_index+1};
+                self.send(Request::Insert { at, entries: self.clipboard.clone() })?;
+                self.playlist_marks.clear();
+            },
+            Action::Dedup => {
+                self.send(Request::Dedup)?;
+                self.playlist_marks.clear();
+            },
+            Action::Sort => {
+                self.send(Request::Sort { key: self.sort_key })?;
+                self.sort_key = self.sort_key.next();
+                self.playlist_marks.clear();
+            },
+            Action::PlayNext => {
+                let songs = self.explorer_files();
+                self.explorer_marks.clear();
+                if !songs.is_empty() {
+                    self.send(Request::PlayNext { entries: songs })?;
+                    self.playlist_marks.clear();
+                }
             },
-            KeyBinding::Raw(RawKey::Char('s')) => {
-                if self.player.play_list.len() > 0 {
-                    let selected_song = &self.player.play_list[self.player.playlist_index];
-                    self.player.playing_song = Some(selected_song.0.clone()); 
-                    self.change_song(selected_song.1.clone())?;
+            Action::AddRecursive => {
+                let paths: Vec<PathBuf> = self.explorer_targets().into_iter().map(|(_, path)| path).collect();
+                self.explorer_marks.clear();
+                if !paths.is_empty() {
+                    self.send(Request::Enqueue { paths })?;
                 }
             },
-            KeyBinding::Raw(RawKey::Char('c')) => {
-                self.player.playing_song = None;
-                self.player.play_list.clear();
+            Action::MoveEntryUp => {
+                self.send(Request::MoveUp { index: self.player.playlist_index })?;
+                self.playlist_marks.clear();
+            },
+            Action::MoveEntryDown => {
+                self.send(Request::MoveDown { index: self.player.playlist_index })?;
+                self.playlist_marks.clear();
+            },
+            Action::CycleRepeat => {
+                self.send(Request::Repeat { mode: None })?;
+            },
+            Action::ToggleShuffle => {
+                self.send(Request::Shuffle { on: None })?;
             },
-            KeyBinding::Raw(RawKey::Char('n')) => {
-                self.player.next();
+            Action::Next => {
+                self.send(Request::Next)?;
             },
-            KeyBinding::Raw(RawKey::Char('q')) => {
+            Action::Quit => {
                 self.quit = true;
             },
-            KeyBinding::Raw(RawKey::Enter) => {
-                if self.explorer_index == 0 {            
-                    if self.current_dir.pop() {
-                        self.change_directory(self.current_dir.clone())?;
+            Action::Activate if self.focus == Focus::PlayList => {
+                if !self.player.play_list.is_empty() {
+                    self.change_song(self.player.playlist_index)?;
+                }
+            },
+            Action::Activate => {
+                if !self.explorer_marks.is_empty() {
+                    let songs = self.explorer_files();
+                    self.send(Request::Append { entries: songs })?;
+                    self.explorer_marks.clear();
+                }
+                else if self.explorer_index == 0 {            
+                    if let Some(parent) = self.current_dir.parent() {
+                        self.change_directory(parent.to_path_buf());
                     }
                 }
                 else {
                     let selected = self.explorer_list[self.explorer_index-1].clone();
                     if selected.1.is_dir() {
-                        self.change_directory(selected.1)?;
+                        self.change_directory(selected.1);
                     }
                     else{
-                        self.append_to_playlist(selected)?;
+                        self.send(Request::Append { entries: vec![selected] })?;
                     }               
                 }
             },
-            KeyBinding::Shift(RawKey::Up) => {
-                self.playerlist_up();
-
+            Action::Help => {
+                self.help_scroll = Some(0);
+            },
+            Action::Command => {
+                self.command_line.open();
             },
-            KeyBinding::Shift(RawKey::Down) => {
-                self.playerlist_down();
+            Action::NextBookmark => {
+                if !self.bookmarks.is_empty() {
+                    let next = match self.bookmarks.iter().position(|dir| *dir == self.current_dir) {
+                        Some(i) => (i + 1) % self.bookmarks.len(),
+                        None => 0,
+                    };
+                    self.change_directory(self.bookmarks[next].clone());
+                }
+            },
+            Action::CycleLayout => {
+                self.term_ui.cycle_layout();
+                if !self.term_ui.is_visible(self.focus) {
+                    self.focus = self.focus.toggle();
+                }
+            },
+            Action::GoTop => {
+                match self.focus {
+                    Focus::Explorer => self.explorer_index = 0,
+                    Focus::PlayList => { self.send(Request::Select { index: 0 })?; },
+                }
+            },
+            Action::GoBottom => {
+                match self.focus {
+                    Focus::Explorer => self.explorer_index = self.explorer_list.len(),
+                    Focus::PlayList => {
+                        let index = self.player.play_list.len().saturating_sub(1);
+                        self.send(Request::Select { index })?;
+                    },
+                }
             },
-            _ => ()
         }
         Ok(())
     }
@@ -214,6 +830,8 @@ impl MusicPlayer {
             KeyCode::Left => RawKey::Left,
             KeyCode::Right => RawKey::Right,
             KeyCode::F(i) => return KeyBinding::F(i),
+            // Lock, media and modifier keys, which only some terminals report
+            _ => return KeyBinding::Unsupported,
         };
         match modifiers {
             KeyModifiers::CONTROL => KeyBinding::Ctrl(inner),
@@ -241,53 +859,176 @@ impl MusicPlayer {
         }
     }
 
-    fn playerlist_up(&mut self) {
-        if self.player.playlist_index == 0 {
-            self.player.playlist_index = self.player.play_list.len()-1;
+    fn extend_selection(&mut self, up: bool) -> Result<(), Error> {
+        match self.focus {
+            Focus::Explorer => {
+                let from = self.explorer_index;
+                if up { self.explorer_up() } else { self.explorer_down() }
+                let to = self.explorer_index;
+                // Index 0 is the "Go Back" row, which can't be marked
+                if from > 0 && to > 0 {
+                    self.explorer_marks.extend(from-1, to-1);
+                }
+            },
+            Focus::PlayList => {
+                if self.player.play_list.is_empty() {return Ok(());}
+                let from = self.player.playlist_index;
+                if up { self.playerlist_up()? } else { self.playerlist_down()? }
+                self.playlist_marks.extend(from, self.player.playlist_index);
+            },
+        }
+        Ok(())
+    }
+
+    /// The marked explorer entries, or the entry under the cursor when
+    /// nothing is marked.
+    fn explorer_targets(&self) -> Vec<(String, PathBuf)> {
+        if self.explorer_marks.is_empty() {
+            match self.explorer_index {
+                0 => vec![],
+                i => self.explorer_list.get(i-1).cloned().into_iter().collect(),
+            }
         }
         else {
-            self.player.playlist_index -=1;
+            self.explorer_marks.marked.iter()
+                .filter_map(|&i| self.explorer_list.get(i).cloned())
+                .collect()
         }
     }
 
-    fn playerlist_down(&mut self) {
-        if self.player.playlist_index == self.player.play_list.len()-1 {
-            self.player.playlist_index = 0;
+    fn explorer_files(&self) -> Vec<(String, PathBuf)> {
+        self.explorer_targets().into_iter().filter(|(_, path)| path.is_file()).collect()
+    }
+
+    fn playlist_targets(&self) -> BTreeSet<usize> {
+        if self.playlist_marks.is_empty() && !self.player.play_list.is_empty() {
+            BTreeSet::from([self.player.playlist_index])
         }
         else {
-            self.player.playlist_index +=1;
+            self.playlist_marks.marked.clone()
         }
     }
 
-    fn append_to_playlist(&mut self, selected_song: (String, PathBuf)) -> Result<(), Error> {
-        self.player.play_list.push(selected_song);
+    fn playerlist_up(&mut self) -> Result<(), Error> {
+        if self.player.play_list.is_empty() {return Ok(());}
+        let index = match self.player.playlist_index {
+            0 => self.player.play_list.len()-1,
+            i => i-1,
+        };
+        self.send(Request::Select { index })?;
         Ok(())
     }
 
-    fn change_directory(&mut self, dir_path: PathBuf) -> Result<(), Error> {
-        self.current_dir = dir_path;
-        let (path_list, name_list) =  self.read_dir_and_music(self.current_dir.as_path())?;
-        self.explorer_list = name_list.into_iter().zip(path_list.into_iter()).collect();
-        self.explorer_index = 0;
+    fn playerlist_down(&mut self) -> Result<(), Error> {
+        if self.player.play_list.is_empty() {return Ok(());}
+        let index = match self.player.playlist_index {
+            i if i == self.player.play_list.len()-1 => 0,
+            i => i+1,
+        };
+        self.send(Request::Select { index })?;
         Ok(())
     }
 
-    fn change_song(&mut self, song_path: PathBuf) -> Result<(), Error> {
-        match self.player.play_song(song_path.as_path()){
-            true =>(),
-            false => panic!("failed to play")
+    // Stays in the current directory, saying why, when the new one can't
+    // be read.
+    fn change_directory(&mut self, dir_path: PathBuf) {
+        match self.read_dir_and_music(dir_path.as_path()) {
+            Ok((path_list, name_list)) => {
+                self.current_dir = dir_path;
+                self.explorer_list = name_list.into_iter().zip(path_list.into_iter()).collect();
+                self.explorer_index = 0;
+                self.explorer_marks.clear();
+            },
+            Err(err) => {
+                let msg = format!("Could not open {}: {}", dir_path.display(), err);
+                self.command_line.message = Some(Message::Error(msg));
+            },
+        }
+    }
+
+    fn change_song(&mut self, index: usize) -> Result<(), Error> {
+        if let Err(msg) = self.send(Request::Play { index: Some(index) })?.into_result() {
+            self.command_line.message = Some(Message::Error(msg));
         }
         Ok(())
     }
     pub fn destruct(mut self) -> Result<(), Error>{
-        disable_raw_mode()?;
-        execute!(
-            self.terminal.backend_mut(),
-            LeaveAlternateScreen,
-            DisableMouseCapture
-        )?;
-        self.terminal.show_cursor()?;
-        Ok(())
+        self.mpris.take();
+        stop_backend(&self.backend, self.player_thread.take(), self.socket_path.take());
+        restore_terminal(self.terminal.backend_mut())
     }
     
 }
+
+// How often the position is drawn again on a timer, if at all: every
+// `refresh` while playing, less often while the terminal is in the
+// background, and never while paused or stopped.
+fn redraw_interval(playing: bool, focused: bool, refresh: Duration) -> Option<Duration> {
+    match (playing, focused) {
+        (false, _) => None,
+        (true, true) => Some(refresh),
+        (true, false) => Some(refresh.max(UNFOCUSED_REFRESH)),
+    }
+}
+
+// Queues what the command line asked for, and returns the player's state
+// after that.
+fn queue_launch(launch: &Launch, backend: &mut Backend) -> Result<PlayerState, Error> {
+    let mut player = backend.status(None)?;
+    for request in launch.requests(&player) {
+        backend.send(request)?;
+    }
+    player.update(backend.status(Some(player.playlist_version))?);
+    Ok(player)
+}
+
+// Switches the terminal over to the TUI, putting it back as it was if that
+// fails part way, so the shell isn't left in raw mode.
+fn open_terminal(config: &Config) -> Result<(Terminal<CrosstermBackend<Stdout>>, UI), Error> {
+    enable_raw_mode()?;
+    let opened = (|| {
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen, EnableMouseCapture, EnableFocusChange)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        terminal.hide_cursor()?;
+        let term_ui = UI::new(config, &terminal)?;
+        Ok((terminal, term_ui))
+    })();
+    if opened.is_err() {
+        // The error that got us here is the one worth reporting
+        let _ = restore_terminal(&mut io::stdout());
+    }
+    opened
+}
+
+fn restore_terminal(out: &mut impl Write) -> Result<(), Error> {
+    disable_raw_mode()?;
+    execute!(out, LeaveAlternateScreen, DisableMouseCapture, DisableFocusChange, Show)
+}
+
+// Shuts down the player thread when the player runs in this process, and
+// takes away the control socket.
+fn stop_backend(backend: &Backend, player_thread: Option<thread::JoinHandle<PlayerState>>, socket_path: Option<PathBuf>) {
+    if let (Backend::Local(handle), Some(thread)) = (backend, player_thread) {
+        handle.call(Request::Shutdown);
+        let _ = thread.join();
+    }
+    if let Some(path) = socket_path {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_redrawn_on_a_timer_unless_playing() {
+        let refresh = Duration::from_millis(100);
+        assert_eq!(redraw_interval(true, true, refresh), Some(refresh));
+        assert_eq!(redraw_interval(true, false, refresh), Some(UNFOCUSED_REFRESH));
+        assert_eq!(redraw_interval(true, false, Duration::from_secs(5)), Some(Duration::from_secs(5)));
+        assert_eq!(redraw_interval(false, true, refresh), None);
+        assert_eq!(redraw_interval(false, false, refresh), None);
+    }
+}
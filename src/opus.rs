@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::path::Path;
+use std::time::Duration;
+
+use ogg::PacketReader;
+use opus_decoder::OpusMultistreamDecoder;
+use rodio::Source;
+
+use crate::audio;
+
+// Opus is always decoded at 48 kHz, and its granule positions count that
+// clock whatever rate the file was made from
+const RATE: u32 = 48_000;
+// The most a packet can hold: 120 ms per channel
+const MAX_PACKET_SAMPLES: usize = RATE as usize * 120 / 1000;
+
+// What decoding needs from the identification header.
+#[derive(Debug, PartialEq)]
+struct Head {
+    channels: u8,
+    // Samples per channel at the start that only prime the decoder
+    pre_skip: u64,
+    // Gain to apply, in 1/256 dB
+    gain: i16,
+    streams: u8,
+    coupled: u8,
+    mapping: Vec<u8>,
+}
+
+impl Head {
+    fn parse(packet: &[u8]) -> Option<Head> {
+        if !packet.starts_with(b"OpusHead") || packet.len() < 19 {
+            return None;
+        }
+        let channels = packet[9];
+        // Family 0 is mono or stereo in one stream, and has no table
+        let (streams, coupled, mapping) = match packet[18] {
+            0 if (1..=2).contains(&channels) => (1, channels - 1, (0..channels).collect()),
+            0 => return None,
+            _ => (*packet.get(19)?, *packet.get(20)?, packet.get(21..21 + usize::from(channels))?.to_vec()),
+        };
+        Some(Head {
+            channels,
+            pre_skip: u64::from(u16::from_le_bytes([packet[10], packet[11]])),
+            gain: i16::from_le_bytes([packet[16], packet[17]]),
+            streams,
+            coupled,
+            mapping,
+        })
+    }
+}
+
+/// The comments of the Ogg Opus file at `path`, which use the same field
+/// names as Vorbis ones.
+pub fn comments(path: &Path) -> Option<Vec<(String, String)>> {
+    let mut packets = PacketReader::new(BufReader::new(File::open(path).ok()?));
+    packets.read_packet().ok()??;
+    let tags = packets.read_packet().ok()??.data;
+    let mut fields = tags.strip_prefix(b"OpusTags")?;
+    // The encoder's name, then how many comments there are
+    take_field(&mut fields)?;
+    let count = u32::from_le_bytes(fields.get(..4)?.try_into().ok()?);
+    fields = &fields[4..];
+    let mut comments = vec![];
+    for _ in 0..count {
+        let comment = String::from_utf8_lossy(take_field(&mut fields)?).into_owned();
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.push((key.to_string(), value.to_string()));
+        }
+    }
+    Some(comments)
+}
+
+// Takes a field that starts with its length off the front of `data`.
+fn take_field<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let field = data.get(4..4 + len)?;
+    *data = &data[4 + len..];
+    Some(field)
+}
+
+/// The length of the Ogg Opus file at `path`, from the position of its last
+/// page.
+pub fn duration(path: &Path) -> Option<Duration> {
+    let mut packets = PacketReader::new(BufReader::new(File::open(path).ok()?));
+    let head = Head::parse(&packets.read_packet().ok()??.data)?;
+    let end = audio::last_granule(&mut File::open(path).ok()?)?;
+    Some(Duration::from_secs_f64(end.saturating_sub(head.pre_skip) as f64 / f64::from(RATE)))
+}
+
+/// The decoded audio of an Ogg Opus file, for the sink. Only the first
+/// stream of a chained file is played.
+pub struct OpusSource {
+    packets: PacketReader<BufReader<File>>,
+    decoder: OpusMultistreamDecoder,
+    serial: u32,
+    channels: u16,
+    gain: f32,
+    // Samples per channel decoded so far, counting the pre-skip, which is
+    // how granule positions count them
+    decoded: u64,
+    pre_skip: u64,
+    samples: Vec<i16>,
+    pos: usize,
+}
+
+impl OpusSource {
+    pub fn open(path: &Path) -> io::Result<OpusSource> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+        let mut packets = PacketReader::new(BufReader::new(File::open(path)?));
+        let first = packets.read_packet().map_err(|err| invalid(&err.to_string()))?
+            .ok_or_else(|| invalid("an empty file"))?;
+        let head = Head::parse(&first.data).ok_or_else(|| invalid("not an Opus stream"))?;
+        // The comments come next, and play no part in decoding
+        packets.read_packet().map_err(|err| invalid(&err.to_string()))?;
+        let decoder = OpusMultistreamDecoder::new(RATE, usize::from(head.channels), usize::from(head.streams),
+            usize::from(head.coupled), &head.mapping)
+            .map_err(|err| Error::new(ErrorKind::Unsupported, err.to_string()))?;
+        let mut source = OpusSource {
+            packets,
+            decoder,
+            serial: first.stream_serial(),
+            channels: u16::from(head.channels),
+            gain: 10f32.powf(f32::from(head.gain) / 256.0 / 20.0),
+            decoded: 0,
+            pre_skip: head.pre_skip,
+            samples: vec![],
+            pos: 0,
+        };
+        source.advance();
+        if source.samples.is_empty() {
+            return Err(invalid("no audio could be decoded"));
+        }
+        Ok(source)
+    }
+
+    // Decodes the next packet that has samples to play, leaving no samples
+    // at the end. The pre-skip is dropped from the start, and the last page
+    // says where the stream ends within its last packet.
+    fn advance(&mut self) {
+        self.pos = 0;
+        self.samples.clear();
+        let channels = usize::from(self.channels);
+        while self.samples.is_empty() {
+            let Ok(Some(packet)) = self.packets.read_packet() else { return };
+            if packet.stream_serial() != self.serial {
+                continue;
+            }
+            let mut pcm = vec![0; MAX_PACKET_SAMPLES * channels];
+            // A damaged packet is skipped, as minimp3's junk is
+            let Ok(len) = self.decoder.decode(&packet.data, &mut pcm, false) else { continue };
+            let start = self.decoded;
+            self.decoded += len as u64;
+            let mut end = self.decoded;
+            if packet.last_in_stream() {
+                end = end.min(packet.absgp_page());
+            }
+            let skip = self.pre_skip.saturating_sub(start).min(len as u64) as usize;
+            let keep = end.saturating_sub(start).min(len as u64) as usize;
+            if skip < keep {
+                self.samples.extend(pcm[skip * channels..keep * channels].iter()
+                    .map(|&sample| (f32::from(sample) * self.gain).clamp(-32768.0, 32767.0) as i16));
+            }
+            if packet.last_in_stream() {
+                return;
+            }
+        }
+    }
+}
+
+impl Iterator for OpusSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = *self.samples.get(self.pos)?;
+        self.pos += 1;
+        if self.pos == self.samples.len() {
+            self.advance();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for OpusSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.samples.len() - self.pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    use ogg::{PacketWriteEndInfo, PacketWriter};
+
+    use super::*;
+    use crate::scan::is_music_file;
+    use crate::tags::Tags;
+
+    // An Ogg Opus file of five packets of mono silence, each a 20 ms CELT
+    // frame with no data, after a pre-skip of 312 samples. The last page
+    // ends the stream 500 samples into its packet.
+    fn silent_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("tasco-{}-{}.opus", process::id(), name));
+        let mut writer = PacketWriter::new(File::create(&path).unwrap());
+        let mut write = |packet: &[u8], end, granule| {
+            writer.write_packet(packet.to_vec().into_boxed_slice(), 7, end, granule).unwrap();
+        };
+        write(b"OpusHead\x01\x01\x38\x01\x80\xbb\0\0\0\0\0", PacketWriteEndInfo::EndPage, 0);
+        let mut tags = b"OpusTags\x05\0\0\0Tasco\x02\0\0\0".to_vec();
+        for comment in ["title=Quiet", "ARTIST=Nobody"] {
+            tags.extend((comment.len() as u32).to_le_bytes());
+            tags.extend(comment.as_bytes());
+        }
+        write(&tags, PacketWriteEndInfo::EndPage, 0);
+        for _ in 0..4 {
+            write(&[0xf8], PacketWriteEndInfo::NormalPacket, 0);
+        }
+        write(&[0xf8], PacketWriteEndInfo::EndStream, 312 + 4 * 960 + 500);
+        path
+    }
+
+    #[test]
+    fn silence_is_trimmed_to_the_granule_positions() {
+        let path = silent_file("trim");
+        assert!(is_music_file(&path));
+        assert_eq!(duration(&path), Some(Duration::from_secs_f64(4340.0 / 48_000.0)));
+        let source = OpusSource::open(&path).unwrap();
+        assert_eq!((source.channels(), source.sample_rate()), (1, 48_000));
+        assert_eq!(source.count(), 4340);
+        let tags = Tags::read(&path);
+        assert_eq!((tags.title.as_deref(), tags.artist.as_deref()), (Some("Quiet"), Some("Nobody")));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn identification_headers() {
+        let mut stereo = b"OpusHead\x01\x02\x38\x01\x80\xbb\0\0\x00\x01\x00".to_vec();
+        assert_eq!(Head::parse(&stereo), Some(Head {
+            channels: 2, pre_skip: 312, gain: 256, streams: 1, coupled: 1, mapping: vec![0, 1],
+        }));
+        // Family 0 can't hold more than two channels
+        stereo[9] = 3;
+        assert_eq!(Head::parse(&stereo), None);
+        let surround = b"OpusHead\x01\x03\x38\x01\x80\xbb\0\0\0\0\x01\x02\x01\x00\x02\x01";
+        assert_eq!(Head::parse(surround), Some(Head {
+            channels: 3, pre_skip: 312, gain: 0, streams: 2, coupled: 1, mapping: vec![0, 2, 1],
+        }));
+        assert_eq!(Head::parse(&surround[..22]), None);
+        assert_eq!(Head::parse(b"\x01vorbis"), None);
+    }
+}
@@ -2,11 +2,26 @@ use std::{
     fs::File,
     path::{PathBuf, Path}, 
     time::{Duration, Instant}, 
-    io::BufReader
+    io::BufReader,
+    ops::Range,
+    collections::{HashMap, BTreeSet},
 };
 
-use flume::Sender;
 use rodio::{Decoder, Devices, OutputStream, OutputStreamHandle, Sink, Source};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::audio::{probed_duration, vorbis_duration, AudioFormat, ProbedSource};
+use crate::mpegts::{self, TsSource};
+use crate::opus::{self, OpusSource};
+use crate::radio::{self, is_stream, Title};
+use crate::tags::Tags;
+
+// Going back within this much of the start of a track goes to the previous
+// track instead of restarting this one.
+const PREV_RESTART_AFTER: Duration = Duration::from_secs(3);
+
 pub enum PlayStatus {
     Waiting,
     Playing(Instant, Duration),
@@ -14,101 +29,190 @@ pub enum PlayStatus {
     Complete,
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RepeatMode {
+    Off,
+    All,
+    One,
+}
+
+impl RepeatMode {
+    pub fn next(self) -> RepeatMode {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "off",
+            RepeatMode::All => "all",
+            RepeatMode::One => "one",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RepeatMode> {
+        [RepeatMode::Off, RepeatMode::All, RepeatMode::One].into_iter().find(|m| m.name() == name)
+    }
+}
+
 pub struct PlayController {
     pub volume: f32,
     pub current_time: Duration,
     pub total_time: Duration,
     pub status: PlayStatus,
     pub playing_song: Option<String>,
+    pub playing_path: Option<PathBuf>,
     pub play_list: Vec<(String, PathBuf)>,
+    /// Bumped whenever `play_list` changes, so clients only need to fetch
+    /// the list again when it differs from the copy they have.
+    pub playlist_version: u64,
+    /// A number for each entry of `play_list` that stays with it while
+    /// entries around it are added, removed or moved.
+    pub entry_ids: Vec<u64>,
+    next_entry_id: u64,
     pub playlist_index: usize,
+    pub playing_index: Option<usize>,
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
     pub is_playing: bool,
-    stream: OutputStream,
-    stream_handle: OutputStreamHandle,
+    /// What the internet radio station says is on, while one plays.
+    pub stream_title: Option<String>,
+    // Set while an internet radio stream plays
+    radio: Option<Title>,
+    // None when there is no sound device to play on, as in tests
+    output: Option<(OutputStream, OutputStreamHandle)>,
     sink: Sink,
 }
 
 impl PlayController {
+    /// A player on the default audio device. Without one it still keeps
+    /// the playlist, but every track fails to play.
     pub fn new() -> PlayController {
-        let (stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink = Sink::try_new(&stream_handle).unwrap();
-        Self {           
+        Self::with_output(OutputStream::try_default().ok())
+    }
+
+    fn with_output(output: Option<(OutputStream, OutputStreamHandle)>) -> PlayController {
+        let sink = match &output {
+            Some((_, handle)) => Sink::try_new(handle).unwrap(),
+            None => Sink::new_idle().0,
+        };
+        Self {
             volume: 1.0,
             current_time: Duration::from_secs(0),
             total_time: Duration::from_secs(0),
             status: PlayStatus::Waiting,
             playing_song: None,
+            playing_path: None,
             play_list: vec![],
+            playlist_version: 0,
+            entry_ids: vec![],
+            next_entry_id: 0,
             playlist_index: 0,
+            playing_index: None,
+            repeat: RepeatMode::All,
+            shuffle: false,
             is_playing: false,
-            stream,
-            stream_handle,
+            stream_title: None,
+            radio: None,
+            output,
             sink,
-
         }
     }
 
-    pub fn play_song(&mut self, song_path: &Path) -> bool {
-        let duration: Duration;
-        if song_path.extension().unwrap() == "mp3" {
-            let dur = mp3_duration::from_path(song_path);
-            match dur {
-                Ok(dur) => {
-                    duration = dur;
-                }
-                Err(err) => {
-                    duration = err.at_duration;
-                    if duration.is_zero() {
-                        return false;
-                    }
-                }
-            }
-        } else {
-            if let Ok(f) = File::open(song_path) {
-                let dec = Decoder::new(f);
-                if let Ok(dec) = dec {
-                    if let Some(dur) = dec.total_duration() {
-                        duration = dur;
-                    } else {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            } else {
-                return false;
-            }
-        }
-        match File::open(song_path) {
-            Ok(f) => {
-                let buf_reader = BufReader::new(f);
-                self.sink = self.stream_handle.play_once(buf_reader).unwrap();
-                self.sink.set_volume(self.volume);
-                self.total_time = duration;
-                self.current_time = Duration::from_secs(0);
-                self.status = PlayStatus::Waiting;
-                self.is_playing = false;
-                self.play();
-                return true;
-                
-            }
-            Err(_) => false
+    /// Starts playing the song `start` into the track.
+    pub fn play_song_at(&mut self, song_path: &Path, start: Duration) -> bool {
+        if is_stream(song_path) {
+            return self.play_stream(song_path);
         }
+        let duration = match song_duration(song_path) {
+            Some(duration) => duration,
+            None => return false,
+        };
+        // rodio has no demuxer for transport streams, MP4 or ADTS, nor an
+        // Opus decoder, so their audio is pulled out and decoded here
+        let source: Box<dyn Source<Item = i16> + Send> = match AudioFormat::detect(song_path) {
+            Some(AudioFormat::MpegTs) => match TsSource::open(song_path) {
+                Ok(source) => Box::new(source),
+                Err(_) => return false,
+            },
+            Some(AudioFormat::Mp4 | AudioFormat::Aac) => match ProbedSource::open(song_path) {
+                Ok(source) => Box::new(source),
+                Err(_) => return false,
+            },
+            Some(AudioFormat::Opus) => match OpusSource::open(song_path) {
+                Ok(source) => Box::new(source),
+                Err(_) => return false,
+            },
+            _ => match File::open(song_path).map(|f| Decoder::new(BufReader::new(f))) {
+                Ok(Ok(source)) => Box::new(source),
+                _ => return false,
+            },
+        };
+        let sink = match self.new_sink() {
+            Some(sink) => sink,
+            None => return false,
+        };
+        let start = start.min(duration);
+        sink.append(source.skip_duration(start));
+        self.radio = None;
+        self.start(sink, song_path, duration, start);
+        true
+    }
+
+    // Internet radio has no length and can't seek, so it plays from now
+    // until the station goes away. Connecting happens alongside, so the
+    // service isn't kept waiting on the network.
+    fn play_stream(&mut self, url: &Path) -> bool {
+        let Some(sink) = self.new_sink() else { return false };
+        let (source, title) = radio::open(&url.to_string_lossy());
+        sink.append(source);
+        self.radio = Some(title);
+        self.start(sink, url, Duration::ZERO, Duration::ZERO);
+        true
     }
 
-    pub fn inc_vol(&mut self) {
-        if self.volume < 1.0{
-            self.volume += 0.01;
-            self.sink.set_volume(self.volume);
-            
+    fn new_sink(&self) -> Option<Sink> {
+        Sink::try_new(&self.output.as_ref()?.1).ok()
+    }
+
+    fn start(&mut self, sink: Sink, path: &Path, duration: Duration, start: Duration) {
+        self.sink = sink;
+        self.sink.set_volume(self.volume);
+        self.playing_path = Some(path.to_path_buf());
+        self.stream_title = None;
+        self.total_time = duration;
+        self.current_time = start;
+        self.status = PlayStatus::Waiting;
+        self.is_playing = false;
+        self.play();
+        if let PlayStatus::Playing(start_ins, _) = &mut self.status {
+            *start_ins = start_ins.checked_sub(start).unwrap_or(*start_ins);
         }
     }
 
-    pub fn dec_vol(&mut self) {
-        if self.volume > 0.0 {
-            self.volume -= 0.01;
-            self.sink.set_volume(self.volume);
+    /// Jumps to `position` in the current track, staying paused if it was.
+    pub fn seek(&mut self, position: Duration) -> bool {
+        let path = match &self.playing_path {
+            Some(path) if self.is_playing_or_paused() && self.radio.is_none() => path.clone(),
+            _ => return false,
+        };
+        let paused = !self.is_playing;
+        if !self.play_song_at(path.as_path(), position) {
+            return false;
+        }
+        if paused {
+            self.pause();
         }
+        true
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+        self.sink.set_volume(self.volume);
     }
 
     pub fn is_playing_or_paused(&self) -> bool {
@@ -147,16 +251,194 @@ impl PlayController {
     }
 
     pub fn next(&mut self) {
-        if self.play_list.len() == 0 {return;}
-        if self.playlist_index == self.play_list.len()-1 {
-            self.playlist_index = 0;
+        if let Some(next) = self.next_index(false) {
+            self.play_index(next);
+        }
+    }
+
+    /// Goes back to the start of the track, or to the track before it when
+    /// already near the start.
+    pub fn prev(&mut self) {
+        let Some(current) = self.playing_index else { return };
+        if self.current_time > PREV_RESTART_AFTER && self.radio.is_none() {
+            self.seek(Duration::from_secs(0));
+            return;
+        }
+        let len = self.play_list.len();
+        let prev = match current {
+            0 if self.repeat == RepeatMode::Off => return,
+            0 => len.saturating_sub(1),
+            i => i - 1,
+        };
+        self.play_index(prev);
+    }
+
+    pub fn stop(&mut self) {
+        self.sink.stop();
+        self.status = PlayStatus::Waiting;
+        self.is_playing = false;
+        self.current_time = Duration::from_secs(0);
+        self.playing_index = None;
+        self.playing_song = None;
+        self.playing_path = None;
+        self.radio = None;
+        self.stream_title = None;
+    }
+
+    // Called when the current track runs out, so repeat-one and repeat-off
+    // apply here but not to a manual skip.
+    fn advance(&mut self) {
+        match self.next_index(true) {
+            Some(next) => { self.play_index(next); },
+            None => self.is_playing = false,
+        }
+    }
+
+    fn next_index(&self, finished: bool) -> Option<usize> {
+        let len = self.play_list.len();
+        if len == 0 {return None;}
+        let current = self.playing_index.unwrap_or(self.playlist_index);
+        if finished && self.repeat == RepeatMode::One && self.playing_index.is_some() {
+            return Some(current);
         }
-        else{
-            self.playlist_index += 1;
+        if self.shuffle && len > 1 {
+            let pick = rand::thread_rng().gen_range(0..len-1);
+            return Some(if pick >= current { pick + 1 } else { pick });
+        }
+        if current + 1 < len {
+            Some(current + 1)
+        }
+        else if finished && self.repeat == RepeatMode::Off {
+            None
+        }
+        else {
+            Some(0)
         }
-        let (mut name, mut path)  = self.play_list[self.playlist_index].clone();
-        self.playing_song = Some(name); 
-        self.play_song(path.as_path());
+    }
+
+    pub fn play_index(&mut self, index: usize) -> bool {
+        self.play_index_at(index, Duration::from_secs(0))
+    }
+
+    pub fn play_index_at(&mut self, index: usize, start: Duration) -> bool {
+        if index >= self.play_list.len() {return false;}
+        let (name, path) = self.play_list[index].clone();
+        self.playlist_index = index;
+        self.playing_index = Some(index);
+        self.playing_song = Some(name);
+        self.play_song_at(path.as_path(), start)
+    }
+
+    pub fn clear(&mut self) {
+        self.playlist_version += 1;
+        self.play_list.clear();
+        self.entry_ids.clear();
+        self.playlist_index = 0;
+        if self.playing_index.is_some() {
+            self.stop();
+        }
+    }
+
+    pub fn remove_indices(&mut self, indices: &BTreeSet<usize>) -> Vec<(String, PathBuf)> {
+        let removed = indices.iter()
+            .filter_map(|&i| self.play_list.get(i).cloned())
+            .collect();
+        self.reorder((0..self.play_list.len()).filter(|i| !indices.contains(i)).collect());
+        removed
+    }
+
+    pub fn insert(&mut self, at: usize, entries: Vec<(String, PathBuf)>) {
+        let was_empty = self.play_list.is_empty();
+        let at = at.min(self.play_list.len());
+        let count = entries.len();
+        self.playlist_version += 1;
+        self.play_list.splice(at..at, entries);
+        let first_id = self.next_entry_id;
+        self.next_entry_id += count as u64;
+        self.entry_ids.splice(at..at, first_id..self.next_entry_id);
+        if let Some(playing) = self.playing_index.as_mut() {
+            if *playing >= at { *playing += count; }
+        }
+        if !was_empty && self.playlist_index >= at {
+            self.playlist_index += count;
+        }
+    }
+
+    pub fn append(&mut self, entries: Vec<(String, PathBuf)>) {
+        self.insert(self.play_list.len(), entries);
+    }
+
+    /// Inserts the entries right after the current track, or after the
+    /// cursor when nothing is playing, and returns where they start.
+    pub fn play_next(&mut self, entries: Vec<(String, PathBuf)>) -> usize {
+        let at = match self.playing_index {
+            Some(playing) => playing + 1,
+            None if self.play_list.is_empty() => 0,
+            None => self.playlist_index + 1,
+        };
+        self.insert(at, entries);
+        at
+    }
+
+    pub fn move_up(&mut self, range: Range<usize>) {
+        if range.start == 0 || range.end > self.play_list.len() || range.is_empty() {return;}
+        let mut order: Vec<usize> = (0..self.play_list.len()).collect();
+        order[range.start-1..range.end].rotate_left(1);
+        self.reorder(order);
+    }
+
+    pub fn move_down(&mut self, range: Range<usize>) {
+        if range.end >= self.play_list.len() || range.is_empty() {return;}
+        let mut order: Vec<usize> = (0..self.play_list.len()).collect();
+        order[range.start..range.end+1].rotate_right(1);
+        self.reorder(order);
+    }
+
+    /// Removes every entry whose path already appeared earlier in the list.
+    pub fn dedup(&mut self) {
+        let mut first_seen: HashMap<&PathBuf, usize> = HashMap::new();
+        let mut keep = vec![];
+        let mut playing = self.playing_index;
+        for (i, (_, path)) in self.play_list.iter().enumerate() {
+            match first_seen.get(path) {
+                Some(&first) => {
+                    if playing == Some(i) { playing = Some(first); }
+                }
+                None => {
+                    first_seen.insert(path, i);
+                    keep.push(i);
+                }
+            }
+        }
+        self.playing_index = playing;
+        self.reorder(keep);
+    }
+
+    pub fn sort(&mut self, key: SortKey) {
+        let mut order: Vec<usize> = (0..self.play_list.len()).collect();
+        order.sort_by_cached_key(|&i| key.value(&self.play_list[i]));
+        self.reorder(order);
+    }
+
+    // `order` lists the old indices to keep, in their new order. The cursor
+    // and the playing track follow their entries to the new positions, and
+    // a playing track left out is stopped.
+    fn reorder(&mut self, order: Vec<usize>) {
+        self.playlist_version += 1;
+        let position = |old: usize| order.iter().position(|&i| i == old);
+        let cursor = self.playlist_index;
+        let playing = self.playing_index;
+        self.playing_index = playing.and_then(position);
+        if playing.is_some() && self.playing_index.is_none() {
+            self.stop();
+        }
+        let new_cursor = position(cursor);
+        let mut old: Vec<Option<(String, PathBuf)>> = self.play_list.drain(..).map(Some).collect();
+        self.play_list = order.iter().filter_map(|&i| old[i].take()).collect();
+        self.entry_ids = order.iter().filter_map(|&i| self.entry_ids.get(i).copied()).collect();
+        self.playlist_index = new_cursor
+            .unwrap_or(cursor)
+            .min(self.play_list.len().saturating_sub(1));
     }
 
     pub fn tick(&mut self) {
@@ -164,9 +446,16 @@ impl PlayController {
         match status {
             PlayStatus::Playing(start_ins, paused_time) => {
                 let now = start_ins.elapsed() - *paused_time;
-                if now.ge(&self.total_time) {
+                if let Some(title) = &self.radio {
+                    self.stream_title = title.get();
+                }
+                let finished = match self.radio {
+                    Some(_) => self.sink.empty(),
+                    None => now.ge(&self.total_time),
+                };
+                if finished {
                     *status = PlayStatus::Complete;               
-                    self.next();
+                    self.advance();
                 }
                 else{
                     self.current_time = now;
@@ -175,17 +464,252 @@ impl PlayController {
             _ =>()
         }
     }
+}
 
-    pub fn get_progress(&self) -> String{
-        let current_time = self.current_time;
-        let total_time = self.total_time;
+/// `mm:ss / mm:ss` for the progress bar, or `mm:ss / live` for a stream.
+pub fn format_progress(current_time: Duration, total_time: Duration) -> String {
+    let minute_mins = current_time.as_secs() / 60;
+    let minute_secs = current_time.as_secs() % 60;
+    if total_time.is_zero() {
+        return format!("{:0>2}:{:0>2} / live", minute_mins, minute_secs);
+    }
 
-        let minute_mins = current_time.as_secs() / 60;
-        let minute_secs = current_time.as_secs() % 60;
+    let total_mins = total_time.as_secs() / 60;
+    let total_secs = total_time.as_secs() % 60;
+    format!("{:0>2}:{:0>2} / {:0>2}:{:0>2}",
+        minute_mins, minute_secs, total_mins, total_secs).to_owned()
+}
+
+pub fn song_duration(song_path: &Path) -> Option<Duration> {
+    match AudioFormat::detect(song_path)? {
+        AudioFormat::Mp3 => match mp3_duration::from_path(song_path) {
+            Ok(dur) => Some(dur),
+            Err(err) if !err.at_duration.is_zero() => Some(err.at_duration),
+            Err(_) => None,
+        },
+        AudioFormat::Vorbis => vorbis_duration(song_path),
+        AudioFormat::Opus => opus::duration(song_path),
+        AudioFormat::MpegTs => mpegts::duration(song_path),
+        AudioFormat::Mp4 | AudioFormat::Aac => probed_duration(song_path),
+        AudioFormat::Wav | AudioFormat::Flac => {
+            let f = File::open(song_path).ok()?;
+            Decoder::new(f).ok()?.total_duration()
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Name,
+    Path,
+    Duration,
+    Title,
+    Artist,
+    Album,
+    Track,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Text(String),
+    Number(u128),
+    Missing,
+}
+
+impl SortKey {
+    pub fn next(self) -> SortKey {
+        match self {
+            SortKey::Name => SortKey::Path,
+            SortKey::Path => SortKey::Duration,
+            SortKey::Duration => SortKey::Title,
+            SortKey::Title => SortKey::Artist,
+            SortKey::Artist => SortKey::Album,
+            SortKey::Album => SortKey::Track,
+            SortKey::Track => SortKey::Name,
+        }
+    }
+
+    pub const ALL: [SortKey;7] = [
+        SortKey::Name, SortKey::Path, SortKey::Duration, SortKey::Title,
+        SortKey::Artist, SortKey::Album, SortKey::Track,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Path => "path",
+            SortKey::Duration => "duration",
+            SortKey::Title => "title",
+            SortKey::Artist => "artist",
+            SortKey::Album => "album",
+            SortKey::Track => "track",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SortKey> {
+        Self::ALL.into_iter().find(|k| k.name() == name)
+    }
+
+    fn value(&self, (name, path): &(String, PathBuf)) -> SortValue {
+        let text = |s: Option<String>| s.map_or(SortValue::Missing, |s| SortValue::Text(s.to_lowercase()));
+        match self {
+            SortKey::Name => SortValue::Text(name.to_lowercase()),
+            SortKey::Path => SortValue::Text(path.to_string_lossy().into_owned()),
+            SortKey::Duration => song_duration(path)
+                .map_or(SortValue::Missing, |d| SortValue::Number(d.as_millis())),
+            SortKey::Title => text(Tags::read(path).title),
+            SortKey::Artist => text(Tags::read(path).artist),
+            SortKey::Album => text(Tags::read(path).album),
+            SortKey::Track => Tags::read(path).track
+                .map_or(SortValue::Missing, |t| SortValue::Number(t as u128)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(names: &str) -> Vec<(String, PathBuf)> {
+        names.chars().map(|c| (c.to_string(), PathBuf::from(format!("/music/{}.mp3", c)))).collect()
+    }
+
+    fn controller(names: &str) -> PlayController {
+        let mut player = PlayController::with_output(None);
+        player.append(entries(names));
+        player
+    }
+
+    fn names(player: &PlayController) -> String {
+        player.play_list.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn entry_ids_stay_with_their_entries() {
+        let mut player = controller("abc");
+        assert_eq!(player.entry_ids, [0, 1, 2]);
+        player.insert(1, entries("x"));
+        player.move_down(0..1);
+        player.remove_indices(&[3].into());
+        assert_eq!(names(&player), "xab");
+        assert_eq!(player.entry_ids, [3, 0, 1]);
+        player.clear();
+        player.append(entries("y"));
+        assert_eq!(player.entry_ids, [4]);
+    }
+
+    #[test]
+    fn insert_keeps_the_cursor_and_playing_track_on_their_entries() {
+        let mut player = controller("abc");
+        player.playlist_index = 1;
+        player.playing_index = Some(2);
+        player.insert(1, entries("xy"));
+        assert_eq!(names(&player), "axybc");
+        assert_eq!(player.playlist_index, 3);
+        assert_eq!(player.playing_index, Some(4));
+
+        player.insert(99, entries("z"));
+        assert_eq!(names(&player), "axybcz");
+        assert_eq!(player.playlist_index, 3);
+    }
+
+    #[test]
+    fn inserting_into_an_empty_playlist_leaves_the_cursor_at_the_top() {
+        let mut player = controller("");
+        player.insert(0, entries("ab"));
+        assert_eq!(player.playlist_index, 0);
+    }
+
+    #[test]
+    fn play_next_goes_after_the_playing_track_or_the_cursor() {
+        let mut player = controller("");
+        assert_eq!(player.play_next(entries("a")), 0);
+        let mut player = controller("abc");
+        player.playlist_index = 1;
+        assert_eq!(player.play_next(entries("x")), 2);
+        player.playing_index = Some(0);
+        assert_eq!(player.play_next(entries("y")), 1);
+        assert_eq!(names(&player), "aybxc");
+    }
+
+    #[test]
+    fn moving_a_range_up_and_down() {
+        let mut player = controller("abcd");
+        player.playlist_index = 1;
+        player.move_up(1..3);
+        assert_eq!(names(&player), "bcad");
+        assert_eq!(player.playlist_index, 0);
+
+        player.move_up(0..1);
+        assert_eq!(names(&player), "bcad");
+
+        player.move_down(0..2);
+        assert_eq!(names(&player), "abcd");
+        assert_eq!(player.playlist_index, 1);
+
+        player.move_down(3..4);
+        assert_eq!(names(&player), "abcd");
+    }
+
+    #[test]
+    fn dedup_keeps_first_entries_and_follows_the_playing_track() {
+        let mut player = controller("abacb");
+        player.playing_index = Some(2);
+        player.dedup();
+        assert_eq!(names(&player), "abc");
+        assert_eq!(player.playing_index, Some(0));
+    }
+
+    #[test]
+    fn removing_entries() {
+        let mut player = controller("abcd");
+        player.playlist_index = 3;
+        player.playing_index = Some(2);
+        let removed = player.remove_indices(&BTreeSet::from([0, 2]));
+        assert_eq!(removed, entries("ac"));
+        assert_eq!(names(&player), "bd");
+        assert_eq!(player.playlist_index, 1);
+        assert_eq!(player.playing_index, None);
+    }
+
+    #[test]
+    fn removing_the_playing_entry_stops_it() {
+        let mut player = controller("abc");
+        player.playing_index = Some(1);
+        player.playing_song = Some("b".to_string());
+        player.playing_path = Some(PathBuf::from("/music/b.mp3"));
+        player.play();
+        player.remove_indices(&BTreeSet::from([0]));
+        assert_eq!((player.playing_index, player.playing_song.as_deref()), (Some(0), Some("b")));
+        assert!(player.is_playing_or_paused());
+
+        player.remove_indices(&BTreeSet::from([0]));
+        assert_eq!(names(&player), "c");
+        assert_eq!((player.playing_index, player.playing_song.as_deref(), player.playing_path.as_deref()), (None, None, None));
+        assert!(!player.is_playing_or_paused() && !player.is_playing);
+        // Nothing is playing, so next starts from the cursor
+        assert_eq!(player.next_index(false), Some(0));
+    }
+
+    #[test]
+    fn sorting_moves_the_cursor_with_its_entry() {
+        let mut player = PlayController::with_output(None);
+        player.append(vec![
+            ("c".to_string(), PathBuf::from("/c")),
+            ("A".to_string(), PathBuf::from("/a")),
+            ("b".to_string(), PathBuf::from("/b")),
+        ]);
+        let version = player.playlist_version;
+        player.sort(SortKey::Name);
+        assert_eq!(names(&player), "Abc");
+        assert_eq!(player.playlist_index, 2);
+        assert!(player.playlist_version > version);
+    }
 
-        let total_mins = total_time.as_secs() / 60;
-        let total_secs = total_time.as_secs() % 60;
-        format!("{:0>2}:{:0>2} / {:0>2}:{:0>2}",
-            minute_mins, minute_secs, total_mins, total_secs).to_owned()
+    #[test]
+    fn progress_text() {
+        assert_eq!(format_progress(Duration::from_secs(65), Duration::from_secs(200)), "01:05 / 03:20");
+        assert_eq!(format_progress(Duration::from_secs(5), Duration::ZERO), "00:05 / live");
     }
 }
@@ -0,0 +1,91 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::radio::is_stream;
+
+pub const PLAYLIST_EXTENSIONS: [&str;3] = ["m3u", "m3u8", "pls"];
+
+pub fn is_playlist_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| PLAYLIST_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Writes `entries` as an extended M3U playlist. Track lengths are left as
+/// -1 (unknown) so saving doesn't have to read every file.
+pub fn write_m3u(path: &Path, entries: &[(String, PathBuf)]) -> Result<(), Error> {
+    let mut data = String::from("#EXTM3U\n");
+    for (name, track) in entries {
+        data.push_str(&format!("#EXTINF:-1,{}\n{}\n", name, track.display()));
+    }
+    fs::write(path, data)
+}
+
+/// Reads an M3U or PLS playlist. Relative entries are taken relative to
+/// the playlist's own directory. Of the entries that aren't local files only
+/// `http://` radio streams are kept, named after the URL if untitled.
+pub fn read(path: &Path) -> Result<Vec<(String, PathBuf)>, Error> {
+    let data = fs::read_to_string(path)?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    let ext = path.extension().and_then(OsStr::to_str).map(str::to_lowercase);
+    let entries = match ext.as_deref() {
+        Some("pls") => parse_pls(&data),
+        Some("m3u" | "m3u8") => parse_m3u(&data),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "not an M3U or PLS playlist")),
+    };
+    Ok(entries.into_iter()
+        .filter(|(_, location)| !location.contains("://") || is_stream(Path::new(location)))
+        .map(|(title, location)| {
+            if is_stream(Path::new(&location)) {
+                return (title.unwrap_or_else(|| location.clone()), PathBuf::from(location));
+            }
+            let track = base.join(location);
+            let name = title.unwrap_or_else(|| file_name(&track));
+            (name, track)
+        })
+        .collect())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+// Locations with the `#EXTINF` title that came before them, if any.
+fn parse_m3u(data: &str) -> Vec<(Option<String>, String)> {
+    let mut entries = vec![];
+    let mut title = None;
+    for line in data.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            title = info.split_once(',').map(|(_, t)| t.trim().to_string()).filter(|t| !t.is_empty());
+        }
+        else if !line.is_empty() && !line.starts_with('#') {
+            entries.push((title.take(), line.to_string()));
+        }
+    }
+    entries
+}
+
+// `FileN=` and `TitleN=` keys, in the order of N.
+fn parse_pls(data: &str) -> Vec<(Option<String>, String)> {
+    let mut files: Vec<(u32, String)> = vec![];
+    let mut titles: Vec<(u32, String)> = vec![];
+    for line in data.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else { continue };
+        let key = key.to_lowercase();
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse().ok()) {
+            files.push((n, value.to_string()));
+        }
+        else if let Some(n) = key.strip_prefix("title").and_then(|n| n.parse().ok()) {
+            titles.push((n, value.to_string()));
+        }
+    }
+    files.sort_by_key(|(n, _)| *n);
+    files.into_iter()
+        .map(|(n, location)| {
+            let title = titles.iter().find(|(t, _)| *t == n).map(|(_, title)| title.clone());
+            (title, location)
+        })
+        .collect()
+}
@@ -0,0 +1,240 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::Amount;
+use crate::play_controller::{format_progress, RepeatMode, SortKey};
+
+/// A command sent to the player, one JSON object per line on the control
+/// socket, e.g. `{"cmd":"seek","position":90}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// The player's state. The playlist is left out when it is still at
+    /// version `since`.
+    Status { since: Option<u64> },
+    /// Plays the entry at `index`, or resumes, or starts from the cursor.
+    Play { index: Option<usize> },
+    Pause,
+    Toggle,
+    Next,
+    Prev,
+    Stop,
+    /// Jumps to `position` seconds into the track.
+    Seek { position: f64 },
+    /// Moves forward, or back when negative, by `seconds`.
+    SeekBy { seconds: f64 },
+    Volume { percent: u8 },
+    VolumeBy { percent: i16 },
+    /// Sets the repeat mode, or cycles it when `mode` is missing.
+    Repeat { mode: Option<RepeatMode> },
+    /// Sets shuffle, or toggles it when `on` is missing.
+    Shuffle { on: Option<bool> },
+    /// Moves the playlist cursor.
+    Select { index: usize },
+    Append { entries: Vec<(String, PathBuf)> },
+    Insert { at: usize, entries: Vec<(String, PathBuf)> },
+    /// Inserts after the current track and answers with where they went.
+    PlayNext { entries: Vec<(String, PathBuf)> },
+    /// Adds music files, the contents of playlist files, and everything
+    /// under directories, which are scanned in the background.
+    Enqueue { paths: Vec<PathBuf> },
+    /// Enqueues `path` like `enqueue` and plays the first entry it adds,
+    /// which for a directory is once its scan is done.
+    Open { path: PathBuf },
+    /// Removes entries and answers with what was removed.
+    Remove { indices: BTreeSet<usize> },
+    MoveUp { index: usize },
+    MoveDown { index: usize },
+    Dedup,
+    Sort { key: SortKey },
+    Clear,
+    /// Stops the player, TUI or daemon.
+    Shutdown,
+}
+
+impl Request {
+    /// `volume` or `volume_by`, depending on the kind of amount.
+    pub fn volume(amount: Amount<u8>) -> Request {
+        match amount {
+            Amount::To(percent) => Request::Volume { percent },
+            Amount::Up(step) => Request::VolumeBy { percent: step as i16 },
+            Amount::Down(step) => Request::VolumeBy { percent: -(step as i16) },
+        }
+    }
+
+    /// `seek` or `seek_by`, depending on the kind of amount.
+    pub fn seek(amount: Amount<Duration>) -> Request {
+        match amount {
+            Amount::To(position) => Request::Seek { position: position.as_secs_f64() },
+            Amount::Up(step) => Request::SeekBy { seconds: step.as_secs_f64() },
+            Amount::Down(step) => Request::SeekBy { seconds: -step.as_secs_f64() },
+        }
+    }
+}
+
+/// The answer to a [`Request`]. Only the fields the request produces are
+/// filled in.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<PlayerState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entries: Option<Vec<(String, PathBuf)>>,
+    /// How many tracks `enqueue` added right away, not counting directories
+    /// still being scanned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added: Option<usize>,
+}
+
+impl Response {
+    pub fn ok() -> Response {
+        Self { ok: true, ..Self::default() }
+    }
+
+    pub fn error(msg: impl Into<String>) -> Response {
+        Self { error: Some(msg.into()), ..Self::default() }
+    }
+
+    pub fn into_result(self) -> Result<Response, String> {
+        match self.ok {
+            true => Ok(self),
+            false => Err(self.error.unwrap_or_else(|| "request failed".to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Playback {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// A snapshot of the player, which is all a client such as the TUI gets to
+/// see of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerState {
+    /// Empty when the client already has this `playlist_version`.
+    #[serde(default)]
+    pub play_list: Vec<(String, PathBuf)>,
+    /// Ids that stay with the entries of `play_list` as it changes, and are
+    /// left out along with it.
+    #[serde(default)]
+    pub playlist_ids: Vec<u64>,
+    pub playlist_version: u64,
+    pub playlist_index: usize,
+    pub playing_index: Option<usize>,
+    pub playing_song: Option<String>,
+    pub playing_path: Option<PathBuf>,
+    /// What an internet radio station says is on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_title: Option<String>,
+    pub playback: Playback,
+    #[serde(with = "seconds")]
+    pub current_time: Duration,
+    #[serde(with = "seconds")]
+    pub total_time: Duration,
+    /// In percent.
+    pub volume: u8,
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
+    /// Tracks found so far while directories are being scanned.
+    pub scanning: Option<usize>,
+}
+
+impl PlayerState {
+    /// Takes in a newer snapshot, keeping our copy of the playlist when it
+    /// was left out because it hadn't changed.
+    pub fn update(&mut self, mut newer: PlayerState) {
+        if newer.playlist_version == self.playlist_version {
+            newer.play_list = std::mem::take(&mut self.play_list);
+            newer.playlist_ids = std::mem::take(&mut self.playlist_ids);
+        }
+        *self = newer;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playback == Playback::Playing
+    }
+
+    pub fn is_playing_or_paused(&self) -> bool {
+        self.playback != Playback::Stopped
+    }
+
+    pub fn get_progress(&self) -> String {
+        format_progress(self.current_time, self.total_time)
+    }
+
+    /// What is different about `newer`. Playlists are compared by version.
+    pub fn changes(&self, newer: &PlayerState) -> Vec<Change> {
+        let mut changes = vec![];
+        if (self.playlist_version, self.playlist_index) != (newer.playlist_version, newer.playlist_index) {
+            changes.push(Change::Playlist);
+        }
+        if (self.playing_index, &self.playing_path, &self.stream_title) != (newer.playing_index, &newer.playing_path, &newer.stream_title) {
+            changes.push(Change::Track);
+        }
+        if self.playback != newer.playback {
+            changes.push(Change::Playback);
+        }
+        if self.volume != newer.volume {
+            changes.push(Change::Volume);
+        }
+        if (self.repeat, self.shuffle) != (newer.repeat, newer.shuffle) {
+            changes.push(Change::Options);
+        }
+        if self.scanning != newer.scanning {
+            changes.push(Change::Scan);
+        }
+        changes
+    }
+}
+
+/// What the player thread tells its subscribers about.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PlayerEvent {
+    Changed(Change),
+    /// Playback jumped to this position, rather than just moving on.
+    Seeked(Duration),
+}
+
+/// The kinds of change front ends pass on to their clients.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Change {
+    /// Entries were added, removed or moved, or the cursor moved.
+    Playlist,
+    /// Another track was started, or the radio station's title changed.
+    Track,
+    /// Playing, paused or stopped.
+    Playback,
+    Volume,
+    /// Repeat or shuffle.
+    Options,
+    /// A directory scan started, found more tracks or finished.
+    Scan,
+}
+
+// Durations go over the wire as fractional seconds.
+mod seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let seconds = f64::deserialize(deserializer)?;
+        Duration::try_from_secs_f64(seconds).map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,489 @@
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use flume::{Receiver, Sender, TryRecvError};
+use lewton::inside_ogg::OggStreamReader;
+use rodio::Source;
+
+use crate::audio::ProbedSource;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// A server that goes quiet for this long is taken to have dropped us
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REDIRECTS: usize = 5;
+// Connection attempts in a row that may fail before the stream ends, and
+// how much longer to wait before each one
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+// Decoded chunks (an MP3 frame is 26 ms) read ahead of playback, and how
+// many have to be back after running dry before playing on.
+const BUFFER_CHUNKS: usize = 256;
+const REFILL_CHUNKS: usize = 40;
+
+/// Whether `path` is really the URL of an internet radio stream.
+pub fn is_stream(path: &Path) -> bool {
+    let text = path.to_string_lossy();
+    text.starts_with("http://") || text.starts_with("https://")
+}
+
+/// What the station says is on, shared with the thread decoding it.
+#[derive(Clone, Default)]
+pub struct Title(Arc<Mutex<Option<String>>>);
+
+impl Title {
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().ok()?.clone()
+    }
+
+    fn set(&self, title: Option<String>) {
+        if let Ok(mut current) = self.0.lock() {
+            *current = title.filter(|t| !t.is_empty());
+        }
+    }
+}
+
+/// Starts connecting to the stream at `url` and decoding it on a thread of
+/// its own, so the player isn't held up by a slow station. The source plays
+/// silence until the first audio is in, and ends if none comes. Only MP3,
+/// Ogg Vorbis and AAC streams over plain HTTP are understood.
+pub fn open(url: &str) -> (RadioSource, Title) {
+    let title = Title::default();
+    let (tx, chunks) = flume::bounded(BUFFER_CHUNKS);
+    let url = url.to_string();
+    let shared = title.clone();
+    thread::spawn(move || decode(url, tx, shared));
+    // Any format will do for the silence, which changes with the first chunk
+    let chunk = Chunk::silence(2, 44_100);
+    (RadioSource { chunks, chunk, pos: 0, buffering: true }, title)
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Codec {
+    Mp3,
+    Vorbis,
+    // In ADTS frames. HE-AAC (AAC+) plays as its AAC core, without the
+    // high band that symphonia can't decode.
+    Aac,
+}
+
+struct Response {
+    reader: BufReader<TcpStream>,
+    codec: Codec,
+    // Bytes of audio between ICY metadata blocks
+    metaint: Option<usize>,
+}
+
+// Sends the request and reads the headers, following redirects.
+fn connect(url: &str) -> io::Result<Response> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        if url.starts_with("https://") {
+            return Err(Error::new(ErrorKind::Unsupported, "https streams are not supported"));
+        }
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("not an http:// URL: {}", url)))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let path = path.split('#').next().unwrap_or(path);
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid port in {}", url)))?)
+            },
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let address = (host, port).to_socket_addrs()?.next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("could not resolve {}", host)))?;
+        let mut stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        // HTTP/1.0 keeps the body from being sent in chunks
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: Tasco/{}\r\nAccept: */*\r\nIcy-MetaData: 1\r\n\r\n",
+            path, authority, env!("CARGO_PKG_VERSION"))?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
+        // Shoutcast answers with `ICY 200 OK` instead of an HTTP version
+        let code: u16 = status.split_whitespace().nth(1).and_then(|code| code.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not an HTTP response"))?;
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+            }
+        }
+        let header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+        match code {
+            200 => {},
+            301 | 302 | 303 | 307 | 308 => {
+                let location = header("location")
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "a redirect without a location"))?;
+                url = match location.starts_with('/') {
+                    true => format!("http://{}{}", authority, location),
+                    false => location.to_string(),
+                };
+                continue;
+            },
+            _ => return Err(Error::other(format!("the server answered {}", status.trim()))),
+        }
+        return Ok(Response {
+            codec: codec(header("content-type"), &url)?,
+            metaint: header("icy-metaint").and_then(|n| n.parse().ok()).filter(|&n| n > 0),
+            reader,
+        });
+    }
+    Err(Error::other("too many redirects"))
+}
+
+// Goes by the content type, and by the URL when there is none.
+fn codec(content_type: Option<&str>, url: &str) -> io::Result<Codec> {
+    let content_type = content_type.map(|t| t.split(';').next().unwrap_or_default().trim().to_lowercase());
+    match content_type.as_deref() {
+        Some("audio/mpeg" | "audio/mp3" | "audio/mpeg3" | "audio/x-mpeg") => Ok(Codec::Mp3),
+        Some("application/ogg" | "audio/ogg" | "audio/vorbis" | "audio/x-ogg") => Ok(Codec::Vorbis),
+        Some("audio/aac" | "audio/aacp" | "audio/x-aac") => Ok(Codec::Aac),
+        Some(kind @ "audio/mp4") => {
+            Err(Error::new(ErrorKind::Unsupported, format!("{} streams are not supported", kind)))
+        },
+        Some(kind) if !kind.starts_with("audio/") && kind != "application/octet-stream" => {
+            Err(Error::new(ErrorKind::InvalidData, format!("not an audio stream ({})", kind)))
+        },
+        _ if url.ends_with(".ogg") || url.ends_with(".oga") => Ok(Codec::Vorbis),
+        _ if url.ends_with(".aac") => Ok(Codec::Aac),
+        _ => Ok(Codec::Mp3),
+    }
+}
+
+// Decodes the stream into `tx` until the player lets go of it, connecting
+// again whenever the connection drops. Gives up at once if the first
+// connection gets no audio through, and after a few attempts in a row that
+// get none later on.
+fn decode(url: String, tx: Sender<Chunk>, title: Title) {
+    let mut failures = 0;
+    let mut played = false;
+    while failures <= MAX_RETRIES && !tx.is_disconnected() {
+        let got_audio = match connect(&url) {
+            Ok(Response { reader, codec, metaint }) => {
+                let reader = IcyReader { inner: reader, metaint, until_meta: metaint.unwrap_or(0), title: title.clone() };
+                match codec {
+                    Codec::Mp3 => decode_mp3(reader, &tx),
+                    Codec::Vorbis => decode_vorbis(reader, &tx, &title),
+                    Codec::Aac => decode_aac(reader, &tx),
+                }
+            },
+            Err(_) => false,
+        };
+        if !got_audio && !played {
+            return;
+        }
+        played = true;
+        failures = if got_audio { 0 } else { failures + 1 };
+        if !tx.is_disconnected() {
+            thread::sleep(RETRY_DELAY * failures);
+        }
+    }
+}
+
+// Both return whether any audio came through before the stream ended.
+fn decode_mp3(reader: impl Read, tx: &Sender<Chunk>) -> bool {
+    let mut decoder = minimp3::Decoder::new(reader);
+    let mut got_audio = false;
+    loop {
+        match decoder.next_frame() {
+            Ok(frame) => {
+                let chunk = Chunk { channels: frame.channels as u16, rate: frame.sample_rate as u32, samples: frame.data };
+                if chunk.is_valid() {
+                    if tx.send(chunk).is_err() {
+                        return got_audio;
+                    }
+                    got_audio = true;
+                }
+            },
+            Err(minimp3::Error::SkippedData | minimp3::Error::InsufficientData) => {},
+            Err(minimp3::Error::Eof | minimp3::Error::Io(_)) => return got_audio,
+        }
+    }
+}
+
+fn decode_vorbis(reader: impl Read, tx: &Sender<Chunk>, title: &Title) -> bool {
+    let Ok(mut decoder) = OggStreamReader::new(NoSeek(reader)) else { return false };
+    let mut got_audio = false;
+    let mut serial = None;
+    // Each song is a new Ogg stream with its own comments
+    while let Ok(Some(samples)) = decoder.read_dec_packet_itl() {
+        if serial != Some(decoder.stream_serial()) {
+            serial = Some(decoder.stream_serial());
+            title.set(vorbis_title(&decoder.comment_hdr.comment_list));
+        }
+        let header = &decoder.ident_hdr;
+        let chunk = Chunk { channels: header.audio_channels as u16, rate: header.audio_sample_rate, samples };
+        if chunk.is_valid() {
+            if tx.send(chunk).is_err() {
+                return got_audio;
+            }
+            got_audio = true;
+        }
+    }
+    got_audio
+}
+
+fn decode_aac(reader: impl Read + Send + Sync + 'static, tx: &Sender<Chunk>) -> bool {
+    let Ok(mut decoder) = ProbedSource::adts(reader) else { return false };
+    let mut got_audio = false;
+    while let Some((channels, rate, samples)) = decoder.take_packet() {
+        let chunk = Chunk { channels, rate, samples };
+        if chunk.is_valid() {
+            if tx.send(chunk).is_err() {
+                return got_audio;
+            }
+            got_audio = true;
+        }
+    }
+    got_audio
+}
+
+fn vorbis_title(comments: &[(String, String)]) -> Option<String> {
+    let find = |key: &str| comments.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str());
+    match (find("ARTIST"), find("TITLE")) {
+        (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+        (None, Some(title)) => Some(title.to_string()),
+        _ => None,
+    }
+}
+
+// The audio of a stream with the ICY metadata blocks taken out, keeping
+// the stream title from them.
+struct IcyReader<R> {
+    inner: R,
+    metaint: Option<usize>,
+    until_meta: usize,
+    title: Title,
+}
+
+impl<R: Read> Read for IcyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(metaint) = self.metaint else { return self.inner.read(buf) };
+        if self.until_meta == 0 {
+            // One length byte, in units of 16 bytes; mostly it's 0
+            let mut len = [0];
+            self.inner.read_exact(&mut len)?;
+            let mut meta = vec![0; len[0] as usize * 16];
+            self.inner.read_exact(&mut meta)?;
+            if let Some(title) = stream_title(&meta) {
+                self.title.set(Some(title));
+            }
+            self.until_meta = metaint;
+        }
+        let len = buf.len().min(self.until_meta);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.until_meta -= read;
+        Ok(read)
+    }
+}
+
+// `StreamTitle='Artist - Title';` out of a metadata block. Titles can have
+// quotes in them, so it ends at the `';`.
+fn stream_title(meta: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(meta);
+    let text = text.trim_end_matches('\0');
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let end = text[start..].find("';").map_or(text.len(), |end| start + end);
+    Some(text[start..end].trim().to_string())
+}
+
+// The Ogg reader wants to be able to seek, though it only does so when asked.
+struct NoSeek<R>(R);
+
+impl<R: Read> Read for NoSeek<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R> Seek for NoSeek<R> {
+    fn seek(&mut self, _: SeekFrom) -> io::Result<u64> {
+        Err(Error::new(ErrorKind::Unsupported, "streams can't seek"))
+    }
+}
+
+// Interleaved samples in one format.
+#[derive(Default)]
+struct Chunk {
+    channels: u16,
+    rate: u32,
+    samples: Vec<i16>,
+}
+
+impl Chunk {
+    // 20 ms of nothing, played while the buffer fills up again.
+    fn silence(channels: u16, rate: u32) -> Chunk {
+        Chunk { channels, rate, samples: vec![0; (rate / 50) as usize * channels as usize] }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.channels > 0 && self.rate > 0 && !self.samples.is_empty()
+    }
+}
+
+/// The decoded stream, for the sink. It never waits on the network: when
+/// the buffer runs dry it plays silence until enough has come in again, and
+/// it ends once the decoding thread gives up.
+pub struct RadioSource {
+    chunks: Receiver<Chunk>,
+    chunk: Chunk,
+    pos: usize,
+    buffering: bool,
+}
+
+impl RadioSource {
+    // Moves on to the next chunk. The format can only change between
+    // chunks, so one is always lined up.
+    fn advance(&mut self) {
+        self.pos = 0;
+        let (channels, rate) = (self.chunk.channels, self.chunk.rate);
+        if self.buffering && self.chunks.len() < REFILL_CHUNKS && !self.chunks.is_disconnected() {
+            self.chunk = Chunk::silence(channels, rate);
+            return;
+        }
+        self.buffering = false;
+        self.chunk = match self.chunks.try_recv() {
+            Ok(chunk) => chunk,
+            Err(TryRecvError::Empty) => {
+                self.buffering = true;
+                Chunk::silence(channels, rate)
+            },
+            Err(TryRecvError::Disconnected) => Chunk::default(),
+        };
+    }
+}
+
+impl Iterator for RadioSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = *self.chunk.samples.get(self.pos)?;
+        self.pos += 1;
+        if self.pos == self.chunk.samples.len() {
+            self.advance();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for RadioSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.chunk.samples.len() - self.pos)
+    }
+
+    fn channels(&self) -> u16 {
+        self.chunk.channels.max(1)
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.chunk.rate.max(1)
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    use super::*;
+
+    // An ADTS frame of mono AAC at 44.1 kHz with no spectral data, which
+    // plays as silence.
+    const SILENT_FRAME: [u8; 11] = [0xff, 0xf1, 0x50, 0x40, 0x01, 0x7f, 0xfc, 0x00, 0xc8, 0x00, 0x07];
+
+    // A metadata block: its length in units of 16 bytes, then the text
+    // padded out with zeroes.
+    fn metadata(title: &str) -> Vec<u8> {
+        let mut text = format!("StreamTitle='{}';", title).into_bytes();
+        text.resize(text.len().div_ceil(16) * 16, 0);
+        let mut block = vec![(text.len() / 16) as u8];
+        block.extend(text);
+        block
+    }
+
+    // Answers a request like Shoutcast does, with a metadata block after
+    // every frame.
+    fn serve(stream: &mut TcpStream, title: &str) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+        }
+        write!(stream, "ICY 200 OK\r\nContent-Type: audio/aacp\r\nicy-metaint: 11\r\n\r\n")?;
+        for _ in 0..50 {
+            stream.write_all(&SILENT_FRAME)?;
+            stream.write_all(&metadata(title))?;
+        }
+        Ok(())
+    }
+
+    // Plays the source like the sink would until `done`, for a few seconds
+    // at most.
+    fn play_until(source: &mut RadioSource, done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            source.by_ref().take(4096).for_each(drop);
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn titles_are_read_and_a_dropped_connection_is_made_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream", listener.local_addr().unwrap());
+        let (drop_first, dropped) = flume::bounded(0);
+        thread::spawn(move || {
+            let (mut first, _) = listener.accept().unwrap();
+            serve(&mut first, "Someone - It's a Title").unwrap();
+            let _ = dropped.recv();
+            drop(first);
+            let (mut second, _) = listener.accept().unwrap();
+            serve(&mut second, "Second").unwrap();
+            // Kept open until the test is over
+            let _ = dropped.recv();
+        });
+
+        let (mut source, title) = open(&url);
+        assert!(play_until(&mut source, || title.get().as_deref() == Some("Someone - It's a Title")));
+        drop_first.send(()).unwrap();
+        // Only a connection that got audio through is made again
+        assert!(play_until(&mut source, || title.get().as_deref() == Some("Second")));
+    }
+
+    #[test]
+    fn a_station_that_never_answers_ends_the_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let (mut source, _) = open(&url);
+        let chunks = source.chunks.clone();
+        assert!(play_until(&mut source, || chunks.is_disconnected()));
+        // What is left of the silence, then nothing
+        assert_eq!(source.nth(4096), None);
+    }
+}
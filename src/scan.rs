@@ -0,0 +1,226 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use flume::{Receiver, Sender};
+
+use crate::audio::AudioFormat;
+
+const PROGRESS_STEP: usize = 50;
+// Extensions only audio files have, so their contents needn't be read to
+// list them, and ones that are never audio. `.ts` and `.mp4` are left to
+// the contents, as they are as often video.
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "wav", "ogg", "oga", "opus", "m4a", "m4b", "aac"];
+const OTHER_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "bmp", "webp", "heic", "heif", "avif", "txt", "nfo", "log", "cue", "lrc",
+    "pdf", "m3u", "m3u8", "pls", "sfv", "md5", "db", "ini",
+];
+
+pub enum ScanEvent {
+    Progress(usize),
+    Done(Vec<(String, PathBuf)>),
+}
+
+/// Collects the music files under `roots` on a background thread. Dropping
+/// the returned receiver stops the scan.
+pub fn spawn_scan(roots: Vec<(String, PathBuf)>) -> Receiver<ScanEvent> {
+    scan(roots, None)
+}
+
+/// Like [`spawn_scan`], but symlinks are only followed as far as they stay
+/// inside `within`, which has to be a canonical path.
+pub fn spawn_scan_within(roots: Vec<(String, PathBuf)>, within: PathBuf) -> Receiver<ScanEvent> {
+    scan(roots, Some(within))
+}
+
+fn scan(roots: Vec<(String, PathBuf)>, within: Option<PathBuf>) -> Receiver<ScanEvent> {
+    let (tx, rx) = flume::unbounded();
+    thread::spawn(move || {
+        let mut scanner = Scanner { tx, visited: HashSet::new(), songs: vec![], within };
+        for (name, path) in roots {
+            let keep_going = if path.is_dir() {
+                scanner.walk(path.as_path())
+            }
+            else {
+                scanner.push(name, path)
+            };
+            if !keep_going {
+                return;
+            }
+        }
+        let Scanner { tx, songs, .. } = scanner;
+        let _ = tx.send(ScanEvent::Done(songs));
+    });
+    rx
+}
+
+/// Whether `path` holds audio Tasco can play. The contents decide, so a
+/// misnamed file still counts.
+pub fn is_music_file(path: &Path) -> bool {
+    AudioFormat::detect(path).is_some()
+}
+
+/// Like [`is_music_file`], but trusts a well-known extension instead of
+/// reading the file, which is fast enough for listing a whole directory on
+/// the UI thread. Files without one are still sniffed.
+pub fn looks_like_music_file(path: &Path) -> bool {
+    let ext = path.extension().and_then(OsStr::to_str).map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some(ext) if AUDIO_EXTENSIONS.contains(&ext) => true,
+        Some(ext) if OTHER_EXTENSIONS.contains(&ext) => false,
+        _ => is_music_file(path),
+    }
+}
+
+struct Scanner {
+    tx: Sender<ScanEvent>,
+    visited: HashSet<PathBuf>,
+    songs: Vec<(String, PathBuf)>,
+    within: Option<PathBuf>,
+}
+
+impl Scanner {
+    // Whether `path` is where the scan may go, once symlinks are resolved.
+    fn allows(&self, path: &Path) -> bool {
+        match &self.within {
+            Some(within) => fs::canonicalize(path).is_ok_and(|real| real.starts_with(within)),
+            None => true,
+        }
+    }
+
+    // Returns false once the receiving side has gone away.
+    fn walk(&mut self, dir: &Path) -> bool {
+        // Symlinked directories are followed, but each real directory is
+        // visited only once so a link back up the tree can't loop forever.
+        let first_visit = self.allows(dir) && fs::canonicalize(dir).is_ok_and(|real| self.visited.insert(real));
+        if !first_visit {
+            return true;
+        }
+        let mut files = vec![];
+        let mut dirs = vec![];
+        if let Ok(entries) = fs::read_dir(dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                if path.is_dir() {
+                    dirs.push((name, path));
+                }
+                else if path.is_file() && self.allows(&path) && is_music_file(&path) {
+                    files.push((name, path));
+                }
+            }
+        }
+        files.sort_by(|a, b| natural_cmp(&a.0, &b.0));
+        dirs.sort_by(|a, b| natural_cmp(&a.0, &b.0));
+        for (name, path) in files {
+            if !self.push(name, path) {
+                return false;
+            }
+        }
+        for (_, path) in dirs {
+            if !self.walk(path.as_path()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn push(&mut self, name: String, path: PathBuf) -> bool {
+        self.songs.push((name, path));
+        if self.songs.len().is_multiple_of(PROGRESS_STEP) {
+            return self.tx.send(ScanEvent::Progress(self.songs.len())).is_ok();
+        }
+        true
+    }
+}
+
+/// Compares names so that embedded numbers sort by value, e.g. "2 - b.mp3"
+/// comes before "10 - a.mp3" and "Disc 2" before "Disc 10".
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = Chunks(a);
+    let mut b = Chunks(b);
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => {
+                let ord = match (x.as_bytes()[0].is_ascii_digit(), y.as_bytes()[0].is_ascii_digit()) {
+                    (true, true) => {
+                        let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                        x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+                    }
+                    _ => x.to_lowercase().cmp(&y.to_lowercase()),
+                };
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+        }
+    }
+}
+
+// Splits a string into alternating runs of ASCII digits and everything else.
+struct Chunks<'a>(&'a str);
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let first = self.0.chars().next()?;
+        let digits = first.is_ascii_digit();
+        let end = self.0
+            .find(|c: char| c.is_ascii_digit() != digits)
+            .unwrap_or(self.0.len());
+        let (chunk, rest) = self.0.split_at(end);
+        self.0 = rest;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_sort_by_value() {
+        assert_eq!(natural_cmp("2 - b.mp3", "10 - a.mp3"), Ordering::Less);
+        assert_eq!(natural_cmp("Disc 10", "Disc 2"), Ordering::Greater);
+        assert_eq!(natural_cmp("track007", "track7"), Ordering::Equal);
+        assert_eq!(natural_cmp("track 99999999999999999999999", "track 100000000000000000000000"), Ordering::Less);
+    }
+
+    #[test]
+    fn text_ignores_case() {
+        assert_eq!(natural_cmp("abc", "ABD"), Ordering::Less);
+        assert_eq!(natural_cmp("Intro", "intro"), Ordering::Equal);
+    }
+
+    #[test]
+    fn prefixes_come_first() {
+        assert_eq!(natural_cmp("Song", "Song 2"), Ordering::Less);
+        assert_eq!(natural_cmp("", "a"), Ordering::Less);
+    }
+
+    #[test]
+    fn sorted_names() {
+        let mut names = vec!["10.mp3", "1.mp3", "b.mp3", "2.mp3", "A.mp3"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["1.mp3", "2.mp3", "10.mp3", "A.mp3", "b.mp3"]);
+    }
+
+    #[test]
+    fn listing_goes_by_well_known_extensions() {
+        // None of these exist, so only the name can decide
+        assert!(looks_like_music_file(Path::new("/nowhere/a.MP3")));
+        assert!(looks_like_music_file(Path::new("/nowhere/b.opus")));
+        assert!(!looks_like_music_file(Path::new("/nowhere/cover.jpg")));
+        assert!(!looks_like_music_file(Path::new("/nowhere/clip.mp4")));
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::BTreeSet;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Focus {
+    Explorer,
+    PlayList,
+}
+
+impl Focus {
+    pub fn toggle(self) -> Focus {
+        match self {
+            Focus::Explorer => Focus::PlayList,
+            Focus::PlayList => Focus::Explorer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub marked: BTreeSet<usize>,
+}
+
+impl Selection {
+    pub fn toggle(&mut self, index: usize) {
+        if !self.marked.remove(&index) {
+            self.marked.insert(index);
+        }
+    }
+
+    /// Marks every index between `from` and `to`, inclusive.
+    pub fn extend(&mut self, from: usize, to: usize) {
+        self.marked.extend(from.min(to)..=from.max(to));
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.marked.contains(&index)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.marked.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.marked.clear();
+    }
+
+    /// Moves the marks to where their entries went when the list changed
+    /// from `old` to `new`, both given as entry ids. Marks on entries that
+    /// are gone are dropped.
+    pub fn remap(&mut self, old: &[u64], new: &[u64]) {
+        let ids: BTreeSet<u64> = self.marked.iter().filter_map(|&i| old.get(i).copied()).collect();
+        self.marked = new.iter().enumerate()
+            .filter(|(_, id)| ids.contains(id))
+            .map(|(i, _)| i)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_follow_their_entries() {
+        let mut marks = Selection::default();
+        marks.extend(1, 2);
+        marks.toggle(4);
+        // 10 is removed and 14 moved to the front, behind a new entry
+        marks.remap(&[10, 11, 12, 13, 14], &[15, 14, 11, 12, 13]);
+        assert_eq!(marks.marked, BTreeSet::from([1, 2, 3]));
+        marks.remap(&[15, 14, 11, 12, 13], &[]);
+        assert!(marks.is_empty());
+    }
+}
@@ -0,0 +1,359 @@
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use flume::{Receiver, RecvTimeoutError, Sender};
+
+use crate::config::Config;
+use crate::play_controller::PlayController;
+use crate::playlist_file::{self, is_playlist_file};
+use crate::protocol::{Playback, PlayerEvent, PlayerState, Request, Response};
+use crate::radio::is_stream;
+use crate::scan::{is_music_file, spawn_scan, ScanEvent};
+use crate::session::Session;
+
+// How often playback is moved along while no requests come in.
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The player and everything that changes it. It runs on a thread of its
+/// own, and requests from the TUI, the control socket and any other front
+/// end all reach it through a [`Handle`].
+pub struct Service {
+    player: PlayController,
+    // Directories being scanned for `enqueue`, with how many tracks each
+    // has found so far and whether to play the first of them
+    scans: Vec<(Receiver<ScanEvent>, usize, bool)>,
+    subscribers: Vec<Sender<PlayerEvent>>,
+    // Set once a `shutdown` request has been handled
+    shutdown: bool,
+}
+
+impl Service {
+    /// Starts the player on a thread of its own with the configured volume,
+    /// repeat and shuffle, then restores `session` over them. It is created
+    /// on that thread because the audio output can't move between threads.
+    /// The thread ends on a `shutdown` request, or once every handle is
+    /// gone, with the player's final state.
+    pub fn spawn(config: &Config, session: Option<Session>, resume: bool, calls: Requests) -> JoinHandle<PlayerState> {
+        let (volume, repeat, shuffle) = (config.volume, config.repeat, config.shuffle);
+        thread::spawn(move || {
+            let mut player = PlayController::new();
+            player.set_volume(volume as f32 / 100.0);
+            player.repeat = repeat;
+            player.shuffle = shuffle;
+            if let Some(session) = session {
+                session.restore(&mut player, resume);
+            }
+            Service { player, scans: vec![], subscribers: vec![], shutdown: false }.run(calls)
+        })
+    }
+
+    fn run(mut self, calls: Requests) -> PlayerState {
+        let mut seen = self.snapshot();
+        while !self.shutdown {
+            match calls.recv_timeout(TICK_INTERVAL) {
+                Ok(Call::Request(request, reply)) => { let _ = reply.send(self.handle(request)); },
+                Ok(Call::Subscribe(events)) => self.subscribers.push(events),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.tick();
+            let now = self.snapshot();
+            for change in seen.changes(&now) {
+                self.emit(PlayerEvent::Changed(change));
+            }
+            seen = now;
+        }
+        self.state(None)
+    }
+
+    // The state without the playlist, which is cheap enough to take after
+    // every request.
+    fn snapshot(&self) -> PlayerState {
+        self.state(Some(self.player.playlist_version))
+    }
+
+    fn emit(&mut self, event: PlayerEvent) {
+        self.subscribers.retain(|events| events.send(event).is_ok());
+    }
+
+    fn handle(&mut self, request: Request) -> Response {
+        match self.apply(request) {
+            Ok(response) => response,
+            Err(msg) => Response::error(msg),
+        }
+    }
+
+    fn apply(&mut self, request: Request) -> Result<Response, String> {
+        let player = &mut self.player;
+        match request {
+            Request::Status { since } => {
+                return Ok(Response { state: Some(self.state(since)), ..Response::ok() });
+            },
+            Request::Play { index: Some(index) } => self.play(index)?,
+            Request::Play { index: None } => self.resume()?,
+            Request::Pause => player.pause(),
+            Request::Toggle if player.is_playing => player.pause(),
+            Request::Toggle => self.resume()?,
+            Request::Next => player.next(),
+            Request::Prev => {
+                let track = player.playing_index;
+                player.prev();
+                // Going back to the start of the same track is a seek too
+                if player.playing_index == track && player.is_playing_or_paused() {
+                    let position = player.current_time;
+                    self.emit(PlayerEvent::Seeked(position));
+                }
+            },
+            Request::Stop => player.stop(),
+            Request::Seek { position } => self.seek(to_duration(position)?)?,
+            Request::SeekBy { seconds } => {
+                let current = player.current_time;
+                let target = match seconds < 0.0 {
+                    true => current.saturating_sub(to_duration(-seconds)?),
+                    // Past the end only needs to get as far as the end
+                    false => current.checked_add(to_duration(seconds)?)
+                        .unwrap_or(player.total_time)
+                        .min(player.total_time),
+                };
+                self.seek(target)?;
+            },
+            Request::Volume { percent } => player.set_volume(percent.min(100) as f32 / 100.0),
+            Request::VolumeBy { percent } => {
+                let current = (player.volume * 100.0).round() as i16;
+                player.set_volume(current.saturating_add(percent).clamp(0, 100) as f32 / 100.0);
+            },
+            Request::Repeat { mode } => player.repeat = mode.unwrap_or_else(|| player.repeat.next()),
+            Request::Shuffle { on } => player.shuffle = on.unwrap_or(!player.shuffle),
+            Request::Select { index } => {
+                self.check_index(index)?;
+                self.player.playlist_index = index;
+            },
+            Request::Append { entries } => player.append(entries),
+            Request::Insert { at, entries } => player.insert(at, entries),
+            Request::PlayNext { entries } => {
+                let index = player.play_next(entries);
+                return Ok(Response { index: Some(index), ..Response::ok() });
+            },
+            Request::Enqueue { paths } => {
+                let mut added = 0;
+                for path in paths {
+                    added += self.enqueue(path, false)?;
+                }
+                return Ok(Response { added: Some(added), ..Response::ok() });
+            },
+            Request::Open { path } => {
+                let added = self.enqueue(path, true)?;
+                return Ok(Response { added: Some(added), ..Response::ok() });
+            },
+            Request::Remove { indices } => {
+                let entries = player.remove_indices(&indices);
+                return Ok(Response { entries: Some(entries), ..Response::ok() });
+            },
+            Request::MoveUp { index } => {
+                self.check_index(index)?;
+                self.player.move_up(index..index+1);
+            },
+            Request::MoveDown { index } => {
+                self.check_index(index)?;
+                self.player.move_down(index..index+1);
+            },
+            Request::Dedup => player.dedup(),
+            Request::Sort { key } => player.sort(key),
+            Request::Clear => player.clear(),
+            Request::Shutdown => self.shutdown = true,
+        }
+        Ok(Response::ok())
+    }
+
+    fn check_index(&self, index: usize) -> Result<(), String> {
+        match index < self.player.play_list.len() {
+            true => Ok(()),
+            false => Err(format!("the playlist only has {} entries", self.player.play_list.len())),
+        }
+    }
+
+    fn play(&mut self, index: usize) -> Result<(), String> {
+        self.check_index(index)?;
+        if !self.player.play_index(index) {
+            let (name, _) = &self.player.play_list[index];
+            return Err(format!("could not play {}", name));
+        }
+        Ok(())
+    }
+
+    // Unpauses, or starts the track under the cursor when nothing is playing.
+    fn resume(&mut self) -> Result<(), String> {
+        if self.player.is_playing_or_paused() {
+            self.player.play();
+            return Ok(());
+        }
+        if self.player.play_list.is_empty() {
+            return Err("the playlist is empty".to_string());
+        }
+        self.play(self.player.playlist_index)
+    }
+
+    fn seek(&mut self, position: Duration) -> Result<(), String> {
+        if !self.player.is_playing_or_paused() {
+            return Err("nothing is playing".to_string());
+        }
+        if !self.player.seek(position.min(self.player.total_time)) {
+            return Err("could not seek".to_string());
+        }
+        let position = self.player.current_time;
+        self.emit(PlayerEvent::Seeked(position));
+        Ok(())
+    }
+
+    // Returns how many tracks were added straight away.
+    // Adds what `path` holds to the end of the playlist, and plays the first
+    // of it when `play` is set.
+    fn enqueue(&mut self, path: PathBuf, play: bool) -> Result<usize, String> {
+        let name = file_name(&path);
+        if path.is_dir() {
+            self.scans.push((spawn_scan(vec![(name, path)]), 0, play));
+            return Ok(0);
+        }
+        let at = self.player.play_list.len();
+        let count = self.add(path, name)?;
+        if play && count > 0 {
+            self.play(at)?;
+        }
+        Ok(count)
+    }
+
+    fn add(&mut self, path: PathBuf, name: String) -> Result<usize, String> {
+        if is_stream(&path) {
+            self.player.append(vec![(path.to_string_lossy().into_owned(), path)]);
+            return Ok(1);
+        }
+        if !path.is_file() {
+            return Err(format!("no such file or directory: {}", path.display()));
+        }
+        let entries = if is_playlist_file(&path) {
+            playlist_file::read(&path)
+                .map_err(|err| format!("could not read {}: {}", path.display(), err))?
+        }
+        else if is_music_file(&path) {
+            vec![(name, path)]
+        }
+        else {
+            return Err(format!("not a music file: {}", path.display()));
+        };
+        let count = entries.len();
+        self.player.append(entries);
+        Ok(count)
+    }
+
+    // Moves playback along and picks up finished scans.
+    fn tick(&mut self) {
+        self.player.tick();
+        let mut finished = vec![];
+        self.scans.retain_mut(|(scan, found, play)| {
+            for event in scan.try_iter() {
+                match event {
+                    ScanEvent::Progress(count) => *found = count,
+                    ScanEvent::Done(songs) => {
+                        finished.push((songs, *play));
+                        return false;
+                    }
+                }
+            }
+            true
+        });
+        for (songs, play) in finished {
+            let at = self.player.play_list.len();
+            let count = songs.len();
+            self.player.append(songs);
+            if play && count > 0 {
+                self.player.play_index(at);
+            }
+        }
+    }
+
+    fn state(&self, since: Option<u64>) -> PlayerState {
+        let player = &self.player;
+        let playback = match (player.is_playing_or_paused(), player.is_playing) {
+            (false, _) => Playback::Stopped,
+            (true, true) => Playback::Playing,
+            (true, false) => Playback::Paused,
+        };
+        let (play_list, playlist_ids) = match since {
+            Some(version) if version == player.playlist_version => (vec![], vec![]),
+            _ => (player.play_list.clone(), player.entry_ids.clone()),
+        };
+        let scanning = match self.scans.is_empty() {
+            true => None,
+            false => Some(self.scans.iter().map(|(_, found, _)| found).sum()),
+        };
+        PlayerState {
+            play_list,
+            playlist_ids,
+            playlist_version: player.playlist_version,
+            playlist_index: player.playlist_index,
+            playing_index: player.playing_index,
+            playing_song: player.playing_song.clone(),
+            playing_path: player.playing_path.clone(),
+            stream_title: player.stream_title.clone(),
+            playback,
+            current_time: player.current_time,
+            total_time: player.total_time,
+            volume: (player.volume * 100.0).round() as u8,
+            repeat: player.repeat,
+            shuffle: player.shuffle,
+            scanning,
+        }
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn to_duration(seconds: f64) -> Result<Duration, String> {
+    Duration::try_from_secs_f64(seconds.max(0.0))
+        .map_err(|_| format!("invalid number of seconds: {}", seconds))
+}
+
+/// What the [`Service`] is asked to do from other threads.
+pub enum Call {
+    /// A request, with the sender its response should go back on.
+    Request(Request, Sender<Response>),
+    /// Asks for [`PlayerEvent`]s to be sent to this sender from now on.
+    Subscribe(Sender<PlayerEvent>),
+}
+
+/// The end of the channel the [`Service`] reads its calls from.
+pub type Requests = Receiver<Call>;
+
+/// A way to reach the [`Service`] from other threads, such as the ones
+/// serving socket connections.
+#[derive(Clone)]
+pub struct Handle {
+    tx: Sender<Call>,
+}
+
+impl Handle {
+    /// A handle and the end of the channel the service reads from.
+    pub fn new() -> (Handle, Requests) {
+        let (tx, rx) = flume::unbounded();
+        (Self { tx }, rx)
+    }
+
+    pub fn call(&self, request: Request) -> Response {
+        let (reply_tx, reply_rx) = flume::bounded(1);
+        if self.tx.send(Call::Request(request, reply_tx)).is_err() {
+            return Response::error("the player has shut down");
+        }
+        reply_rx.recv().unwrap_or_else(|_| Response::error("the player has shut down"))
+    }
+
+    /// Events about what changes in the player from now on. The channel is
+    /// closed when the player shuts down.
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        let (tx, rx) = flume::unbounded();
+        let _ = self.tx.send(Call::Subscribe(tx));
+        rx
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs;
+use std::io::{self, Error};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::play_controller::PlayController;
+use crate::protocol::PlayerState;
+
+/// What gets carried over between runs: the playlist, the cursor, the volume,
+/// the track that was playing together with how far into it we were, and the
+/// directory open in the explorer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub play_list: Vec<(String, PathBuf)>,
+    pub playlist_index: usize,
+    pub playing_index: Option<usize>,
+    pub position: Duration,
+    pub volume: f32,
+    #[serde(default)]
+    pub current_dir: Option<PathBuf>,
+}
+
+/// `$XDG_STATE_HOME/tasco`, falling back to `~/.local/state/tasco`.
+pub fn state_dir() -> Option<PathBuf> {
+    let state_dir = dirs::state_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local").join("state")))?;
+    Some(state_dir.join("tasco"))
+}
+
+impl Session {
+    /// `$XDG_STATE_HOME/tasco/session.json`, falling back to
+    /// `~/.local/state/tasco/session.json`.
+    pub fn path() -> Option<PathBuf> {
+        Some(state_dir()?.join("session.json"))
+    }
+
+    pub fn load() -> Option<Session> {
+        let data = fs::read_to_string(Self::path()?).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Self::path().ok_or_else(|| Error::from(io::ErrorKind::NotFound))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string(self).map_err(Error::from)?;
+        // Write beside the real file and rename so a crash mid-write can't
+        // leave a truncated session behind.
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Takes the session from a full snapshot of the player, playlist and
+    /// all.
+    pub fn capture(state: &PlayerState, current_dir: Option<PathBuf>) -> Session {
+        let position = if state.is_playing_or_paused() {
+            state.current_time
+        }
+        else {
+            Duration::from_secs(0)
+        };
+        Self {
+            play_list: state.play_list.clone(),
+            playlist_index: state.playlist_index,
+            playing_index: state.playing_index,
+            position,
+            volume: state.volume as f32 / 100.0,
+            current_dir,
+        }
+    }
+
+    /// Loads the session into `player`. With `resume` the saved track starts
+    /// playing again at the saved position, otherwise the cursor is just left
+    /// on it.
+    pub fn restore(self, player: &mut PlayController, resume: bool) {
+        player.clear();
+        player.append(self.play_list);
+        player.set_volume(self.volume);
+        let len = player.play_list.len();
+        player.playlist_index = self.playlist_index.min(len.saturating_sub(1));
+        if let Some(index) = self.playing_index.filter(|&i| i < len) {
+            if resume {
+                player.play_index_at(index, self.position);
+            }
+            else {
+                player.playlist_index = index;
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use id3::TagLike;
+use lewton::inside_ogg::OggStreamReader;
+use symphonia::core::meta::StandardTagKey;
+
+use crate::audio::{self, AudioFormat};
+use crate::opus;
+
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+}
+
+impl Tags {
+    pub fn read(path: &Path) -> Tags {
+        match AudioFormat::detect(path) {
+            Some(AudioFormat::Mp3) => Self::read_id3(path),
+            Some(AudioFormat::Flac) => Self::read_flac(path),
+            Some(AudioFormat::Vorbis) => Self::read_vorbis(path),
+            Some(AudioFormat::Opus) => Self::read_opus(path),
+            Some(AudioFormat::Mp4) => Self::read_mp4(path),
+            _ => Tags::default(),
+        }
+    }
+
+    fn read_id3(path: &Path) -> Tags {
+        match id3::Tag::read_from_path(path) {
+            Ok(tag) => Self {
+                title: tag.title().map(str::to_owned),
+                artist: tag.artist().map(str::to_owned),
+                album: tag.album().map(str::to_owned),
+                track: tag.track(),
+            },
+            Err(_) => Tags::default(),
+        }
+    }
+
+    fn read_flac(path: &Path) -> Tags {
+        match claxon::FlacReader::open(path) {
+            Ok(reader) => Self::from_comments(|key| reader.get_tag(key).next().map(str::to_owned)),
+            Err(_) => Tags::default(),
+        }
+    }
+
+    fn read_vorbis(path: &Path) -> Tags {
+        let reader = File::open(path).ok().and_then(|f| OggStreamReader::new(BufReader::new(f)).ok());
+        match reader {
+            // Unlike FLAC's, these keys keep whatever case the tagger wrote
+            Some(reader) => Self::from_comments(|key| reader.comment_hdr.comment_list.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.clone())),
+            None => Tags::default(),
+        }
+    }
+
+    fn read_opus(path: &Path) -> Tags {
+        match opus::comments(path) {
+            Some(comments) => Self::from_comments(|key| comments.iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.clone())),
+            None => Tags::default(),
+        }
+    }
+
+    fn read_mp4(path: &Path) -> Tags {
+        let Some(mut format) = audio::probe(path) else { return Tags::default() };
+        let metadata = format.metadata();
+        let Some(revision) = metadata.current() else { return Tags::default() };
+        let first = |key: StandardTagKey| revision.tags().iter()
+            .find(|tag| tag.std_key == Some(key))
+            .map(|tag| tag.value.to_string());
+        Self {
+            title: first(StandardTagKey::TrackTitle),
+            artist: first(StandardTagKey::Artist),
+            album: first(StandardTagKey::Album),
+            track: first(StandardTagKey::TrackNumber)
+                .and_then(|t| t.split('/').next().and_then(|n| n.trim().parse().ok())),
+        }
+    }
+
+    // FLAC and Ogg files share the Vorbis comment field names.
+    fn from_comments(first: impl Fn(&str) -> Option<String>) -> Tags {
+        Self {
+            title: first("TITLE"),
+            artist: first("ARTIST"),
+            album: first("ALBUM"),
+            // TRACKNUMBER may be written as "3/12"
+            track: first("TRACKNUMBER")
+                .and_then(|t| t.split('/').next().and_then(|n| n.trim().parse().ok())),
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::BTreeMap;
+use std::env;
+
+use serde::Deserialize;
+use tui::style::{Color, Modifier, Style};
+use tui::widgets::BorderType;
+
+pub const BUILTIN_THEMES: [&str;4] = ["default", "dark", "light", "high-contrast"];
+
+/// Colors for every part of the UI.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub border: Color,
+    pub focused_border: Color,
+    pub border_type: BorderType,
+    pub text: Color,
+    pub highlight_fg: Color,
+    pub highlight_bg: Color,
+    pub marked: Color,
+    pub control: Color,
+    pub progress_fg: Color,
+    pub progress_bg: Color,
+    pub volume_fg: Color,
+    pub volume_bg: Color,
+    pub wave_fg: Color,
+    pub wave_bg: Color,
+    pub help_key: Color,
+    pub error: Color,
+    /// Set when `NO_COLOR` is in the environment: every color is dropped and
+    /// only bold/reverse/underline are used to tell things apart.
+    pub monochrome: bool,
+    depth: ColorDepth,
+}
+
+/// A `[themes.<name>]` table from the config file. Anything left out comes
+/// from `base`, or the default theme.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub base: Option<String>,
+    pub border: Option<String>,
+    pub focused_border: Option<String>,
+    pub border_type: Option<String>,
+    pub text: Option<String>,
+    pub highlight_fg: Option<String>,
+    pub highlight_bg: Option<String>,
+    pub marked: Option<String>,
+    pub control: Option<String>,
+    pub progress_fg: Option<String>,
+    pub progress_bg: Option<String>,
+    pub volume_fg: Option<String>,
+    pub volume_bg: Option<String>,
+    pub wave_fg: Option<String>,
+    pub wave_bg: Option<String>,
+    pub help_key: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+impl Theme {
+    pub fn dark() -> Theme {
+        Self {
+            border: Color::Reset,
+            focused_border: Color::Cyan,
+            border_type: BorderType::Rounded,
+            text: Color::Reset,
+            highlight_fg: Color::Reset,
+            highlight_bg: Color::Cyan,
+            marked: Color::Yellow,
+            control: Color::LightBlue,
+            progress_fg: Color::Cyan,
+            progress_bg: Color::DarkGray,
+            volume_fg: Color::LightCyan,
+            volume_bg: Color::Black,
+            wave_fg: Color::Cyan,
+            wave_bg: Color::Black,
+            help_key: Color::Cyan,
+            error: Color::LightRed,
+            monochrome: false,
+            depth: ColorDepth::TrueColor,
+        }
+    }
+
+    pub fn light() -> Theme {
+        Self {
+            border: Color::Gray,
+            focused_border: Color::Blue,
+            border_type: BorderType::Rounded,
+            text: Color::Black,
+            highlight_fg: Color::White,
+            highlight_bg: Color::Blue,
+            marked: Color::Magenta,
+            control: Color::Blue,
+            progress_fg: Color::Blue,
+            progress_bg: Color::Gray,
+            volume_fg: Color::Blue,
+            volume_bg: Color::White,
+            wave_fg: Color::Blue,
+            wave_bg: Color::White,
+            help_key: Color::Blue,
+            error: Color::Red,
+            monochrome: false,
+            depth: ColorDepth::TrueColor,
+        }
+    }
+
+    pub fn high_contrast() -> Theme {
+        Self {
+            border: Color::White,
+            focused_border: Color::Yellow,
+            border_type: BorderType::Thick,
+            text: Color::White,
+            highlight_fg: Color::Black,
+            highlight_bg: Color::Yellow,
+            marked: Color::LightGreen,
+            control: Color::White,
+            progress_fg: Color::Yellow,
+            progress_bg: Color::Black,
+            volume_fg: Color::Yellow,
+            volume_bg: Color::Black,
+            wave_fg: Color::White,
+            wave_bg: Color::Black,
+            help_key: Color::Yellow,
+            error: Color::LightRed,
+            monochrome: false,
+            depth: ColorDepth::TrueColor,
+        }
+    }
+
+    fn builtin(name: &str) -> Option<Theme> {
+        match name {
+            "default" | "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "high-contrast" => Some(Self::high_contrast()),
+            _ => None,
+        }
+    }
+
+    /// Resolves the theme called `name`, either built in or from the config's
+    /// `[themes]` tables, and adapts it to what the terminal can show.
+    pub fn load(name: &str, user_themes: &BTreeMap<String, ThemeConfig>) -> Result<Theme, String> {
+        let mut theme = Self::resolve(name, user_themes, 0)?;
+        let depth = ColorDepth::detect();
+        theme.map_colors(|color| depth.fit(color));
+        theme.depth = depth;
+        if env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+            theme.map_colors(|_| Color::Reset);
+            theme.monochrome = true;
+        }
+        Ok(theme)
+    }
+
+    fn resolve(name: &str, user_themes: &BTreeMap<String, ThemeConfig>, depth: usize) -> Result<Theme, String> {
+        let user = match user_themes.get(name) {
+            Some(user) => user,
+            None => return Self::builtin(name).ok_or_else(|| {
+                let mut known: Vec<&str> = BUILTIN_THEMES.to_vec();
+                known.extend(user_themes.keys().map(String::as_str));
+                format!("unknown theme \"{}\", expected one of: {}", name, known.join(", "))
+            }),
+        };
+        if depth > user_themes.len() {
+            return Err(format!("theme \"{}\" inherits from itself", name));
+        }
+        let mut theme = match &user.base {
+            Some(base) => Self::resolve(base, user_themes, depth + 1)?,
+            None => Self::dark(),
+        };
+        let field = |value: &Option<String>, key: &str, target: &mut Color| -> Result<(), String> {
+            if let Some(value) = value {
+                *target = parse_color(value)
+                    .ok_or_else(|| format!("theme \"{}\": invalid color \"{}\" for {}", name, value, key))?;
+            }
+            Ok(())
+        };
+        field(&user.border, "border", &mut theme.border)?;
+        field(&user.focused_border, "focused_border", &mut theme.focused_border)?;
+        field(&user.text, "text", &mut theme.text)?;
+        field(&user.highlight_fg, "highlight_fg", &mut theme.highlight_fg)?;
+        field(&user.highlight_bg, "highlight_bg", &mut theme.highlight_bg)?;
+        field(&user.marked, "marked", &mut theme.marked)?;
+        field(&user.control, "control", &mut theme.control)?;
+        field(&user.progress_fg, "progress_fg", &mut theme.progress_fg)?;
+        field(&user.progress_bg, "progress_bg", &mut theme.progress_bg)?;
+        field(&user.volume_fg, "volume_fg", &mut theme.volume_fg)?;
+        field(&user.volume_bg, "volume_bg", &mut theme.volume_bg)?;
+        field(&user.wave_fg, "wave_fg", &mut theme.wave_fg)?;
+        field(&user.wave_bg, "wave_bg", &mut theme.wave_bg)?;
+        field(&user.help_key, "help_key", &mut theme.help_key)?;
+        field(&user.error, "error", &mut theme.error)?;
+        if let Some(border_type) = &user.border_type {
+            theme.border_type = match border_type.as_str() {
+                "plain" => BorderType::Plain,
+                "rounded" => BorderType::Rounded,
+                "double" => BorderType::Double,
+                "thick" => BorderType::Thick,
+                other => return Err(format!(
+                    "theme \"{}\": invalid border_type \"{}\", expected plain, rounded, double or thick", name, other)),
+            };
+        }
+        Ok(theme)
+    }
+
+    fn map_colors(&mut self, f: impl Fn(Color) -> Color) {
+        for color in [
+            &mut self.border, &mut self.focused_border, &mut self.text,
+            &mut self.highlight_fg, &mut self.highlight_bg, &mut self.marked,
+            &mut self.control, &mut self.progress_fg, &mut self.progress_bg,
+            &mut self.volume_fg, &mut self.volume_bg, &mut self.wave_fg,
+            &mut self.wave_bg, &mut self.help_key, &mut self.error,
+        ] {
+            *color = f(*color);
+        }
+    }
+
+    /// An RGB color as close as the terminal can show it, used for pictures.
+    pub fn rgb(&self, r: u8, g: u8, b: u8) -> Color {
+        if self.monochrome {
+            Color::Reset
+        }
+        else {
+            self.depth.fit(Color::Rgb(r, g, b))
+        }
+    }
+
+    pub fn text_style(&self) -> Style {
+        Style::default().fg(self.text)
+    }
+
+    pub fn border_style(&self, focused: bool) -> Style {
+        match (focused, self.monochrome) {
+            (true, true) => Style::default().add_modifier(Modifier::BOLD),
+            (true, false) => Style::default().fg(self.focused_border),
+            (false, _) => Style::default().fg(self.border),
+        }
+    }
+
+    pub fn highlight_style(&self) -> Style {
+        if self.monochrome {
+            Style::default().add_modifier(Modifier::REVERSED)
+        }
+        else {
+            // A reset foreground would hide the marked color under the cursor
+            let style = Style::default().bg(self.highlight_bg);
+            if self.highlight_fg == Color::Reset { style } else { style.fg(self.highlight_fg) }
+        }
+    }
+
+    pub fn marked_style(&self) -> Style {
+        Style::default().fg(self.marked).add_modifier(Modifier::BOLD)
+    }
+
+    pub fn control_style(&self) -> Style {
+        Style::default().fg(self.control)
+    }
+
+    pub fn progress_style(&self) -> Style {
+        Style::default().fg(self.progress_fg).bg(self.progress_bg).add_modifier(Modifier::BOLD)
+    }
+
+    pub fn volume_style(&self) -> Style {
+        Style::default().fg(self.volume_fg).bg(self.volume_bg).add_modifier(Modifier::BOLD)
+    }
+
+    pub fn wave_style(&self) -> Style {
+        Style::default().fg(self.wave_fg).bg(self.wave_bg)
+    }
+
+    pub fn error_style(&self) -> Style {
+        Style::default().fg(self.error).add_modifier(Modifier::BOLD)
+    }
+
+    pub fn help_key_style(&self) -> Style {
+        if self.monochrome {
+            Style::default().add_modifier(Modifier::BOLD)
+        }
+        else {
+            Style::default().fg(self.help_key)
+        }
+    }
+}
+
+/// Parses a color name (`cyan`, `lightblue`, `reset`), a 256-color palette
+/// index (`"208"`) or a hex RGB value (`"#ff8800"`).
+pub fn parse_color(value: &str) -> Option<Color> {
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i+2], 16).ok();
+        return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+    }
+    if let Ok(index) = value.parse::<u8>() {
+        return Some(Color::Indexed(index));
+    }
+    let color = match value.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+        "reset" | "default" => Color::Reset,
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        _ => return None,
+    };
+    Some(color)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ColorDepth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+// The 16 ANSI colors with their usual xterm RGB values.
+const ANSI16: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+impl ColorDepth {
+    fn detect() -> ColorDepth {
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let term = env::var("TERM").unwrap_or_default();
+        if colorterm == "truecolor" || colorterm == "24bit" {
+            ColorDepth::TrueColor
+        }
+        else if term.contains("256color") {
+            ColorDepth::Ansi256
+        }
+        else {
+            ColorDepth::Ansi16
+        }
+    }
+
+    // Replaces colors the terminal can't show with the closest one it can.
+    fn fit(self, color: Color) -> Color {
+        match (self, color) {
+            (ColorDepth::TrueColor, _) => color,
+            (ColorDepth::Ansi256, Color::Rgb(r, g, b)) => Color::Indexed(rgb_to_256(r, g, b)),
+            (ColorDepth::Ansi16, Color::Rgb(r, g, b)) => nearest_ansi16(r, g, b),
+            (ColorDepth::Ansi16, Color::Indexed(i)) if i < 16 => ANSI16[i as usize].0,
+            (ColorDepth::Ansi16, Color::Indexed(i)) => {
+                let (r, g, b) = indexed_to_rgb(i);
+                nearest_ansi16(r, g, b)
+            }
+            _ => color,
+        }
+    }
+}
+
+fn rgb_to_256(r: u8, g: u8, b: u8) -> u8 {
+    if r == g && g == b {
+        // The 24 step gray ramp skips pure black and white
+        return match r {
+            0..=7 => 16,
+            248..=255 => 231,
+            _ => 232 + (r - 8) / 10,
+        };
+    }
+    let level = |c: u8| (c as u16 * 5 / 255) as u8;
+    16 + 36 * level(r) + 6 * level(g) + level(b)
+}
+
+fn indexed_to_rgb(i: u8) -> (u8, u8, u8) {
+    match i {
+        0..=15 => ANSI16[i as usize].1,
+        16..=231 => {
+            let i = i - 16;
+            let level = |c: u8| if c == 0 { 0 } else { 55 + c * 40 };
+            (level(i / 36), level(i / 6 % 6), level(i % 6))
+        }
+        _ => {
+            let gray = 8 + (i - 232) * 10;
+            (gray, gray, gray)
+        }
+    }
+}
+
+fn nearest_ansi16(r: u8, g: u8, b: u8) -> Color {
+    let distance = |(cr, cg, cb): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(r, cr) + d(g, cg) + d(b, cb)
+    };
+    ANSI16.iter()
+        .min_by_key(|(_, rgb)| distance(*rgb))
+        .map_or(Color::Reset, |(color, _)| *color)
+}
@@ -1,20 +1,110 @@
 use std::io::{Error};
 use std::path::{Path, PathBuf};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use image::RgbImage;
+use image::imageops::{self, FilterType};
 use rand::Rng;
 use tui::{
     backend::{CrosstermBackend, Backend},
     layout::{Alignment, Rect, Layout, Constraint, Direction}, 
-    widgets::{Block, BorderType, Borders, List, ListItem, Paragraph, Wrap, ListState, LineGauge, BarChart},
+    buffer::Buffer,
+    widgets::{Block, BorderType, Borders, List, ListItem, Paragraph, Wrap, ListState, LineGauge, BarChart, Clear, Widget},
     text::{Text, Span, Spans},
-    style::{Color, Style, Modifier,},
+    style::{Style, Modifier,},
     symbols,
     Frame,
     Terminal
 };
 
-use crate::play_controller::PlayController;
+use crate::protocol::{Change, PlayerState};
 use crate::config::Config;
+use crate::keymap::{Action, Keymap};
+use crate::selection::{Focus, Selection};
+use crate::format::format_track;
+use crate::theme::Theme;
+use crate::layout::{self, Layouts, Panel};
+use crate::lyrics::Lyrics;
+use crate::cover;
+use crate::library::Album;
+use crate::command::{CommandLine, Message, COMMANDS};
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Hit {
+    Explorer(Option<usize>),
+    PlayList(Option<usize>),
+    Progress(f64),
+    Volume(f64),
+}
+
+// Below this width the panels are stacked instead of side by side, and below
+// this height the wave panel is dropped to leave room for the lists.
+const NARROW_WIDTH: u16 = 80;
+const SHORT_HEIGHT: u16 = 24;
+
+/// What the TUI shows besides the player's state: the explorer, the marks,
+/// which list has focus and the overlays.
+pub struct View<'a> {
+    pub explorer_list: &'a [(String, PathBuf)],
+    pub dir_name: Option<&'a str>,
+    pub explore_index: usize,
+    pub focus: Focus,
+    pub explorer_marks: &'a Selection,
+    pub playlist_marks: &'a Selection,
+    pub help_scroll: Option<u16>,
+    pub command_line: &'a CommandLine,
+}
+
+/// The panels that have to be drawn again. The others are copied from the
+/// last frame.
+#[derive(Default)]
+pub struct Dirty {
+    all: bool,
+    panels: HashSet<Panel>,
+}
+
+impl Dirty {
+    pub fn everything() -> Dirty {
+        Dirty { all: true, panels: HashSet::new() }
+    }
+
+    pub fn mark_all(&mut self) {
+        self.all = true;
+    }
+
+    pub fn mark(&mut self, panel: Panel) {
+        self.panels.insert(panel);
+    }
+
+    /// Marks the panels that show what `change` is about.
+    pub fn change(&mut self, change: Change) {
+        let panels: &[Panel] = match change {
+            // The playing track's name comes from the playlist
+            Change::Playlist => &[Panel::Playlist, Panel::NowPlaying],
+            Change::Track => &[Panel::NowPlaying, Panel::Progress, Panel::Lyrics, Panel::Cover],
+            Change::Playback => &[Panel::Progress, Panel::Wave],
+            Change::Volume => &[Panel::Progress],
+            Change::Options => &[Panel::NowPlaying],
+            Change::Scan => &[Panel::Playlist],
+        };
+        self.panels.extend(panels);
+    }
+
+    pub fn is_clean(&self) -> bool {
+        !self.all && self.panels.is_empty()
+    }
+
+    fn contains(&self, panel: Panel) -> bool {
+        self.all || self.panels.contains(&panel)
+    }
+}
+
 pub struct UI {
+    size: Rect,
+    // Where each panel of the current layout is
+    areas: HashMap<Panel, Rect>,
+    // What was on screen after the last draw, to copy unchanged panels from
+    last_frame: Buffer,
     curr_dir: CurrDir,
     control_bar: ControlBar,
     curr_song: CurrSong, 
@@ -22,85 +112,279 @@ pub struct UI {
     playlist: PlayList,
     effect_bar: EffectivenessBar,
     process_bar: ProcessBar,
+    now_playing_names: TrackNames,
+    playlist_names: TrackNames,
+    lyrics: LyricsPanel,
+    cover: CoverPanel,
+    library: LibraryPanel,
+    help: HelpPopup,
+    theme: Theme,
+    layouts: Layouts,
+    extras: TrackExtras,
+    // The albums under the music roots, once they have been read
+    albums: Option<Vec<Album>>,
+}
+
+// Lyrics and cover art of the playing track. The inner `None` means there
+// were none to find, the outer one that they haven't been looked for yet.
+#[derive(Default)]
+struct TrackExtras {
+    path: Option<PathBuf>,
+    lyrics: Option<Option<Lyrics>>,
+    cover: Option<Option<RgbImage>>,
 }
 
 impl UI 
 {
     pub fn new<B: Backend>(config: &Config, terminal: &Terminal<B>) -> Result<UI, Error> {
-        let terminal_size = terminal.size()?;
-        let layout = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([Constraint::Length(3), Constraint::Percentage(100)].as_ref())
-                .split(terminal_size);
-        let header_layout = Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([Constraint::Percentage(20), Constraint::Percentage(60),Constraint::Percentage(20)].as_ref())
-                .split(layout[0]);
-        let body_layout = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Percentage(20), Constraint::Percentage(60),Constraint::Percentage(20)].as_ref())
-            .split(layout[1]);
-        let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .constraints([Constraint::Percentage(90), Constraint::Length(10)].as_ref())
-        .split(body_layout[1]);
-        
-        Ok(Self{
-            curr_dir: CurrDir::new(header_layout[0]),
-            control_bar: ControlBar::new(header_layout[1]),
-            curr_song: CurrSong::new(header_layout[2]),
-            explore: Explorer::new(body_layout[0]),
-            playlist: PlayList::new(body_layout[2]),
-            effect_bar: EffectivenessBar::new(chunks[0]),
-            process_bar: ProcessBar::new(chunks[1]),
-        })
-    }
-
-
-    pub fn draw_ui<B: Backend>(&self, 
-        terminal: &mut Terminal<B>, 
-        explorer_list: &Vec<(String, PathBuf)>,
-        play_list: &Vec<(String, PathBuf)>,
-        dir_name: Option<&str>,
-        playing_song: Option<&String>,
-        explore_index: usize,
-        playlist_index: usize,
-        player: &PlayController,
-        ) -> Result<(), Error> 
+        let hidden = Rect::default();
+        let mut ui = Self{
+            size: hidden,
+            areas: HashMap::new(),
+            last_frame: Buffer::empty(hidden),
+            curr_dir: CurrDir::new(hidden),
+            control_bar: ControlBar::new(hidden, &config.keymap),
+            curr_song: CurrSong::new(hidden),
+            explore: Explorer::new(hidden),
+            playlist: PlayList::new(hidden),
+            effect_bar: EffectivenessBar::new(hidden),
+            process_bar: ProcessBar::new(hidden),
+            now_playing_names: TrackNames::new(&config.display.now_playing),
+            playlist_names: TrackNames::new(&config.display.playlist),
+            lyrics: LyricsPanel::new(hidden),
+            cover: CoverPanel::new(hidden),
+            library: LibraryPanel::new(hidden),
+            help: HelpPopup::new(config),
+            theme: config.colors.clone(),
+            layouts: config.panels.clone(),
+            extras: TrackExtras::default(),
+            albums: None,
+        };
+        ui.resize(terminal.size()?);
+        Ok(ui)
+    }
+
+    /// Lays every panel out again for a terminal of `size`.
+    pub fn resize(&mut self, size: Rect) {
+        self.size = size;
+        let compact = layout::compact();
+        let node = if size.width < NARROW_WIDTH { &compact } else { self.layouts.current() };
+        let skip: &[Panel] = if size.height < SHORT_HEIGHT { &[Panel::Wave] } else { &[] };
+        let areas = node.areas(size, skip);
+        let area = |panel| areas.get(&panel).copied().unwrap_or_default();
+        self.curr_dir = CurrDir::new(area(Panel::Directory));
+        self.control_bar.area = area(Panel::Controls);
+        self.curr_song = CurrSong::new(area(Panel::NowPlaying));
+        self.explore = Explorer::new(area(Panel::Explorer));
+        self.playlist = PlayList::new(area(Panel::Playlist));
+        self.effect_bar = EffectivenessBar::new(area(Panel::Wave));
+        self.process_bar = ProcessBar::new(area(Panel::Progress));
+        self.lyrics = LyricsPanel::new(area(Panel::Lyrics));
+        self.cover = CoverPanel::new(area(Panel::Cover));
+        self.library = LibraryPanel::new(area(Panel::Library));
+        self.help.resize(size);
+        self.areas = areas;
+    }
+
+    /// Switches to the next layout preset.
+    pub fn cycle_layout(&mut self) {
+        self.layouts.cycle();
+        self.resize(self.size);
+    }
+
+    /// Whether `panel` takes up any room in the current layout.
+    pub fn shows(&self, panel: Panel) -> bool {
+        self.areas.get(&panel).is_some_and(|area| area.area() > 0)
+    }
+
+    /// Whether the panel for `focus` is part of the current layout.
+    pub fn is_visible(&self, focus: Focus) -> bool {
+        match focus {
+            Focus::Explorer => self.explore.area.area() > 0,
+            Focus::PlayList => self.playlist.area.area() > 0,
+        }
+    }
+
+    // Loads the lyrics and cover art of the playing track, but only for the
+    // panels that are on screen.
+    fn update_extras(&mut self, playing: Option<&PathBuf>) {
+        if self.extras.path.as_ref() != playing {
+            self.extras = TrackExtras { path: playing.cloned(), ..TrackExtras::default() };
+        }
+        let Some(path) = &self.extras.path else { return };
+        if self.lyrics.area.area() > 0 && self.extras.lyrics.is_none() {
+            self.extras.lyrics = Some(Lyrics::load(path));
+        }
+        if self.cover.area.area() > 0 && self.extras.cover.is_none() {
+            self.extras.cover = Some(cover::load(path));
+        }
+    }
+
+    /// Fills the library panel with what a library scan found.
+    pub fn set_albums(&mut self, albums: Vec<Album>) {
+        self.albums = Some(albums);
+    }
+
+    pub fn draw_ui<B: Backend>(&mut self,
+        terminal: &mut Terminal<B>,
+        player: &PlayerState,
+        view: &View,
+        dirty: &Dirty,
+        ) -> Result<(), Error>
     {
-        terminal.draw(|frame| {
-            self.draw_explorer(frame, explorer_list, dir_name, explore_index);
-            self.control_bar.draw(frame);
-            self.draw_playlist(frame, play_list, playing_song, playlist_index);
-            self.effect_bar.draw(frame, player);
-            self.process_bar.draw(frame, player);
+        let size = terminal.size()?;
+        if size != self.size {
+            self.resize(size);
+        }
+        // After a resize there is nothing to copy from
+        let all = dirty.all || self.last_frame.area != size;
+        let fresh = |panel| all || dirty.contains(panel);
+        self.update_extras(player.playing_path.as_ref());
+        let frame = terminal.draw(|frame| {
+            for (&panel, &area) in &self.areas {
+                if !fresh(panel) {
+                    frame.render_widget(Reuse(&self.last_frame), area);
+                }
+            }
+            if fresh(Panel::Directory) || fresh(Panel::Explorer) {
+                self.draw_explorer(frame, view.explorer_list, view.dir_name, view.explore_index,
+                    view.explorer_marks, view.focus == Focus::Explorer);
+            }
+            if self.control_bar.area.area() > 0 && fresh(Panel::Controls) {
+                self.control_bar.draw(frame, &self.theme);
+            }
+            if fresh(Panel::NowPlaying) || fresh(Panel::Playlist) {
+                self.draw_playlist(frame, player, view.playlist_marks, view.focus == Focus::PlayList);
+            }
+            if self.effect_bar.area.area() > 0 && fresh(Panel::Wave) {
+                self.effect_bar.draw(frame, player, &self.theme);
+            }
+            if self.process_bar.progress_area.area() > 0 && fresh(Panel::Progress) {
+                self.process_bar.draw(frame, player, &self.theme);
+            }
+            if self.lyrics.area.area() > 0 && fresh(Panel::Lyrics) {
+                let lyrics = self.extras.lyrics.as_ref().and_then(Option::as_ref);
+                self.lyrics.draw(frame, lyrics, player.current_time, &self.theme);
+            }
+            if self.cover.area.area() > 0 && fresh(Panel::Cover) {
+                self.cover.draw(frame, self.extras.cover.as_ref().and_then(Option::as_ref), &self.theme);
+            }
+            if self.library.area.area() > 0 && fresh(Panel::Library) {
+                self.library.draw(frame, self.albums.as_deref(), &self.theme);
+            }
+            if let Some(scroll) = view.help_scroll {
+                self.help.draw(frame, scroll, &self.theme);
+            }
+            draw_command_line(frame, size, view.command_line, &self.theme);
         })?;
+        self.last_frame = frame.buffer.clone();
         Ok(())
     }
 
     pub fn draw_explorer<B: Backend>(&self, 
         frame: &mut Frame<B>,
-        explorer_list: &Vec<(String, PathBuf)>, 
+        explorer_list: &[(String, PathBuf)], 
         dir_name: Option<&str>,
-        index: usize) 
+        index: usize,
+        marks: &Selection,
+        focused: bool) 
     {
-        self.curr_dir.draw(frame, dir_name);
-        self.explore.draw(frame, explorer_list, index);
+        if self.curr_dir.area.area() > 0 {
+            self.curr_dir.draw(frame, dir_name, &self.theme);
+        }
+        if self.explore.area.area() > 0 {
+            self.explore.draw(frame, explorer_list, index, marks, focused, &self.theme);
+        }
     }
 
     pub fn draw_playlist<B: Backend>(&self, 
         frame: &mut Frame<B>, 
-        play_list: &Vec<(String, PathBuf)>, 
-        playing_song: Option<&String>, 
-        index: usize) 
+        player: &PlayerState,
+        marks: &Selection,
+        focused: bool)
     {
-        self.curr_song.draw(frame, playing_song);
-        self.playlist.draw(frame, play_list, index);
+        let mut playing_text = match player.playing_index.and_then(|i| player.play_list.get(i)) {
+            Some((name, path)) => Some(self.now_playing_names.get(name, path)),
+            None => player.playing_song.clone(),
+        };
+        // The station's name can be a long URL, so the song goes first
+        if let (Some(text), Some(title)) = (playing_text.as_mut(), &player.stream_title) {
+            *text = format!("{} ({})", title, text);
+        }
+        if self.curr_song.area.area() > 0 {
+            self.curr_song.draw(frame, playing_text.as_ref(), player, &self.theme);
+        }
+        if self.playlist.area.area() == 0 {
+            return;
+        }
+        let items = player.play_list.iter().enumerate()
+            .map(|(i, (name, path))| marked_item(&self.playlist_names.get(name, path), marks.contains(i), &self.theme))
+            .collect();
+        self.playlist.draw(frame, items, player.playlist_index, focused, player.scanning, &self.theme);
+    }
+
+    /// Works out what is under the mouse at `column`, `row`. List hits carry
+    /// the row index when the click landed on an entry.
+    pub fn hit(&self, column: u16, row: u16, explorer_index: usize, explorer_len: usize, player: &PlayerState) -> Option<Hit> {
+        if contains(self.explore.area, column, row) {
+            // The explorer list has the extra "Go Back" row at the top
+            return Some(Hit::Explorer(list_row(self.explore.area, row, explorer_index, explorer_len + 1)));
+        }
+        if contains(self.playlist.area, column, row) {
+            return Some(Hit::PlayList(list_row(self.playlist.area, row, player.playlist_index, player.play_list.len())));
+        }
+        if contains(self.process_bar.progress_area, column, row) {
+            let line = self.process_bar.progress_line.get();
+            if column >= line.x && column < line.right() {
+                return Some(Hit::Progress(line_ratio(line, column)));
+            }
+        }
+        if contains(self.process_bar.vol_area, column, row) {
+            return Some(Hit::Volume(line_ratio(self.process_bar.vol_line.get(), column)));
+        }
+        None
     }
 
+    /// The furthest the help text can be scrolled.
+    pub fn help_max_scroll(&self) -> u16 {
+        self.help.max_scroll()
+    }
 }
 
 
+fn marked_item(name: &str, marked: bool, theme: &Theme) -> ListItem<'static> {
+    if marked {
+        ListItem::new(format!("* {}", name))
+            .style(theme.marked_style())
+    }
+    else {
+        ListItem::new(name.to_string())
+    }
+}
+
+// Display names built from a config template, cached per file so tags are
+// only read once.
+struct TrackNames {
+    template: String,
+    cache: RefCell<HashMap<PathBuf, String>>,
+}
+
+impl TrackNames {
+    fn new(template: &str) -> TrackNames {
+        Self { template: template.to_string(), cache: RefCell::new(HashMap::new()) }
+    }
+
+    fn get(&self, name: &str, path: &Path) -> String {
+        if self.template == "{name}" {
+            return name.to_string();
+        }
+        self.cache.borrow_mut()
+            .entry(path.to_path_buf())
+            .or_insert_with(|| format_track(&self.template, name, path))
+            .clone()
+    }
+}
+
 struct PlayList {
     area: Rect,
     index: usize,
@@ -111,21 +395,22 @@ impl PlayList {
         Self { area, index: 0 }
     }
 
-    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, play_list: &Vec<(String, PathBuf)>, index: usize) {
-        let mut items = vec![];
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, items: Vec<ListItem<'static>>, selected: usize, focused: bool, scan_progress: Option<usize>, theme: &Theme) {
         let mut list_state = ListState::default();
-        for item in play_list {
-            items.push(ListItem::new(item.0.as_str()));
-        }
-        if items.len() > 0 { list_state.select(Some(index));}
+        if !items.is_empty() { list_state.select(Some(selected));}
+        let title = match scan_progress {
+            Some(count) => format!("Playlist (scanning, {} found)", count),
+            None => "Playlist".to_string(),
+        };
         let block = Block::default()
-            .title("Playlist")
+            .title(title)
             .borders(Borders::ALL)
             .title_alignment(Alignment::Center)
-            .border_type(BorderType::Rounded);         
+            .border_type(theme.border_type)
+            .border_style(theme.border_style(focused));
         let list = List::new(items)
             .block(block)
-            .highlight_style(Style::default().bg(Color::Cyan))
+            .highlight_style(theme.highlight_style())
             .highlight_symbol("> ");
         frame.render_stateful_widget(list, self.area.clone(), &mut list_state);
     }
@@ -142,19 +427,20 @@ impl Explorer {
         }
     }
 
-    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, explorer_list: &Vec<(String, PathBuf)>, index: usize) {
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, explorer_list: &[(String, PathBuf)], index: usize, marks: &Selection, focused: bool, theme: &Theme) {
         let mut items = vec![ListItem::new("Go Back")];
-        for item in explorer_list {
-            items.push(ListItem::new(item.0.as_str()));
+        for (i, item) in explorer_list.iter().enumerate() {
+            items.push(marked_item(item.0.as_str(), marks.contains(i), theme));
         }
         let block = Block::default()
             .title("Explorer")
             .borders(Borders::ALL)
             .title_alignment(Alignment::Center)
-            .border_type(BorderType::Rounded);         
+            .border_type(theme.border_type)
+            .border_style(theme.border_style(focused));
         let list = List::new(items)
             .block(block)
-            .highlight_style(Style::default().bg(Color::Cyan))
+            .highlight_style(theme.highlight_style())
             .highlight_symbol("> ");
         
         let mut list_state = ListState::default();
@@ -167,6 +453,9 @@ impl Explorer {
 struct ProcessBar {
     progress_area: Rect,
     vol_area: Rect,
+    // Where the gauge lines were last drawn, for mapping clicks to a ratio
+    progress_line: Cell<Rect>,
+    vol_line: Cell<Rect>,
 }
 
 impl ProcessBar {
@@ -177,73 +466,108 @@ impl ProcessBar {
             .split(area);
         Self { 
             progress_area: layout[0],
-            vol_area: layout[1]
+            vol_area: layout[1],
+            progress_line: Cell::new(Rect::default()),
+            vol_line: Cell::new(Rect::default()),
         }
     }
 
-    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, player: &PlayController) {
-        self.draw_vol(frame, player.volume as f64);
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, player: &PlayerState, theme: &Theme) {
+        self.draw_vol(frame, player.volume as f64 / 100.0, theme);
         
         if player.is_playing_or_paused() {
-            let precent = player.current_time.as_secs_f64() / player.total_time.as_secs_f64();
+            // A radio stream has no length to be some way through
+            let precent = match player.total_time.is_zero() {
+                true => 0.0,
+                false => player.current_time.as_secs_f64() / player.total_time.as_secs_f64(),
+            };
             let progress = player.get_progress();
-            self.draw_progress(frame, Some(progress), precent);
+            self.draw_progress(frame, Some(progress), precent, theme);
         }
         else{
-            self.draw_progress(frame, None, 0.0);
+            self.draw_progress(frame, None, 0.0, theme);
         }
         
     }
     
-    pub fn draw_progress <B: Backend>(&self, frame: &mut Frame<B>, progress: Option<String>, percent: f64) {
+    pub fn draw_progress <B: Backend>(&self, frame: &mut Frame<B>, progress: Option<String>, percent: f64, theme: &Theme) {
         let mut s = "No More Sound".to_string();
         if let Some(progress) = progress {
             s = progress;
         }  
+        let label_width = Span::raw(s.as_str()).width() as u16;
         let gauge = LineGauge::default()
             .ratio(percent)
             .line_set(symbols::line::THICK)
             .label(s)
             .style(Style::default().add_modifier(Modifier::ITALIC))
-            .gauge_style(
-                Style::default()
-                    .bg(Color::DarkGray)
-                    .fg(Color::Cyan)
-                    .add_modifier(Modifier::BOLD),
-            );
+            .gauge_style(theme.progress_style());
         let layout = Layout::default()
             .margin(1)
             .horizontal_margin(1)
             .constraints([Constraint::Percentage(100)].as_ref())
-            .split(self.progress_area.clone());
+            .split(self.progress_area);
+        self.progress_line.set(gauge_line(layout[0], label_width));
         frame.render_widget(gauge, layout[0]);
     } 
 
-    pub fn draw_vol<B: Backend>(&self, frame: &mut Frame<B>, vol: f64) {
+    pub fn draw_vol<B: Backend>(&self, frame: &mut Frame<B>, vol: f64, theme: &Theme) {
         let bar = LineGauge::default()
             .ratio(vol)
             .label("VOL")
             .line_set(symbols::line::THICK)
             .block(
                 Block::default()
-                    .border_type(BorderType::Rounded)
+                    .border_type(theme.border_type)
+                    .border_style(theme.border_style(false))
                     .borders(Borders::LEFT | Borders::RIGHT),
             )
-            .gauge_style(
-                Style::default()
-                    .fg(Color::LightCyan)
-                    .bg(Color::Black)
-                    .add_modifier(Modifier::BOLD),
-            );
+            .gauge_style(theme.volume_style());
             let layout = Layout::default()
             .margin(1)
             .horizontal_margin(1)
             .constraints([Constraint::Percentage(100)].as_ref())
-            .split(self.vol_area.clone());
+            .split(self.vol_area);
+        // Inside the left and right borders, after the "VOL" label
+        let inner = Rect { x: layout[0].x + 1, width: layout[0].width.saturating_sub(2), ..layout[0] };
+        self.vol_line.set(gauge_line(inner, 3));
         frame.render_widget(bar, layout[0]);
     }
 }
 
+// The part of a LineGauge drawn as the line: it starts one column after the
+// label and runs to the right edge.
+fn gauge_line(gauge_area: Rect, label_width: u16) -> Rect {
+    let start = (gauge_area.x + label_width + 1).min(gauge_area.right());
+    Rect::new(start, gauge_area.y, gauge_area.right() - start, gauge_area.height.min(1))
+}
+
+// Maps a column on a gauge line to the ratio it represents.
+fn line_ratio(line: Rect, column: u16) -> f64 {
+    if line.width == 0 {
+        return 0.0;
+    }
+    (column.saturating_sub(line.x) as f64 / line.width as f64).clamp(0.0, 1.0)
+}
+
+// Which row of a bordered list is at screen `row`, given the selected entry.
+// The list scrolls just far enough to keep the selection visible, so the
+// offset can be worked out the same way tui's List does.
+fn list_row(area: Rect, row: u16, selected: usize, len: usize) -> Option<usize> {
+    let inner_top = area.y + 1;
+    let inner_height = area.height.saturating_sub(2) as usize;
+    if row < inner_top || row >= inner_top + inner_height as u16 {
+        return None;
+    }
+    let offset = selected.saturating_sub(inner_height.saturating_sub(1));
+    let index = offset + (row - inner_top) as usize;
+    if index < len { Some(index) } else { None }
+}
+
+fn contains(area: Rect, column: u16, row: u16) -> bool {
+    column >= area.x && column < area.right() && row >= area.y && row < area.bottom()
+}
+
 struct EffectivenessBar {
     area: Rect,
 }
@@ -253,7 +577,7 @@ impl EffectivenessBar {
         Self { area }
     }
 
-    pub fn draw<B>(&self, frame: &mut Frame<B>, player: &PlayController)
+    pub fn draw<B>(&self, frame: &mut Frame<B>, player: &PlayerState, theme: &Theme)
     where
         B: Backend
     {
@@ -261,7 +585,7 @@ impl EffectivenessBar {
         let mut cols = vec![];
         for _ in 0..20 {
             let mut i = rng.gen_range(0..10);
-            if !player.is_playing {
+            if !player.is_playing() {
                 i = 0
             }
             cols.push(("_", i));
@@ -269,7 +593,7 @@ impl EffectivenessBar {
         let items = BarChart::default()
                 .bar_width(4)
                 .bar_gap(1)
-                .bar_style(Style::default().fg(Color::Cyan).bg(Color::Black))
+                .bar_style(theme.wave_style())
                 .data(&cols)
                 .value_style(Style::default().add_modifier(Modifier::ITALIC))
                 .label_style(Style::default().add_modifier(Modifier::ITALIC))
@@ -278,6 +602,7 @@ impl EffectivenessBar {
                     Block::default()
                         .borders(Borders::TOP | Borders::BOTTOM)
                         .border_type(BorderType::Double)
+                        .border_style(theme.border_style(false))
                         .title("Wave")
                         .title_alignment(Alignment::Center),
                 );  
@@ -285,28 +610,42 @@ impl EffectivenessBar {
     }
 }
 
+// Symbols for the main actions, each shown with a key bound to it.
+const CONTROL_HINTS: [(&str, Action); 5] = [
+    ("▶", Action::PlaySelected),
+    ("||", Action::TogglePause),
+    (">>|", Action::Next),
+    ("EXT", Action::Quit),
+    ("HLP", Action::Help),
+];
+
 struct ControlBar {
     area: Rect,
+    hint: String,
 }
 
 impl ControlBar {
-    pub fn new(area: Rect) -> ControlBar {
-        Self { area }
+    pub fn new(area: Rect, keymap: &Keymap) -> ControlBar {
+        let hint = CONTROL_HINTS.iter()
+            .filter_map(|(symbol, action)| Some(format!("{}({})", symbol, keymap.short_key(*action)?)))
+            .collect::<Vec<_>>()
+            .join(" ");
+        Self { area, hint }
     }
 
-    pub fn draw<B>(&self, frame: &mut Frame<B>)
+    pub fn draw<B>(&self, frame: &mut Frame<B>, theme: &Theme)
     where
         B: Backend
     {
-        let mut p = Paragraph::new(vec![Spans::from("▶(s) >>|(n) EXT(q) HLP(h)")])
+        let mut p = Paragraph::new(vec![Spans::from(self.hint.as_str())])
             .style(Style::default())
             .alignment(Alignment::Center);
         let block = Block::default()
             .title("Control")
             .borders(Borders::ALL)
             .title_alignment(Alignment::Center)
-            .border_type(BorderType::Rounded)
-            .style(Style::default().fg(Color::LightBlue));
+            .border_type(theme.border_type)
+            .style(theme.control_style());
         p = p.block(block);
         
         frame.render_widget(p, self.area.clone());
@@ -322,7 +661,7 @@ impl CurrDir {
         Self { area }
     }
 
-    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, current_dir: Option<&str>) {
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, current_dir: Option<&str>, theme: &Theme) {
         let mut dir_text = "".to_string();
         if let Some(text) = current_dir {
             dir_text = text.to_owned();
@@ -334,12 +673,13 @@ impl CurrDir {
         .block(
             Block::default()
                 .borders(Borders::ALL)
-                .border_type(BorderType::Rounded)
+                .border_type(theme.border_type)
+                .border_style(theme.border_style(false))
                 .title("Directory")
                 .title_alignment(Alignment::Center),
         )
         .alignment(Alignment::Center)
-        .style(Style::default().add_modifier(Modifier::BOLD));
+        .style(theme.text_style().add_modifier(Modifier::BOLD));
     
         frame.render_widget(text, self.area.clone());
     }
@@ -354,7 +694,7 @@ impl CurrSong {
         Self { area }
     }
 
-    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, playing_song: Option<&String>) {
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, playing_song: Option<&String>, player: &PlayerState, theme: &Theme) {
         let mut playing_text = "".to_string();
         if let Some(text) = playing_song {
             playing_text = text.clone();
@@ -362,17 +702,247 @@ impl CurrSong {
         else{
             playing_text = "None".to_string();
         }
+        let mut title = format!("Now Playing (repeat {}", player.repeat.name());
+        if player.shuffle {
+            title.push_str(", shuffle");
+        }
+        title.push(')');
         let text = Paragraph::new(playing_text)
         .block(
             Block::default()
                 .borders(Borders::ALL)
-                .border_type(BorderType::Rounded)
-                .title("Now Playing")
+                .border_type(theme.border_type)
+                .border_style(theme.border_style(false))
+                .title(title)
                 .title_alignment(Alignment::Center),
         )
         .alignment(Alignment::Center)
-        .style(Style::default().add_modifier(Modifier::BOLD));
+        .style(theme.text_style().add_modifier(Modifier::BOLD));
     
         frame.render_widget(text, self.area.clone());
     }
-}
\ No newline at end of file
+}
+// The `:` prompt, or the result of the last command, over the bottom row.
+fn draw_command_line<B: Backend>(frame: &mut Frame<B>, size: Rect, command_line: &CommandLine, theme: &Theme) {
+    let area = Rect::new(size.x, size.bottom().saturating_sub(1), size.width, size.height.min(1));
+    let text = if command_line.active {
+        let before_cursor = Span::raw(&command_line.input[..command_line.cursor]).width() as u16;
+        frame.set_cursor((area.x + 1 + before_cursor).min(area.right().saturating_sub(1)), area.y);
+        Span::styled(format!(":{}", command_line.input), theme.text_style())
+    }
+    else {
+        match &command_line.message {
+            Some(Message::Info(msg)) => Span::styled(msg.clone(), theme.text_style()),
+            Some(Message::Error(msg)) => Span::styled(msg.clone(), theme.error_style()),
+            None => return,
+        }
+    };
+    frame.render_widget(Clear, area);
+    frame.render_widget(Paragraph::new(Spans::from(text)), area);
+}
+
+struct LyricsPanel {
+    area: Rect,
+}
+
+impl LyricsPanel {
+    pub fn new(area: Rect) -> LyricsPanel {
+        Self { area }
+    }
+
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, lyrics: Option<&Lyrics>, position: Duration, theme: &Theme) {
+        let block = Block::default()
+            .title("Lyrics")
+            .borders(Borders::ALL)
+            .title_alignment(Alignment::Center)
+            .border_type(theme.border_type)
+            .border_style(theme.border_style(false));
+        let Some(lyrics) = lyrics else {
+            let text = Paragraph::new("No lyrics").block(block).alignment(Alignment::Center);
+            frame.render_widget(text, self.area);
+            return;
+        };
+        let current = lyrics.current_line(position);
+        let lines: Vec<Spans> = lyrics.lines.iter().enumerate()
+            .map(|(i, (_, line))| {
+                let style = if Some(i) == current { theme.highlight_style() } else { theme.text_style() };
+                Spans::from(Span::styled(line.clone(), style))
+            })
+            .collect();
+        // Keep the line being sung in the middle of the panel
+        let half = self.area.height.saturating_sub(2) / 2;
+        let scroll = current.map_or(0, |i| (i as u16).saturating_sub(half));
+        let text = Paragraph::new(lines)
+            .block(block)
+            .alignment(Alignment::Center)
+            .scroll((scroll, 0));
+        frame.render_widget(text, self.area);
+    }
+}
+
+struct CoverPanel {
+    area: Rect,
+}
+
+impl CoverPanel {
+    pub fn new(area: Rect) -> CoverPanel {
+        Self { area }
+    }
+
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, cover: Option<&RgbImage>, theme: &Theme) {
+        let block = Block::default()
+            .title("Cover")
+            .borders(Borders::ALL)
+            .title_alignment(Alignment::Center)
+            .border_type(theme.border_type)
+            .border_style(theme.border_style(false));
+        let inner = block.inner(self.area);
+        frame.render_widget(block, self.area);
+        match cover {
+            Some(image) if !theme.monochrome => frame.render_widget(HalfBlocks { image, theme }, inner),
+            Some(_) => frame.render_widget(Paragraph::new("Cover art needs colors").alignment(Alignment::Center), inner),
+            None => frame.render_widget(Paragraph::new("No cover art").alignment(Alignment::Center), inner),
+        }
+    }
+}
+
+// The cells of an earlier frame under the area it is rendered to.
+struct Reuse<'a>(&'a Buffer);
+
+impl Widget for Reuse<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let area = area.intersection(self.0.area);
+        for y in area.top()..area.bottom() {
+            for x in area.left()..area.right() {
+                *buf.get_mut(x, y) = self.0.get(x, y).clone();
+            }
+        }
+    }
+}
+
+// Draws a picture with two pixels per cell: the upper half block takes the
+// top pixel as its foreground and the bottom pixel as its background.
+struct HalfBlocks<'a> {
+    image: &'a RgbImage,
+    theme: &'a Theme,
+}
+
+impl Widget for HalfBlocks<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let (width, height) = self.image.dimensions();
+        if width == 0 || height == 0 || area.area() == 0 {
+            return;
+        }
+        // Fit inside the area, keeping the aspect ratio
+        let scale = (area.width as f64 / width as f64).min(area.height as f64 * 2.0 / height as f64);
+        let w = ((width as f64 * scale) as u32).max(1);
+        let h = ((height as f64 * scale) as u32).max(1);
+        let scaled = imageops::resize(self.image, w, h, FilterType::Triangle);
+        let x0 = area.x + (area.width - w as u16) / 2;
+        let y0 = area.y + (area.height - h.div_ceil(2) as u16) / 2;
+        for y in (0..h).step_by(2) {
+            for x in 0..w {
+                let [r, g, b] = scaled.get_pixel(x, y).0;
+                let cell = buf.get_mut(x0 + x as u16, y0 + (y / 2) as u16);
+                cell.set_symbol("▀").set_fg(self.theme.rgb(r, g, b));
+                if y + 1 < h {
+                    let [r, g, b] = scaled.get_pixel(x, y + 1).0;
+                    cell.set_bg(self.theme.rgb(r, g, b));
+                }
+            }
+        }
+    }
+}
+
+struct LibraryPanel {
+    area: Rect,
+}
+
+impl LibraryPanel {
+    pub fn new(area: Rect) -> LibraryPanel {
+        Self { area }
+    }
+
+    // `None` while the music roots are still being read.
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, albums: Option<&[Album]>, theme: &Theme) {
+        let items: Vec<ListItem> = match albums {
+            Some(albums) => albums.iter()
+                .map(|(name, count)| ListItem::new(format!("{} ({})", name, count)).style(theme.text_style()))
+                .collect(),
+            None => vec![ListItem::new("Scanning…").style(theme.text_style())],
+        };
+        let block = Block::default()
+            .title("Library")
+            .borders(Borders::ALL)
+            .title_alignment(Alignment::Center)
+            .border_type(theme.border_type)
+            .border_style(theme.border_style(false));
+        frame.render_widget(List::new(items).block(block), self.area);
+    }
+}
+
+struct HelpPopup {
+    area: Rect,
+    lines: Vec<Spans<'static>>,
+}
+
+impl HelpPopup {
+    pub fn new(config: &Config) -> HelpPopup {
+        let mut lines = vec![];
+        for (heading, rows) in config.keymap.help() {
+            if rows.is_empty() {
+                continue;
+            }
+            if !lines.is_empty() {
+                lines.push(Spans::from(""));
+            }
+            lines.push(Spans::from(Span::styled(heading, Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED))));
+            for (keys, description) in rows {
+                lines.push(Spans::from(vec![
+                    Span::styled(format!("  {:<22}", keys), config.colors.help_key_style()),
+                    Span::raw(description),
+                ]));
+            }
+        }
+        lines.push(Spans::from(""));
+        lines.push(Spans::from(Span::styled("Commands", Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED))));
+        for (name, args, description) in COMMANDS {
+            lines.push(Spans::from(vec![
+                Span::styled(format!("  {:<22}", format!(":{} {}", name, args)), config.colors.help_key_style()),
+                Span::raw(description),
+            ]));
+        }
+        Self { area: Rect::default(), lines }
+    }
+
+    pub fn resize(&mut self, terminal_size: Rect) {
+        let width = (terminal_size.width * 3 / 5).max(40).min(terminal_size.width);
+        let height = (terminal_size.height * 4 / 5).min(terminal_size.height);
+        self.area = Rect::new(
+            terminal_size.x + (terminal_size.width - width) / 2,
+            terminal_size.y + (terminal_size.height - height) / 2,
+            width,
+            height,
+        );
+    }
+
+    fn max_scroll(&self) -> u16 {
+        // Two rows go to the border
+        (self.lines.len() as u16).saturating_sub(self.area.height.saturating_sub(2))
+    }
+
+    pub fn draw<B: Backend>(&self, frame: &mut Frame<B>, scroll: u16, theme: &Theme) {
+        let text = Paragraph::new(self.lines.clone())
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_type(theme.border_type)
+                    .border_style(theme.border_style(false))
+                    .title("Help (Esc to close, arrows to scroll)")
+                    .title_alignment(Alignment::Center),
+            )
+            .scroll((scroll.min(self.max_scroll()), 0));
+        frame.render_widget(Clear, self.area);
+        frame.render_widget(text, self.area);
+    }
+}